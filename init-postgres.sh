@@ -0,0 +1,11 @@
+#!/bin/bash
+
+# Create a directory for the PostgreSQL database
+mkdir -p $(pwd)/data/postgres
+
+# Create the database and its user, the schema is created by gaia-hub on startup
+docker run --rm --name gaia-postgres -e POSTGRES_DB=gaia_domain -e POSTGRES_USER=gaia -e POSTGRES_PASSWORD=$POSTGRES_PASSWORD -v "$(pwd)/data/postgres:/var/lib/postgresql/data" -d postgres:16
+sleep 20
+docker stop gaia-postgres
+
+echo "Database initialized in ./data/postgres"
@@ -0,0 +1,51 @@
+CREATE TABLE IF NOT EXISTS devices (
+  id bigint unsigned NOT NULL AUTO_INCREMENT,
+  device_id varchar(256) NOT NULL,
+  version varchar(256) NOT NULL,
+  arch varchar(128) NOT NULL,
+  os varchar(128) NOT NULL,
+  client_address varchar(256) NOT NULL,
+  login_time TIMESTAMP,
+  meta JSON,
+  created_at TIMESTAMP DEFAULT NOW(),
+  updated_at TIMESTAMP DEFAULT NOW() ON UPDATE NOW(),
+  PRIMARY KEY (id),
+  UNIQUE KEY device_id (device_id)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8;
+
+CREATE TABLE IF NOT EXISTS node_status (
+  id bigint unsigned NOT NULL AUTO_INCREMENT,
+  node_id varchar(256) NOT NULL,
+  device_id varchar(256) NOT NULL,
+  subdomain varchar(256) DEFAULT '',
+  version varchar(256) NOT NULL,
+  arch varchar(128) NOT NULL,
+  os varchar(128) NOT NULL,
+  client_address varchar(256) NOT NULL,
+  login_time TIMESTAMP,
+  last_active_time TIMESTAMP,
+  last_avail_time TIMESTAMP,
+  run_id varchar(256) DEFAULT '',
+  meta JSON,
+  node_version varchar(20) DEFAULT '',
+  chat_model varchar(256) DEFAULT '',
+  embedding_model varchar(256) DEFAULT '',
+  status varchar(24),
+  created_at TIMESTAMP DEFAULT NOW(),
+  updated_at TIMESTAMP DEFAULT NOW() ON UPDATE NOW(),
+  PRIMARY KEY (id),
+  UNIQUE KEY node_id (node_id),
+  UNIQUE KEY subdomain (subdomain),
+  INDEX idx_status (status),
+  INDEX idx_login_time (login_time),
+  INDEX idx_last_active_time (last_active_time),
+  INDEX idx_last_avail_time (last_avail_time)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8;
+
+CREATE TABLE IF NOT EXISTS domain_nodes (
+  domain varchar(256) NOT NULL,
+  node_id varchar(256) NOT NULL,
+  weight bigint NOT NULL,
+  UNIQUE KEY node_id (node_id),
+  PRIMARY KEY (domain, node_id)
+);
@@ -0,0 +1,9 @@
+CREATE TABLE bans (
+  id bigint unsigned NOT NULL AUTO_INCREMENT,
+  kind varchar(32) NOT NULL,
+  value varchar(256) NOT NULL,
+  reason varchar(512) DEFAULT '',
+  created_at TIMESTAMP DEFAULT NOW(),
+  PRIMARY KEY (id),
+  UNIQUE KEY kind_value (kind, value)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8;
@@ -0,0 +1,4 @@
+ALTER TABLE node_status
+  DROP INDEX idx_frps_id,
+  DROP COLUMN frps_id;
+DROP TABLE frps_servers;
@@ -0,0 +1,13 @@
+CREATE TABLE frps_servers (
+  frps_id varchar(64) NOT NULL,
+  version varchar(64) DEFAULT '',
+  region varchar(128) DEFAULT '',
+  status varchar(24) NOT NULL,
+  last_seen_time TIMESTAMP NOT NULL,
+  created_at TIMESTAMP DEFAULT NOW(),
+  PRIMARY KEY (frps_id)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8;
+
+ALTER TABLE node_status
+  ADD COLUMN frps_id varchar(64) DEFAULT '',
+  ADD INDEX idx_frps_id (frps_id);
@@ -0,0 +1,5 @@
+ALTER TABLE node_status
+  DROP INDEX idx_location,
+  DROP COLUMN city,
+  DROP COLUMN subdivision,
+  DROP COLUMN country;
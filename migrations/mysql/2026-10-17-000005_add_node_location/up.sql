@@ -0,0 +1,5 @@
+ALTER TABLE node_status
+  ADD COLUMN country varchar(8) DEFAULT '',
+  ADD COLUMN subdivision varchar(16) DEFAULT '',
+  ADD COLUMN city varchar(128) DEFAULT '',
+  ADD INDEX idx_location (country, subdivision, city);
@@ -0,0 +1 @@
+ALTER TABLE domain_nodes MODIFY weight bigint unsigned NOT NULL;
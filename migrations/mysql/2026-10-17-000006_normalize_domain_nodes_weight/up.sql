@@ -0,0 +1,2 @@
+-- Databases created by the old init-mysql.sh have an unsigned weight
+ALTER TABLE domain_nodes MODIFY weight bigint NOT NULL;
@@ -0,0 +1,13 @@
+CREATE TABLE domains (
+  name varchar(256) NOT NULL,
+  owner varchar(256) NOT NULL DEFAULT '',
+  description varchar(1024) NOT NULL DEFAULT '',
+  chat_model varchar(256) NOT NULL DEFAULT '',
+  embedding_model varchar(256) NOT NULL DEFAULT '',
+  status varchar(24) NOT NULL DEFAULT 'active',
+  created_at TIMESTAMP DEFAULT NOW(),
+  PRIMARY KEY (name)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8;
+
+-- The domains only known by their nodes so far
+INSERT INTO domains (name) SELECT DISTINCT domain FROM domain_nodes;
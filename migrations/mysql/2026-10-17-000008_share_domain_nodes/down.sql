@@ -0,0 +1,2 @@
+-- Fails while a node still belongs to several domains
+ALTER TABLE domain_nodes DROP INDEX domain_nodes_node_id, ADD UNIQUE KEY node_id (node_id);
@@ -0,0 +1,2 @@
+-- A node may belong to several domains
+ALTER TABLE domain_nodes DROP INDEX node_id, ADD INDEX domain_nodes_node_id (node_id);
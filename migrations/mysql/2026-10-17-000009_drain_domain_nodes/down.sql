@@ -0,0 +1,4 @@
+ALTER TABLE domain_nodes
+  DROP COLUMN last_user_conn_time,
+  DROP COLUMN drain_deadline,
+  DROP COLUMN draining_since;
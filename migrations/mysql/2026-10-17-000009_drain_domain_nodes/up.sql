@@ -0,0 +1,4 @@
+ALTER TABLE domain_nodes
+  ADD COLUMN draining_since TIMESTAMP NULL,
+  ADD COLUMN drain_deadline TIMESTAMP NULL,
+  ADD COLUMN last_user_conn_time TIMESTAMP NULL;
@@ -0,0 +1 @@
+ALTER TABLE node_status ADD COLUMN probe_latency_ms bigint NULL;
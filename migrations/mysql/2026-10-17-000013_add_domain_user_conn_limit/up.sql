@@ -0,0 +1 @@
+ALTER TABLE domains ADD COLUMN user_conn_limit BIGINT NULL;
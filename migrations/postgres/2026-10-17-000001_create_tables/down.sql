@@ -0,0 +1,4 @@
+DROP TABLE domain_nodes;
+DROP TABLE node_status;
+DROP TABLE devices;
+DROP FUNCTION set_updated_at;
@@ -0,0 +1,59 @@
+CREATE OR REPLACE FUNCTION set_updated_at() RETURNS trigger AS $$
+BEGIN
+  NEW.updated_at := NOW();
+  RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TABLE IF NOT EXISTS devices (
+  id bigserial PRIMARY KEY,
+  device_id varchar(256) NOT NULL UNIQUE,
+  version varchar(256) NOT NULL,
+  arch varchar(128) NOT NULL,
+  os varchar(128) NOT NULL,
+  client_address varchar(256) NOT NULL,
+  login_time timestamp,
+  meta jsonb,
+  created_at timestamp DEFAULT NOW(),
+  updated_at timestamp DEFAULT NOW()
+);
+
+CREATE OR REPLACE TRIGGER devices_updated_at BEFORE UPDATE ON devices
+  FOR EACH ROW EXECUTE FUNCTION set_updated_at();
+
+CREATE TABLE IF NOT EXISTS node_status (
+  id bigserial PRIMARY KEY,
+  node_id varchar(256) NOT NULL UNIQUE,
+  device_id varchar(256) NOT NULL,
+  subdomain varchar(256) UNIQUE DEFAULT '',
+  version varchar(256) NOT NULL,
+  arch varchar(128) NOT NULL,
+  os varchar(128) NOT NULL,
+  client_address varchar(256) NOT NULL,
+  login_time timestamp,
+  last_active_time timestamp,
+  last_avail_time timestamp,
+  run_id varchar(256) DEFAULT '',
+  meta jsonb,
+  node_version varchar(20) DEFAULT '',
+  chat_model varchar(256) DEFAULT '',
+  embedding_model varchar(256) DEFAULT '',
+  status varchar(24),
+  created_at timestamp DEFAULT NOW(),
+  updated_at timestamp DEFAULT NOW()
+);
+
+CREATE OR REPLACE TRIGGER node_status_updated_at BEFORE UPDATE ON node_status
+  FOR EACH ROW EXECUTE FUNCTION set_updated_at();
+
+CREATE INDEX IF NOT EXISTS idx_status ON node_status (status);
+CREATE INDEX IF NOT EXISTS idx_login_time ON node_status (login_time);
+CREATE INDEX IF NOT EXISTS idx_last_active_time ON node_status (last_active_time);
+CREATE INDEX IF NOT EXISTS idx_last_avail_time ON node_status (last_avail_time);
+
+CREATE TABLE IF NOT EXISTS domain_nodes (
+  domain varchar(256) NOT NULL,
+  node_id varchar(256) NOT NULL UNIQUE,
+  weight bigint NOT NULL,
+  PRIMARY KEY (domain, node_id)
+);
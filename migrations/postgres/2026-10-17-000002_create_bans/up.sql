@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS bans (
+  id bigserial PRIMARY KEY,
+  kind varchar(32) NOT NULL,
+  value varchar(256) NOT NULL,
+  reason varchar(512) DEFAULT '',
+  created_at timestamp DEFAULT NOW(),
+  UNIQUE (kind, value)
+);
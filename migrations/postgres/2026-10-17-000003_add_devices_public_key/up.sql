@@ -0,0 +1 @@
+ALTER TABLE devices ADD COLUMN IF NOT EXISTS public_key varchar(128) DEFAULT '';
@@ -0,0 +1,3 @@
+DROP INDEX idx_frps_id;
+ALTER TABLE node_status DROP COLUMN frps_id;
+DROP TABLE frps_servers;
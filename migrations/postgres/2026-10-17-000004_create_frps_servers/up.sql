@@ -0,0 +1,11 @@
+CREATE TABLE IF NOT EXISTS frps_servers (
+  frps_id varchar(64) PRIMARY KEY,
+  version varchar(64) DEFAULT '',
+  region varchar(128) DEFAULT '',
+  status varchar(24) NOT NULL,
+  last_seen_time timestamp NOT NULL,
+  created_at timestamp DEFAULT NOW()
+);
+
+ALTER TABLE node_status ADD COLUMN IF NOT EXISTS frps_id varchar(64) DEFAULT '';
+CREATE INDEX IF NOT EXISTS idx_frps_id ON node_status (frps_id);
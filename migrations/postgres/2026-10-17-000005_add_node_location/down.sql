@@ -0,0 +1,5 @@
+DROP INDEX idx_location;
+ALTER TABLE node_status
+  DROP COLUMN city,
+  DROP COLUMN subdivision,
+  DROP COLUMN country;
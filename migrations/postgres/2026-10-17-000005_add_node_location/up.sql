@@ -0,0 +1,5 @@
+ALTER TABLE node_status
+  ADD COLUMN IF NOT EXISTS country varchar(8) DEFAULT '',
+  ADD COLUMN IF NOT EXISTS subdivision varchar(16) DEFAULT '',
+  ADD COLUMN IF NOT EXISTS city varchar(128) DEFAULT '';
+CREATE INDEX IF NOT EXISTS idx_location ON node_status (country, subdivision, city);
@@ -0,0 +1,12 @@
+CREATE TABLE IF NOT EXISTS domains (
+  name varchar(256) PRIMARY KEY,
+  owner varchar(256) NOT NULL DEFAULT '',
+  description varchar(1024) NOT NULL DEFAULT '',
+  chat_model varchar(256) NOT NULL DEFAULT '',
+  embedding_model varchar(256) NOT NULL DEFAULT '',
+  status varchar(24) NOT NULL DEFAULT 'active',
+  created_at timestamp DEFAULT NOW()
+);
+
+-- The domains only known by their nodes so far
+INSERT INTO domains (name) SELECT DISTINCT domain FROM domain_nodes ON CONFLICT DO NOTHING;
@@ -0,0 +1,3 @@
+-- Fails while a node still belongs to several domains
+DROP INDEX domain_nodes_node_id;
+ALTER TABLE domain_nodes ADD CONSTRAINT domain_nodes_node_id_key UNIQUE (node_id);
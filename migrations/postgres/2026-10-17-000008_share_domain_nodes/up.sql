@@ -0,0 +1,3 @@
+-- A node may belong to several domains
+ALTER TABLE domain_nodes DROP CONSTRAINT domain_nodes_node_id_key;
+CREATE INDEX domain_nodes_node_id ON domain_nodes (node_id);
@@ -0,0 +1,4 @@
+ALTER TABLE domain_nodes
+  ADD COLUMN IF NOT EXISTS draining_since timestamp,
+  ADD COLUMN IF NOT EXISTS drain_deadline timestamp,
+  ADD COLUMN IF NOT EXISTS last_user_conn_time timestamp;
@@ -0,0 +1 @@
+ALTER TABLE node_status DROP COLUMN probe_latency_ms;
@@ -0,0 +1 @@
+ALTER TABLE node_status ADD COLUMN IF NOT EXISTS probe_latency_ms bigint;
@@ -0,0 +1 @@
+ALTER TABLE devices ADD COLUMN IF NOT EXISTS pending_public_key varchar(128) NOT NULL DEFAULT '';
@@ -0,0 +1,3 @@
+DROP TABLE domain_nodes;
+DROP TABLE node_status;
+DROP TABLE devices;
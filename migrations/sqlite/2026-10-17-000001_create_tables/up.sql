@@ -0,0 +1,46 @@
+CREATE TABLE IF NOT EXISTS devices (
+  id integer PRIMARY KEY AUTOINCREMENT,
+  device_id varchar UNIQUE NOT NULL,
+  version varchar NOT NULL,
+  arch varchar NOT NULL,
+  os varchar NOT NULL,
+  client_address varchar NOT NULL,
+  login_time bigint,
+  meta text,
+  created_at bigint DEFAULT (strftime('%s', 'now')),
+  updated_at bigint DEFAULT (strftime('%s', 'now'))
+);
+
+CREATE TABLE IF NOT EXISTS node_status (
+  id integer PRIMARY KEY AUTOINCREMENT,
+  node_id varchar UNIQUE NOT NULL,
+  device_id varchar NOT NULL,
+  subdomain varchar UNIQUE DEFAULT '',
+  version varchar NOT NULL,
+  arch varchar NOT NULL,
+  os varchar NOT NULL,
+  client_address varchar NOT NULL,
+  login_time bigint,
+  last_active_time bigint,
+  last_avail_time bigint,
+  run_id varchar DEFAULT '',
+  meta text,
+  node_version varchar DEFAULT '',
+  chat_model varchar DEFAULT '',
+  embedding_model varchar DEFAULT '',
+  status varchar,
+  created_at bigint DEFAULT (strftime('%s', 'now')),
+  updated_at bigint DEFAULT (strftime('%s', 'now'))
+);
+
+CREATE INDEX IF NOT EXISTS idx_status ON node_status (status);
+CREATE INDEX IF NOT EXISTS idx_login_time ON node_status (login_time);
+CREATE INDEX IF NOT EXISTS idx_last_active_time ON node_status (last_active_time);
+CREATE INDEX IF NOT EXISTS idx_last_avail_time ON node_status (last_avail_time);
+
+CREATE TABLE IF NOT EXISTS domain_nodes (
+  domain varchar NOT NULL,
+  node_id varchar UNIQUE NOT NULL,
+  weight bigint NOT NULL,
+  PRIMARY KEY (domain, node_id)
+);
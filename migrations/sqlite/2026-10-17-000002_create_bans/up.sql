@@ -0,0 +1,8 @@
+CREATE TABLE bans (
+  id integer PRIMARY KEY AUTOINCREMENT,
+  kind varchar NOT NULL,
+  value varchar NOT NULL,
+  reason varchar DEFAULT '',
+  created_at bigint DEFAULT (strftime('%s', 'now')),
+  UNIQUE (kind, value)
+);
@@ -0,0 +1 @@
+ALTER TABLE devices ADD COLUMN public_key varchar DEFAULT '';
@@ -0,0 +1,11 @@
+CREATE TABLE frps_servers (
+  frps_id varchar PRIMARY KEY NOT NULL,
+  version varchar DEFAULT '',
+  region varchar DEFAULT '',
+  status varchar NOT NULL,
+  last_seen_time bigint NOT NULL,
+  created_at bigint DEFAULT (strftime('%s', 'now'))
+);
+
+ALTER TABLE node_status ADD COLUMN frps_id varchar DEFAULT '';
+CREATE INDEX idx_frps_id ON node_status (frps_id);
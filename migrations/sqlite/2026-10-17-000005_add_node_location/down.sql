@@ -0,0 +1,4 @@
+DROP INDEX idx_location;
+ALTER TABLE node_status DROP COLUMN city;
+ALTER TABLE node_status DROP COLUMN subdivision;
+ALTER TABLE node_status DROP COLUMN country;
@@ -0,0 +1,4 @@
+ALTER TABLE node_status ADD COLUMN country varchar DEFAULT '';
+ALTER TABLE node_status ADD COLUMN subdivision varchar DEFAULT '';
+ALTER TABLE node_status ADD COLUMN city varchar DEFAULT '';
+CREATE INDEX idx_location ON node_status (country, subdivision, city);
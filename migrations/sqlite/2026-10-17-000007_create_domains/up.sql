@@ -0,0 +1,12 @@
+CREATE TABLE domains (
+  name varchar PRIMARY KEY NOT NULL,
+  owner varchar NOT NULL DEFAULT '',
+  description varchar NOT NULL DEFAULT '',
+  chat_model varchar NOT NULL DEFAULT '',
+  embedding_model varchar NOT NULL DEFAULT '',
+  status varchar NOT NULL DEFAULT 'active',
+  created_at bigint DEFAULT (strftime('%s', 'now'))
+);
+
+-- The domains only known by their nodes so far
+INSERT INTO domains (name) SELECT DISTINCT domain FROM domain_nodes;
@@ -0,0 +1,11 @@
+-- Fails while a node still belongs to several domains
+CREATE TABLE domain_nodes_unique (
+  domain varchar NOT NULL,
+  node_id varchar UNIQUE NOT NULL,
+  weight bigint NOT NULL,
+  PRIMARY KEY (domain, node_id)
+);
+INSERT INTO domain_nodes_unique (domain, node_id, weight)
+  SELECT domain, node_id, weight FROM domain_nodes;
+DROP TABLE domain_nodes;
+ALTER TABLE domain_nodes_unique RENAME TO domain_nodes;
@@ -0,0 +1,14 @@
+-- A node may belong to several domains, sqlite can only drop the unique node_id by
+-- rebuilding the table
+CREATE TABLE domain_nodes_shared (
+  domain varchar NOT NULL,
+  node_id varchar NOT NULL,
+  weight bigint NOT NULL,
+  PRIMARY KEY (domain, node_id)
+);
+INSERT INTO domain_nodes_shared (domain, node_id, weight)
+  SELECT domain, node_id, weight FROM domain_nodes;
+DROP TABLE domain_nodes;
+ALTER TABLE domain_nodes_shared RENAME TO domain_nodes;
+
+CREATE INDEX domain_nodes_node_id ON domain_nodes (node_id);
@@ -0,0 +1,3 @@
+ALTER TABLE domain_nodes DROP COLUMN last_user_conn_time;
+ALTER TABLE domain_nodes DROP COLUMN drain_deadline;
+ALTER TABLE domain_nodes DROP COLUMN draining_since;
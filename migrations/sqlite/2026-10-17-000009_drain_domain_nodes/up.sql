@@ -0,0 +1,3 @@
+ALTER TABLE domain_nodes ADD COLUMN draining_since bigint;
+ALTER TABLE domain_nodes ADD COLUMN drain_deadline bigint;
+ALTER TABLE domain_nodes ADD COLUMN last_user_conn_time bigint;
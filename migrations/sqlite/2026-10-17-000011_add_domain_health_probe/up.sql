@@ -0,0 +1 @@
+ALTER TABLE domains ADD COLUMN health_probe text;
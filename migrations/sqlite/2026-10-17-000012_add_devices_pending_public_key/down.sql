@@ -0,0 +1 @@
+ALTER TABLE devices DROP COLUMN pending_public_key;
@@ -0,0 +1 @@
+ALTER TABLE devices ADD COLUMN pending_public_key varchar NOT NULL DEFAULT '';
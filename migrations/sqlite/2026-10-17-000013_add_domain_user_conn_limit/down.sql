@@ -0,0 +1 @@
+ALTER TABLE domains DROP COLUMN user_conn_limit;
@@ -0,0 +1,192 @@
+use chrono::NaiveDateTime;
+use lazy_static::lazy_static;
+use std::env;
+
+use crate::models;
+
+lazy_static! {
+    // Scale the routed weights by the health of the nodes
+    static ref ADAPTIVE_WEIGHTS: bool = env::var("ADAPTIVE_WEIGHTS")
+        .unwrap_or_else(|_| String::from("false"))
+        .parse()
+        .expect("ADAPTIVE_WEIGHTS must be true or false");
+    // Health checks answered within this time keep the whole weight
+    static ref ADAPTIVE_LATENCY_TARGET_MS: i64 = env::var("ADAPTIVE_LATENCY_TARGET_MS")
+        .unwrap_or_else(|_| String::from("2000"))
+        .parse()
+        .expect("ADAPTIVE_LATENCY_TARGET_MS must be a number");
+    // Nodes reported available within this time keep the whole weight
+    static ref ADAPTIVE_FRESH_SECS: i64 = env::var("ADAPTIVE_FRESH_SECS")
+        .unwrap_or_else(|_| String::from("3600"))
+        .parse()
+        .expect("ADAPTIVE_FRESH_SECS must be a number");
+}
+
+// The least share of its weight a healthy but slow or stale node keeps from each signal
+const MIN_FACTOR: f64 = 0.1;
+
+// The shares a signal leaves of the weight, so the routed weight only moves when a measure
+// crosses a step, not on every small change of the latency or the age
+const FACTORS: [f64; 5] = [1.0, 0.75, 0.5, 0.25, MIN_FACTOR];
+
+pub fn adaptive_weights() -> bool {
+    *ADAPTIVE_WEIGHTS
+}
+
+// The largest share not above `target / measure` for a measure above its target, none keeps
+// it all
+fn factor(measure: Option<i64>, target: i64) -> f64 {
+    match measure {
+        Some(measure) if measure > target => {
+            let share = target.max(1) as f64 / measure as f64;
+            FACTORS
+                .into_iter()
+                .find(|factor| *factor <= share)
+                .unwrap_or(MIN_FACTOR)
+        }
+        _ => 1.0,
+    }
+}
+
+/// The share of its weight the node is routed with, from its last health check response time
+/// and the freshness of its last availability report.
+fn health_score(node: &models::Node, now: &NaiveDateTime) -> f64 {
+    let latency = factor(node.probe_latency_ms, *ADAPTIVE_LATENCY_TARGET_MS);
+    let age = node.last_avail_time.map(|t| (*now - t).num_seconds());
+    let freshness = factor(age, *ADAPTIVE_FRESH_SECS);
+    latency * freshness
+}
+
+// A positive weight never drops to 0, which only draining and the configuration set
+fn scale(weight: i64, score: f64) -> i64 {
+    match weight {
+        ..=0 => weight,
+        _ => ((weight as f64 * score).round() as i64).max(1),
+    }
+}
+
+/// The weight written to the routing for the node, scaled by its health in adaptive mode.
+pub fn effective_weight(weight: i64, node: &models::Node) -> i64 {
+    if !adaptive_weights() {
+        return weight;
+    }
+    let now = chrono::Utc::now().naive_utc();
+    scale(weight, health_score(node, &now))
+}
+
+/// Whether a routed weight moving between `from` and `to` only follows the health of a node
+/// with this configured weight, both being weights its scaling gives.
+pub fn is_health_change(weight: i64, from: i64, to: i64) -> bool {
+    let scaled = |routed: i64| {
+        FACTORS
+            .into_iter()
+            .any(|a| FACTORS.into_iter().any(|b| scale(weight, a * b) == routed))
+    };
+    weight > 0 && scaled(from) && scaled(to)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+    use serde_json::Value;
+
+    fn node(probe_latency_ms: Option<i64>, last_avail_time: Option<NaiveDateTime>) -> models::Node {
+        let time = chrono::DateTime::from_timestamp(1_700_000_000, 0)
+            .unwrap()
+            .naive_utc();
+        models::Node {
+            id: 1,
+            node_id: String::from("n1"),
+            device_id: String::from("dev1"),
+            subdomain: String::from("n1.gaia"),
+            version: String::new(),
+            arch: String::new(),
+            os: String::new(),
+            client_address: String::new(),
+            login_time: time,
+            last_active_time: time,
+            last_avail_time,
+            run_id: String::new(),
+            meta: Value::Null,
+            node_version: String::new(),
+            chat_model: String::new(),
+            embedding_model: String::new(),
+            status: String::from("online"),
+            created_at: time,
+            updated_at: time,
+            frps_id: String::new(),
+            country: String::new(),
+            subdivision: String::new(),
+            city: String::new(),
+            probe_latency_ms,
+        }
+    }
+
+    #[test]
+    fn measures_within_their_target_keep_the_whole_weight() {
+        assert_eq!(factor(None, 2000), 1.0);
+        assert_eq!(factor(Some(0), 2000), 1.0);
+        assert_eq!(factor(Some(2000), 2000), 1.0);
+    }
+
+    #[test]
+    fn measures_above_their_target_keep_a_step_of_the_weight() {
+        assert_eq!(factor(Some(2001), 2000), 0.75);
+        assert_eq!(factor(Some(2600), 2000), 0.75);
+        assert_eq!(factor(Some(2700), 2000), 0.5);
+        assert_eq!(factor(Some(4000), 2000), 0.5);
+        assert_eq!(factor(Some(8000), 2000), 0.25);
+        assert_eq!(factor(Some(60_000), 2000), MIN_FACTOR);
+        assert_eq!(factor(Some(1), 0), 1.0);
+        assert_eq!(factor(Some(100), 0), MIN_FACTOR);
+    }
+
+    #[test]
+    fn small_latency_changes_keep_the_weight() {
+        let now = chrono::Utc::now().naive_utc();
+        let weights: Vec<i64> = (3000..3900)
+            .step_by(100)
+            .map(|latency| scale(100, health_score(&node(Some(latency), None), &now)))
+            .collect();
+        assert!(weights.iter().all(|weight| *weight == 50), "{:?}", weights);
+    }
+
+    #[test]
+    fn nodes_without_probe_data_keep_their_weight() {
+        let now = chrono::Utc::now().naive_utc();
+        assert_eq!(scale(10, health_score(&node(None, None), &now)), 10);
+        assert_eq!(scale(10, health_score(&node(None, Some(now)), &now)), 10);
+    }
+
+    #[test]
+    fn stale_nodes_lose_weight() {
+        let now = chrono::Utc::now().naive_utc();
+        let fresh_secs = *ADAPTIVE_FRESH_SECS;
+        let stale = node(None, Some(now - Duration::seconds(fresh_secs * 2)));
+        assert_eq!(scale(10, health_score(&stale, &now)), 5);
+        let slow_and_stale = node(
+            Some(60_000),
+            Some(now - Duration::seconds(fresh_secs * 100)),
+        );
+        assert_eq!(scale(100, health_score(&slow_and_stale, &now)), 1);
+    }
+
+    #[test]
+    fn weights_of_0_stay_0_and_positive_ones_above() {
+        assert_eq!(scale(0, 1.0), 0);
+        assert_eq!(scale(0, 0.01), 0);
+        assert_eq!(scale(1, 0.01), 1);
+        assert_eq!(scale(3, 0.01), 1);
+    }
+
+    #[test]
+    fn health_changes_move_between_scaled_weights() {
+        assert!(is_health_change(100, 100, 50));
+        assert!(is_health_change(100, 50, 100));
+        assert!(is_health_change(100, 75, 1));
+        assert!(!is_health_change(100, 70, 100));
+        assert!(!is_health_change(100, 100, 200));
+        assert!(!is_health_change(0, 0, 0));
+    }
+}
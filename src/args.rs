@@ -1,4 +1,4 @@
-use clap::Parser;
+use clap::{Parser, Subcommand};
 
 lazy_static::lazy_static! {
     pub(crate) static ref ARGS: Args =
@@ -12,4 +12,24 @@ pub struct Args {
     /// Whether to start with a cluster
     #[arg(short, long)]
     pub cluster: bool,
+
+    /// Don't apply the pending database migrations at startup
+    #[arg(long)]
+    pub skip_migrations: bool,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Apply the pending database migrations and exit
+    Migrate,
+    /// Move the redis keys of the domains and of the active nodes to `REDIS_KEY_PREFIX`
+    /// and exit
+    MigrateRedisKeys {
+        /// The prefix the keys are under now
+        #[arg(long, default_value = "")]
+        from_prefix: String,
+    },
 }
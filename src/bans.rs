@@ -0,0 +1,459 @@
+use bytes::Buf;
+use http_body_util::BodyExt;
+use hyper::{body::Incoming as IncomingBody, header, Request, Response, StatusCode};
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+
+use crate::db::*;
+use crate::domain_nodes::offline_nodes;
+use crate::models;
+use gaia_hub::*;
+
+// Reload the bans checked on user connections at most this often
+pub static BANS_REFRESH_SECS: i64 = 5;
+
+lazy_static! {
+    // The bans checked on user connections, with the time they were loaded by this instance
+    static ref CACHED_BANS: Mutex<Option<(i64, Arc<Vec<models::Ban>>)>> = Mutex::new(None);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BanKind {
+    DeviceId,
+    NodeId,
+    Subdomain,
+    ClientAddress,
+}
+
+impl BanKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BanKind::DeviceId => "device_id",
+            BanKind::NodeId => "node_id",
+            BanKind::Subdomain => "subdomain",
+            BanKind::ClientAddress => "client_address",
+        }
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct NewBanItem {
+    kind: BanKind,
+    value: String,
+    // Left as it is on an existing ban when not given
+    #[serde(default)]
+    reason: Option<String>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct BanItem {
+    kind: BanKind,
+    value: String,
+}
+
+#[derive(Debug, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+enum BanResultCode {
+    Created,
+    Updated,
+    AlreadyExists,
+    InvalidValue,
+    Deleted,
+    NotExist,
+    // Writing the ban failed, the error is logged
+    Failed,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct BanResult {
+    kind: BanKind,
+    value: String,
+    code: BanResultCode,
+}
+
+// Parse a CIDR like `10.0.0.0/8`, a bare IP is treated as a single host
+fn parse_cidr(cidr: &str) -> Option<(IpAddr, u32)> {
+    let (addr, prefix) = match cidr.split_once('/') {
+        Some((addr, prefix)) => (addr, Some(prefix)),
+        None => (cidr, None),
+    };
+    let addr: IpAddr = addr.parse().ok()?;
+    let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+    let prefix = match prefix {
+        Some(prefix) => prefix.parse::<u32>().ok()?,
+        None => max_prefix,
+    };
+    if prefix > max_prefix {
+        return None;
+    }
+    Some((addr, prefix))
+}
+
+// The client address reported by frps is `ip:port`
+pub(crate) fn parse_client_ip(client_address: &str) -> Option<IpAddr> {
+    client_address
+        .parse::<SocketAddr>()
+        .map(|addr| addr.ip())
+        .or_else(|_| client_address.parse::<IpAddr>())
+        .ok()
+}
+
+fn cidr_contains(cidr: &str, client_address: &str) -> bool {
+    let (network, prefix) = match parse_cidr(cidr) {
+        Some(network) => network,
+        None => return false,
+    };
+    let ip = match parse_client_ip(client_address) {
+        Some(ip) => ip,
+        None => return false,
+    };
+    match (network, ip) {
+        (IpAddr::V4(network), IpAddr::V4(ip)) => {
+            let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+            u32::from(network) & mask == u32::from(ip) & mask
+        }
+        (IpAddr::V6(network), IpAddr::V6(ip)) => {
+            let mask = u128::MAX.checked_shl(128 - prefix).unwrap_or(0);
+            u128::from(network) & mask == u128::from(ip) & mask
+        }
+        _ => false,
+    }
+}
+
+/// Find the first ban matching any of the given targets.
+/// Client addresses are matched against the banned CIDRs, other kinds must match exactly.
+pub fn find_ban(targets: &[(BanKind, &str)]) -> Result<Option<models::Ban>> {
+    for (kind, value) in targets {
+        if value.is_empty() {
+            continue;
+        }
+        let ban = match kind {
+            BanKind::ClientAddress => store()
+                .query_bans(Some(kind.as_str()))?
+                .into_iter()
+                .find(|ban| cidr_contains(&ban.value, value)),
+            _ => store().query_ban(kind.as_str(), value)?,
+        };
+        if ban.is_some() {
+            return Ok(ban);
+        }
+    }
+    Ok(None)
+}
+
+// The first of the bans matching any of the targets, in the order of the targets
+fn match_ban(bans: &[models::Ban], targets: &[(BanKind, &str)]) -> Option<models::Ban> {
+    targets
+        .iter()
+        .filter(|(_, value)| !value.is_empty())
+        .find_map(|(kind, value)| {
+            bans.iter()
+                .filter(|ban| ban.kind == kind.as_str())
+                .find(|ban| match kind {
+                    BanKind::ClientAddress => cidr_contains(&ban.value, value),
+                    _ => ban.value == *value,
+                })
+                .cloned()
+        })
+}
+
+/// Like `find_ban`, but against the bans loaded within `BANS_REFRESH_SECS`,
+/// for the user connections which are too frequent to query the database.
+pub fn find_cached_ban(targets: &[(BanKind, &str)]) -> Result<Option<models::Ban>> {
+    let now = chrono::Utc::now().timestamp();
+    let cached = match &*CACHED_BANS.lock().unwrap() {
+        Some((loaded, bans)) if now - loaded < BANS_REFRESH_SECS => Some(bans.clone()),
+        _ => None,
+    };
+    let bans = match cached {
+        Some(bans) => bans,
+        None => {
+            let bans = Arc::new(store().query_bans(None)?);
+            *CACHED_BANS.lock().unwrap() = Some((now, bans.clone()));
+            bans
+        }
+    };
+    Ok(match_ban(&bans, targets))
+}
+
+// Reload the bans on the next user connection, so that this instance applies a change at once
+fn forget_cached_bans() {
+    *CACHED_BANS.lock().unwrap() = None;
+}
+
+// Force the nodes covered by a new ban offline and pull them from the domain routing
+fn offline_banned_nodes(kind: BanKind, value: &str) -> Result<()> {
+    let nodes = match kind {
+        BanKind::DeviceId => store().query_nodes_by_device_id(value)?,
+        BanKind::NodeId => store().query_node_by_node_id(value)?.into_iter().collect(),
+        BanKind::Subdomain => store()
+            .query_node_by_subdomain(value)?
+            .into_iter()
+            .collect(),
+        BanKind::ClientAddress => store()
+            .query_active_nodes()?
+            .into_iter()
+            .filter(|node| cidr_contains(value, &node.client_address))
+            .collect(),
+    };
+
+    let closed = offline_nodes(nodes)?;
+    if closed > 0 {
+        log::info!(
+            "Closed {} nodes banned by {} {}",
+            closed,
+            kind.as_str(),
+            value
+        );
+    }
+    Ok(())
+}
+
+// Record the ban or change its reason in place, so it never lapses, then force the nodes
+// it covers offline
+fn save_ban(item: &NewBanItem) -> Result<BanResultCode> {
+    let kind = item.kind.as_str();
+    let code = match (store().query_ban(kind, &item.value)?, &item.reason) {
+        (None, reason) => {
+            store().insert_ban(kind, &item.value, reason.as_deref().unwrap_or_default())?;
+            BanResultCode::Created
+        }
+        (Some(ban), Some(reason)) if ban.reason != *reason => {
+            store().update_ban(kind, &item.value, reason)?;
+            BanResultCode::Updated
+        }
+        (Some(_), _) => BanResultCode::AlreadyExists,
+    };
+    if !matches!(code, BanResultCode::AlreadyExists) {
+        forget_cached_bans();
+    }
+
+    // The ban holds even if closing the nodes fails, frps is refused on their next request
+    if let Err(e) = offline_banned_nodes(item.kind, &item.value) {
+        log::error!(
+            "Failed to close the nodes banned by {} {}: {}",
+            item.kind.as_str(),
+            item.value,
+            e
+        );
+    }
+    Ok(code)
+}
+
+/// Ban the targets or change the reason of their bans, answering with the code of each item.
+/// Each item is written on its own, so a failed one doesn't undo the others.
+pub async fn create_bans(req: Request<IncomingBody>) -> Result<Response<BoxBody>> {
+    let whole_body = req.collect().await?.aggregate();
+    let items: Vec<NewBanItem> = match serde_json::from_reader(whole_body.reader()) {
+        Ok(data) => data,
+        Err(e) => {
+            return Ok(Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(full(format!("Invalid JSON: {}", e)))?)
+        }
+    };
+
+    let results = blocking(move || {
+        let mut results = vec![];
+
+        for item in items {
+            let mut r = BanResult {
+                kind: item.kind,
+                value: item.value.clone(),
+                code: BanResultCode::Created,
+            };
+
+            let valid = match item.kind {
+                BanKind::ClientAddress => parse_cidr(&item.value).is_some(),
+                _ => !item.value.is_empty(),
+            };
+            if !valid {
+                r.code = BanResultCode::InvalidValue;
+                results.push(r);
+                continue;
+            }
+
+            r.code = match save_ban(&item) {
+                Ok(code) => code,
+                Err(e) => {
+                    log::error!("Failed to ban {} {}: {}", item.kind.as_str(), item.value, e);
+                    BanResultCode::Failed
+                }
+            };
+            results.push(r);
+        }
+        Ok(results)
+    })
+    .await?;
+
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(full(serde_json::to_string(&results)?))?)
+}
+
+pub async fn get_bans(req: Request<IncomingBody>) -> Result<Response<BoxBody>> {
+    let query = req.uri().query().unwrap_or("");
+    let params: HashMap<_, _> = form_urlencoded::parse(query.as_bytes())
+        .into_owned()
+        .collect();
+
+    let kind = params.get("kind").cloned();
+    let bans = blocking(move || store().query_bans(kind.as_deref())).await?;
+
+    let data = serde_json::json!({"code": 0, "msg": "OK", "data": bans });
+
+    let json = serde_json::to_string(&data)?;
+    let response = Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(full(json))?;
+    Ok(response)
+}
+
+/// Lift the bans, answering with the code of each item, `not_exist` if it wasn't banned.
+pub async fn remove_bans(req: Request<IncomingBody>) -> Result<Response<BoxBody>> {
+    let whole_body = req.collect().await?.aggregate();
+    let items: Vec<BanItem> = match serde_json::from_reader(whole_body.reader()) {
+        Ok(data) => data,
+        Err(e) => {
+            return Ok(Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(full(format!("Invalid JSON: {}", e)))?)
+        }
+    };
+
+    let results = blocking(move || {
+        let results: Vec<BanResult> = items
+            .into_iter()
+            .map(|item| {
+                let code = match store().delete_ban(item.kind.as_str(), &item.value) {
+                    Ok(0) => BanResultCode::NotExist,
+                    Ok(_) => {
+                        forget_cached_bans();
+                        BanResultCode::Deleted
+                    }
+                    Err(e) => {
+                        log::error!(
+                            "Failed to unban {} {}: {}",
+                            item.kind.as_str(),
+                            item.value,
+                            e
+                        );
+                        BanResultCode::Failed
+                    }
+                };
+                BanResult {
+                    kind: item.kind,
+                    value: item.value,
+                    code,
+                }
+            })
+            .collect();
+        Ok(results)
+    })
+    .await?;
+
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(full(serde_json::to_string(&results)?))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_cidrs_and_bare_ips() {
+        let v4: IpAddr = "10.0.0.0".parse().unwrap();
+        let v6: IpAddr = "fd00::".parse().unwrap();
+        assert_eq!(parse_cidr("10.0.0.0/8"), Some((v4, 8)));
+        assert_eq!(parse_cidr("10.0.0.0"), Some((v4, 32)));
+        assert_eq!(parse_cidr("10.0.0.0/0"), Some((v4, 0)));
+        assert_eq!(parse_cidr("fd00::/8"), Some((v6, 8)));
+        assert_eq!(parse_cidr("fd00::"), Some((v6, 128)));
+    }
+
+    #[test]
+    fn refuses_malformed_cidrs() {
+        for cidr in [
+            "",
+            "10.0.0",
+            "10.0.0.256/8",
+            "10.0.0.0/",
+            "10.0.0.0/33",
+            "10.0.0.0/-1",
+            "10.0.0.0/8/8",
+            "fd00::/129",
+            "host.example/8",
+        ] {
+            assert_eq!(parse_cidr(cidr), None, "{}", cidr);
+            assert!(!cidr_contains(cidr, "10.0.0.1:7000"), "{}", cidr);
+        }
+    }
+
+    #[test]
+    fn matches_ipv4_client_addresses() {
+        assert!(cidr_contains("10.0.0.0/8", "10.1.2.3:7000"));
+        assert!(cidr_contains("10.0.0.0/8", "10.1.2.3"));
+        assert!(!cidr_contains("10.0.0.0/8", "11.0.0.1:7000"));
+        assert!(cidr_contains("10.1.2.3/32", "10.1.2.3:7000"));
+        assert!(cidr_contains("10.1.2.3", "10.1.2.3:7000"));
+        assert!(!cidr_contains("10.1.2.3/32", "10.1.2.4:7000"));
+        assert!(cidr_contains("0.0.0.0/0", "203.0.113.9:7000"));
+        assert!(cidr_contains("192.168.1.128/25", "192.168.1.200:7000"));
+        assert!(!cidr_contains("192.168.1.128/25", "192.168.1.127:7000"));
+    }
+
+    #[test]
+    fn matches_ipv6_client_addresses() {
+        assert!(cidr_contains("fd00::/8", "[fd12::1]:7000"));
+        assert!(cidr_contains("fd00::/8", "fd12::1"));
+        assert!(!cidr_contains("fd00::/8", "[fe80::1]:7000"));
+        assert!(cidr_contains("::1/128", "[::1]:7000"));
+        assert!(!cidr_contains("::1/128", "[::2]:7000"));
+        assert!(cidr_contains("::/0", "[2001:db8::1]:7000"));
+    }
+
+    #[test]
+    fn matches_bans_in_the_order_of_the_targets() {
+        let ban = |id, kind: BanKind, value: &str| models::Ban {
+            id,
+            kind: kind.as_str().to_string(),
+            value: value.to_string(),
+            reason: String::new(),
+            created_at: chrono::NaiveDateTime::default(),
+        };
+        let bans = [
+            ban(1, BanKind::Subdomain, "n1.gaia"),
+            ban(2, BanKind::DeviceId, "dev1"),
+            ban(3, BanKind::ClientAddress, "10.0.0.0/8"),
+        ];
+        let found = |targets: &[(BanKind, &str)]| match_ban(&bans, targets).map(|ban| ban.id);
+
+        assert_eq!(
+            found(&[(BanKind::DeviceId, "dev1"), (BanKind::Subdomain, "n1.gaia")]),
+            Some(2)
+        );
+        assert_eq!(found(&[(BanKind::ClientAddress, "10.1.2.3:7000")]), Some(3));
+        assert_eq!(found(&[(BanKind::DeviceId, "n1.gaia")]), None);
+        assert_eq!(
+            found(&[(BanKind::Subdomain, ""), (BanKind::NodeId, "n1")]),
+            None
+        );
+    }
+
+    #[test]
+    fn never_matches_across_families_or_bad_addresses() {
+        assert!(!cidr_contains("0.0.0.0/0", "[::1]:7000"));
+        assert!(!cidr_contains("::/0", "10.0.0.1:7000"));
+        assert!(!cidr_contains("::ffff:10.0.0.0/104", "10.0.0.1:7000"));
+        assert!(!cidr_contains("10.0.0.0/8", ""));
+        assert!(!cidr_contains("10.0.0.0/8", "10.0.0.1:port"));
+        assert!(!cidr_contains("10.0.0.0/8", "localhost:7000"));
+    }
+}
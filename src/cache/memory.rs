@@ -0,0 +1,151 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use gaia_hub::*;
+
+use crate::cache::{NodeChange, RoutingCache};
+
+#[derive(Default)]
+struct Tables {
+    subdomains: HashMap<String, String>,
+    // The window and the count of the user connections of each subdomain
+    user_conns: HashMap<String, (i64, i64)>,
+    domains: HashMap<String, BTreeMap<String, i64>>,
+    // The expiry of each taken lock
+    locks: HashMap<String, Instant>,
+}
+
+/// The routing cache of a single hub, kept in the process and lost on restart.
+/// Gateways can't read it, they resolve the nodes through the hub instead.
+#[derive(Default)]
+pub struct MemoryCache {
+    tables: Mutex<Tables>,
+}
+
+impl MemoryCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn tables(&self) -> MutexGuard<'_, Tables> {
+        self.tables.lock().unwrap()
+    }
+}
+
+impl RoutingCache for MemoryCache {
+    fn is_shared(&self) -> bool {
+        false
+    }
+
+    fn acquire_lock(&self, lock_key: &str, _value: &str, lock_duration: u64) -> Result<bool> {
+        let now = Instant::now();
+        let mut tables = self.tables();
+        match tables.locks.get(lock_key) {
+            Some(expiry) if *expiry > now => Ok(false),
+            _ => {
+                let expiry = now + Duration::from_secs(lock_duration);
+                tables.locks.insert(lock_key.to_string(), expiry);
+                Ok(true)
+            }
+        }
+    }
+
+    fn set_subdomain_frps_id(&self, subdomain: &str, frps_id: &str) -> Result<()> {
+        self.tables()
+            .subdomains
+            .insert(subdomain.to_string(), frps_id.to_string());
+        Ok(())
+    }
+
+    fn del_subdomain(&self, subdomain: &str) -> Result<()> {
+        self.tables().subdomains.remove(subdomain);
+        Ok(())
+    }
+
+    fn incr_user_conns(&self, subdomain: &str, window_secs: u64) -> Result<i64> {
+        let window = chrono::Utc::now().timestamp() / window_secs as i64;
+        let mut tables = self.tables();
+        let entry = tables
+            .user_conns
+            .entry(subdomain.to_string())
+            .or_insert((window, 0));
+        if entry.0 != window {
+            *entry = (window, 0);
+        }
+        entry.1 += 1;
+        Ok(entry.1)
+    }
+
+    fn recent_user_conns(&self, subdomain: &str, window_secs: u64) -> Result<i64> {
+        let window = chrono::Utc::now().timestamp() / window_secs as i64;
+        // Only the count of the last window with connections is kept
+        Ok(match self.tables().user_conns.get(subdomain) {
+            Some((w, count)) if *w >= window - 1 => *count,
+            _ => 0,
+        })
+    }
+
+    fn nodes_join(&self, domain: &str, node_id: &str, weight: i64) -> Result<()> {
+        self.tables()
+            .domains
+            .entry(domain.to_string())
+            .or_default()
+            .insert(node_id.to_string(), weight);
+        Ok(())
+    }
+
+    fn node_lefts(&self, domain: &str, node_id: &str) -> Result<()> {
+        let mut tables = self.tables();
+        if let Some(nodes) = tables.domains.get_mut(domain) {
+            nodes.remove(node_id);
+            if nodes.is_empty() {
+                tables.domains.remove(domain);
+            }
+        }
+        Ok(())
+    }
+
+    fn get_domain_nodes(&self, domain: &str) -> Result<Vec<(String, i64)>> {
+        let mut nodes: Vec<(String, i64)> = self
+            .tables()
+            .domains
+            .get(domain)
+            .map(|nodes| nodes.clone().into_iter().collect())
+            .unwrap_or_default();
+        // The same order as the sorted set in redis
+        nodes.sort_by(|a, b| a.1.cmp(&b.1).then_with(|| a.0.cmp(&b.0)));
+        Ok(nodes)
+    }
+
+    fn patch_domain_nodes(&self, domain: &str, changes: &[NodeChange]) -> Result<usize> {
+        let mut tables = self.tables();
+        let nodes = tables.domains.entry(domain.to_string()).or_default();
+        let mut applied = 0;
+        for change in changes {
+            if nodes.get(&change.node_id).copied() != change.from {
+                continue;
+            }
+            match change.to {
+                Some(weight) => nodes.insert(change.node_id.clone(), weight),
+                None => nodes.remove(&change.node_id),
+            };
+            applied += 1;
+        }
+        if nodes.is_empty() {
+            tables.domains.remove(domain);
+        }
+        Ok(applied)
+    }
+
+    fn routed_domains(&self) -> Result<Vec<String>> {
+        let mut domains: Vec<String> = self.tables().domains.keys().cloned().collect();
+        domains.sort();
+        Ok(domains)
+    }
+
+    fn drop_domain(&self, domain: &str) -> Result<()> {
+        self.tables().domains.remove(domain);
+        Ok(())
+    }
+}
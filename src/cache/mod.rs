@@ -0,0 +1,77 @@
+use lazy_static::lazy_static;
+use serde::Serialize;
+use std::env;
+
+use gaia_hub::*;
+
+mod memory;
+mod redis;
+
+pub use self::memory::MemoryCache;
+pub use self::redis::{check_runtime, RedisCache};
+
+lazy_static! {
+    // The tests share one routing in memory
+    static ref CACHE: Box<dyn RoutingCache> = match env::var("REDIS_URL") {
+        _ if cfg!(test) => Box::new(MemoryCache::new()),
+        Ok(redis_url) => connect(&redis_url),
+        Err(_) => {
+            log::warn!("No REDIS_URL in env, keeping the routing in memory");
+            Box::new(MemoryCache::new())
+        }
+    };
+}
+
+/// The routing cache picked by `REDIS_URL`.
+pub fn cache() -> &'static dyn RoutingCache {
+    CACHE.as_ref()
+}
+
+/// Open the routing cache of a url, `memory://` keeps it in the process and anything else
+/// is a redis url, connected on first use.
+pub fn connect(url: &str) -> Box<dyn RoutingCache> {
+    match url.split_once("://") {
+        Some(("memory", _)) => Box::new(MemoryCache::new()),
+        _ => Box::new(RedisCache::new(url)),
+    }
+}
+
+/// A change of the routing of one node, applied only if the node is still at `from`.
+/// `None` stands for a node out of the routing.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct NodeChange {
+    pub node_id: String,
+    pub from: Option<i64>,
+    pub to: Option<i64>,
+}
+
+/// Where the gateways find the frps of each subdomain and the weighted nodes of each domain,
+/// along with the counters and locks the hub instances share.
+pub trait RoutingCache: Send + Sync {
+    /// Whether other hub instances see the same cache, as needed to run with `--cluster`.
+    fn is_shared(&self) -> bool;
+
+    /// Take the lock of a cronjob for `lock_duration` seconds, false if it is already taken.
+    fn acquire_lock(&self, lock_key: &str, value: &str, lock_duration: u64) -> Result<bool>;
+
+    fn set_subdomain_frps_id(&self, subdomain: &str, frps_id: &str) -> Result<()>;
+    fn del_subdomain(&self, subdomain: &str) -> Result<()>;
+    /// Count a new user connection of the subdomain and return the count in the current window.
+    fn incr_user_conns(&self, subdomain: &str, window_secs: u64) -> Result<i64>;
+    /// The user connections of the subdomain counted in the current and the previous window.
+    fn recent_user_conns(&self, subdomain: &str, window_secs: u64) -> Result<i64>;
+
+    /// Add the node to the routing of the domain, or change its weight.
+    fn nodes_join(&self, domain: &str, node_id: &str, weight: i64) -> Result<()>;
+    /// Remove the node from the routing of the domain.
+    fn node_lefts(&self, domain: &str, node_id: &str) -> Result<()>;
+    /// The nodes in the routing of the domain with their weights, lightest first.
+    fn get_domain_nodes(&self, domain: &str) -> Result<Vec<(String, i64)>>;
+    /// Apply the changes to the routing of the domain at once, skipping the nodes which
+    /// moved away from their `from` meanwhile. Returns the number of changes applied.
+    fn patch_domain_nodes(&self, domain: &str, changes: &[NodeChange]) -> Result<usize>;
+    /// The domains having a routing in the cache.
+    fn routed_domains(&self) -> Result<Vec<String>>;
+    /// Remove the whole routing of the domain.
+    fn drop_domain(&self, domain: &str) -> Result<()>;
+}
@@ -0,0 +1,727 @@
+use lazy_static::lazy_static;
+use redis::aio::{ConnectionLike, ConnectionManager, ConnectionManagerConfig};
+use redis::cluster::ClusterClientBuilder;
+use redis::cluster_async::ClusterConnection;
+use redis::cluster_routing::{RoutingInfo, SingleNodeRoutingInfo};
+use redis::sentinel::{Sentinel, SentinelNodeConnectionInfo};
+use redis::{
+    cmd, from_redis_value, AsyncCommands, Cmd, ErrorKind, ExistenceCheck, FromRedisValue,
+    IntoConnectionInfo, Pipeline, RedisFuture, RedisResult, Script, SetExpiry, SetOptions, Value,
+};
+use serde::Serialize;
+use std::collections::BTreeSet;
+use std::env;
+use std::future::Future;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::sync::{Mutex, OnceCell};
+
+use gaia_hub::*;
+
+use crate::cache::{NodeChange, RoutingCache};
+
+lazy_static! {
+    // Milliseconds to wait for a new connection to redis
+    static ref REDIS_CONNECT_TIMEOUT_MS: u64 = env::var("REDIS_CONNECT_TIMEOUT_MS")
+        .unwrap_or_else(|_| String::from("1000"))
+        .parse()
+        .expect("REDIS_CONNECT_TIMEOUT_MS must be a number");
+    // Milliseconds to wait for the reply of a command
+    static ref REDIS_RESPONSE_TIMEOUT_MS: u64 = env::var("REDIS_RESPONSE_TIMEOUT_MS")
+        .unwrap_or_else(|_| String::from("3000"))
+        .parse()
+        .expect("REDIS_RESPONSE_TIMEOUT_MS must be a number");
+    // Attempts to reconnect a lost connection, backing off exponentially up to the max delay
+    static ref REDIS_RECONNECT_RETRIES: usize = env::var("REDIS_RECONNECT_RETRIES")
+        .unwrap_or_else(|_| String::from("3"))
+        .parse()
+        .expect("REDIS_RECONNECT_RETRIES must be a number");
+    static ref REDIS_RECONNECT_MAX_DELAY_MS: u64 = env::var("REDIS_RECONNECT_MAX_DELAY_MS")
+        .unwrap_or_else(|_| String::from("1000"))
+        .parse()
+        .expect("REDIS_RECONNECT_MAX_DELAY_MS must be a number");
+
+    // Put before every key, so several environments can share one redis
+    static ref REDIS_KEY_PREFIX: String = {
+        let prefix = env::var("REDIS_KEY_PREFIX").unwrap_or_default();
+        // Braces would break the hash tags keeping the keys of a domain together
+        assert!(
+            !prefix.contains(['{', '}']),
+            "REDIS_KEY_PREFIX must not contain braces"
+        );
+        prefix
+    };
+
+    // A migration aid: keep writing the running sum layout of `{domain}_nodes_weights` for
+    // the consumers which still read it, until they all read the weights key. Once off, the
+    // legacy key is left as it is.
+    static ref REDIS_LEGACY_WEIGHTS: bool = env::var("REDIS_LEGACY_WEIGHTS")
+        .unwrap_or_else(|_| String::from("true"))
+        .parse()
+        .expect("REDIS_LEGACY_WEIGHTS must be true or false");
+
+    static ref SET_NODE_WEIGHT: Script = Script::new(&format!(
+        "{}\nredis.call('ZADD', KEYS[1], ARGV[3], ARGV[2])\n{}",
+        UPGRADE_LEGACY_WEIGHTS, WRITE_LEGACY_WEIGHTS
+    ));
+    static ref REMOVE_NODE: Script = Script::new(&format!(
+        "{}\nredis.call('ZREM', KEYS[1], ARGV[2])\n{}",
+        UPGRADE_LEGACY_WEIGHTS, WRITE_LEGACY_WEIGHTS
+    ));
+    // ARGV holds the `node_id`, `from` and `to` of each change after `legacy`, an empty
+    // weight standing for a node out of the routing
+    static ref PATCH_NODES: Script = Script::new(&format!(
+        r"{}
+        local applied = 0
+        for i = 2, #ARGV, 3 do
+            local current = redis.call('ZSCORE', KEYS[1], ARGV[i])
+            local expected = ARGV[i + 1]
+            if (not current and expected == '') or (current and tonumber(current) == tonumber(expected)) then
+                if ARGV[i + 2] == '' then
+                    redis.call('ZREM', KEYS[1], ARGV[i])
+                else
+                    redis.call('ZADD', KEYS[1], ARGV[i + 2], ARGV[i])
+                end
+                applied = applied + 1
+            end
+        end
+        {}
+        return applied",
+        UPGRADE_LEGACY_WEIGHTS, WRITE_LEGACY_WEIGHTS
+    ));
+    static ref READ_WEIGHTS: Script = Script::new(&format!(
+        "{}\nreturn redis.call('ZRANGE', KEYS[1], 0, -1, 'WITHSCORES')",
+        UPGRADE_LEGACY_WEIGHTS
+    ));
+    static ref INCR_WITH_EXPIRY: Script = Script::new(
+        r"
+        local count = redis.call('INCR', KEYS[1])
+        if count == 1 then
+            redis.call('EXPIRE', KEYS[1], ARGV[1])
+        end
+        return count
+        ",
+    );
+}
+
+// The routing weights of a domain live in a sorted set scored by the weight of each node,
+// so a node joins, changes or leaves in O(log n) and zero weights are kept.
+//
+// The weight scripts get the weights key and the legacy key, then ARGV `legacy`, `node_id`
+// and `weight`. A domain only known by its legacy key is converted first.
+const UPGRADE_LEGACY_WEIGHTS: &str = r"
+if redis.call('EXISTS', KEYS[1]) == 0 then
+    local legacy = redis.call('ZRANGE', KEYS[2], 0, -1, 'WITHSCORES')
+    local previous = 0
+    for i = 1, #legacy, 2 do
+        local score = tonumber(legacy[i + 1])
+        redis.call('ZADD', KEYS[1], score - previous, legacy[i])
+        previous = score
+    end
+end
+";
+
+// Rebuild the legacy key, where each score is the running sum of the weights.
+// Nodes without weight are left out, as they would share the score of the previous node.
+// Without `legacy`, the key is never touched, so the consumers still reading it keep routing.
+const WRITE_LEGACY_WEIGHTS: &str = r"
+if ARGV[1] == '1' then
+    redis.call('DEL', KEYS[2])
+    local members = redis.call('ZRANGE', KEYS[1], 0, -1, 'WITHSCORES')
+    local total = 0
+    for i = 1, #members, 2 do
+        local weight = tonumber(members[i + 1])
+        if weight > 0 then
+            total = total + weight
+            redis.call('ZADD', KEYS[2], total, members[i])
+        end
+    end
+end
+";
+
+/// A multiplexed connection to redis, which reconnects by itself.
+///
+/// `redis://` and `unix://` urls go to a single server,
+/// `redis+cluster://[auth@]host1:port,host2:port` to a cluster and
+/// `redis+sentinel://[auth@]host1:port,host2:port/service_name[/db]` to the master the
+/// sentinels elect, the auth being the one of the master.
+#[derive(Clone)]
+pub enum RedisConn {
+    Single(ConnectionManager),
+    Cluster(ClusterConnection),
+    Sentinel(SentinelConn),
+}
+
+impl RedisConn {
+    async fn open(redis_url: &str) -> Result<Self> {
+        if let Some(rest) = redis_url.strip_prefix("redis+cluster://") {
+            let (auth, hosts, _) = split_hosts(rest);
+            let nodes: Vec<String> = hosts
+                .iter()
+                .map(|host| format!("redis://{}{}", auth, host))
+                .collect();
+            let client = ClusterClientBuilder::new(nodes)
+                .connection_timeout(Duration::from_millis(*REDIS_CONNECT_TIMEOUT_MS))
+                .response_timeout(Duration::from_millis(*REDIS_RESPONSE_TIMEOUT_MS))
+                .retries(*REDIS_RECONNECT_RETRIES as u32)
+                .max_retry_wait(*REDIS_RECONNECT_MAX_DELAY_MS)
+                .build()?;
+            return Ok(RedisConn::Cluster(client.get_async_connection().await?));
+        }
+        if let Some(rest) = redis_url.strip_prefix("redis+sentinel://") {
+            return Ok(RedisConn::Sentinel(SentinelConn::open(rest).await?));
+        }
+
+        let client = redis::Client::open(redis_url)?;
+        Ok(RedisConn::Single(
+            ConnectionManager::new_with_config(client, manager_config()).await?,
+        ))
+    }
+}
+
+impl RedisConn {
+    /// The keys matching the pattern, gathered from every master of a cluster.
+    async fn scan_match(&mut self, pattern: &str) -> Result<Vec<String>> {
+        let mut keys = vec![];
+        let RedisConn::Cluster(conn) = self else {
+            let mut cursor = 0;
+            loop {
+                let (next, batch): (u64, Vec<String>) =
+                    scan_cmd(cursor, pattern).query_async(self).await?;
+                keys.extend(batch);
+                match next {
+                    0 => return Ok(keys),
+                    _ => cursor = next,
+                }
+            }
+        };
+
+        for (host, port) in cluster_masters(conn).await? {
+            let node = RoutingInfo::SingleNode(SingleNodeRoutingInfo::ByAddress { host, port });
+            let mut cursor = 0;
+            loop {
+                let reply = conn
+                    .route_command(&scan_cmd(cursor, pattern), node.clone())
+                    .await?;
+                let (next, batch): (u64, Vec<String>) = from_redis_value(&reply)?;
+                keys.extend(batch);
+                match next {
+                    0 => break,
+                    _ => cursor = next,
+                }
+            }
+        }
+        Ok(keys)
+    }
+}
+
+fn scan_cmd(cursor: u64, pattern: &str) -> Cmd {
+    let mut scan = cmd("SCAN");
+    scan.arg(cursor)
+        .arg("MATCH")
+        .arg(pattern)
+        .arg("COUNT")
+        .arg(500);
+    scan
+}
+
+// The address of the master of each slot range, as told by `CLUSTER SLOTS`
+async fn cluster_masters(conn: &mut ClusterConnection) -> Result<BTreeSet<(String, u16)>> {
+    let reply = conn
+        .route_command(
+            &cmd("CLUSTER").arg("SLOTS").clone(),
+            RoutingInfo::SingleNode(SingleNodeRoutingInfo::Random),
+        )
+        .await?;
+    let mut masters = BTreeSet::new();
+    for range in from_redis_value::<Vec<Vec<Value>>>(&reply)? {
+        if let Some(master) = range.get(2) {
+            let master: Vec<Value> = from_redis_value(master)?;
+            if let (Some(host), Some(port)) = (master.first(), master.get(1)) {
+                masters.insert((from_redis_value(host)?, from_redis_value(port)?));
+            }
+        }
+    }
+    Ok(masters)
+}
+
+impl ConnectionLike for RedisConn {
+    fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, Value> {
+        match self {
+            RedisConn::Single(conn) => conn.req_packed_command(cmd),
+            RedisConn::Cluster(conn) => conn.req_packed_command(cmd),
+            RedisConn::Sentinel(conn) => conn.req_packed_command(cmd),
+        }
+    }
+
+    fn req_packed_commands<'a>(
+        &'a mut self,
+        cmd: &'a Pipeline,
+        offset: usize,
+        count: usize,
+    ) -> RedisFuture<'a, Vec<Value>> {
+        match self {
+            RedisConn::Single(conn) => conn.req_packed_commands(cmd, offset, count),
+            RedisConn::Cluster(conn) => conn.req_packed_commands(cmd, offset, count),
+            RedisConn::Sentinel(conn) => conn.req_packed_commands(cmd, offset, count),
+        }
+    }
+
+    fn get_db(&self) -> i64 {
+        match self {
+            RedisConn::Single(conn) => conn.get_db(),
+            RedisConn::Cluster(conn) => conn.get_db(),
+            RedisConn::Sentinel(conn) => conn.get_db(),
+        }
+    }
+}
+
+/// The connection to the master of a sentinel service, which asks the sentinels again
+/// once the master is gone or demoted.
+#[derive(Clone)]
+pub struct SentinelConn {
+    sentinel: Arc<Mutex<Sentinel>>,
+    service_name: Arc<str>,
+    node_info: SentinelNodeConnectionInfo,
+    master: Arc<RwLock<ConnectionManager>>,
+}
+
+impl SentinelConn {
+    async fn open(rest: &str) -> Result<Self> {
+        let (auth, hosts, path) = split_hosts(rest);
+        let (service_name, db) = path.split_once('/').unwrap_or((path, "0"));
+        if service_name.is_empty() {
+            return Err("No service name in the redis sentinel url".into());
+        }
+        let sentinels: Vec<String> = hosts
+            .iter()
+            .map(|host| format!("redis://{}", host))
+            .collect();
+        // Only the database and the auth of this url are used for the master
+        let master_info = format!("redis://{}{}/{}", auth, hosts[0], db).into_connection_info()?;
+        let node_info = SentinelNodeConnectionInfo {
+            tls_mode: None,
+            redis_connection_info: Some(master_info.redis),
+        };
+
+        let mut sentinel = Sentinel::build(sentinels)?;
+        let master = Self::connect_master(&mut sentinel, service_name, &node_info).await?;
+        Ok(SentinelConn {
+            sentinel: Arc::new(Mutex::new(sentinel)),
+            service_name: service_name.into(),
+            node_info,
+            master: Arc::new(RwLock::new(master)),
+        })
+    }
+
+    async fn connect_master(
+        sentinel: &mut Sentinel,
+        service_name: &str,
+        node_info: &SentinelNodeConnectionInfo,
+    ) -> RedisResult<ConnectionManager> {
+        let client = sentinel
+            .async_master_for(service_name, Some(node_info))
+            .await?;
+        ConnectionManager::new_with_config(client, manager_config()).await
+    }
+
+    // Follow a failover if the error says the master moved
+    async fn check_failover<T>(&self, result: &RedisResult<T>) {
+        let err = match result {
+            Err(err) => err,
+            Ok(_) => return,
+        };
+        if !(err.kind() == ErrorKind::ReadOnly
+            || err.is_io_error()
+            || err.is_connection_refusal()
+            || err.is_connection_dropped()
+            || err.is_timeout())
+        {
+            return;
+        }
+
+        let mut sentinel = self.sentinel.lock().await;
+        match Self::connect_master(&mut sentinel, &self.service_name, &self.node_info).await {
+            Ok(master) => {
+                log::warn!("Reconnected to the master of {}", self.service_name);
+                *self.master.write().unwrap() = master;
+            }
+            Err(e) => {
+                log::error!(
+                    "Failed to find the master of {}. Error msg: {}",
+                    self.service_name,
+                    e
+                );
+            }
+        }
+    }
+}
+
+impl ConnectionLike for SentinelConn {
+    fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, Value> {
+        Box::pin(async move {
+            let mut master = self.master.read().unwrap().clone();
+            let result = master.req_packed_command(cmd).await;
+            self.check_failover(&result).await;
+            result
+        })
+    }
+
+    fn req_packed_commands<'a>(
+        &'a mut self,
+        cmd: &'a Pipeline,
+        offset: usize,
+        count: usize,
+    ) -> RedisFuture<'a, Vec<Value>> {
+        Box::pin(async move {
+            let mut master = self.master.read().unwrap().clone();
+            let result = master.req_packed_commands(cmd, offset, count).await;
+            self.check_failover(&result).await;
+            result
+        })
+    }
+
+    fn get_db(&self) -> i64 {
+        self.master.read().unwrap().get_db()
+    }
+}
+
+fn manager_config() -> ConnectionManagerConfig {
+    ConnectionManagerConfig::new()
+        .set_connection_timeout(Duration::from_millis(*REDIS_CONNECT_TIMEOUT_MS))
+        .set_response_timeout(Duration::from_millis(*REDIS_RESPONSE_TIMEOUT_MS))
+        .set_number_of_retries(*REDIS_RECONNECT_RETRIES)
+        .set_max_delay(*REDIS_RECONNECT_MAX_DELAY_MS)
+}
+
+// Split `[auth@]host1:port,host2:port[/path]` into the auth with its `@`, the hosts and the path
+fn split_hosts(rest: &str) -> (&str, Vec<&str>, &str) {
+    let (authority, path) = rest.split_once('/').unwrap_or((rest, ""));
+    let (auth, hosts) = match authority.rfind('@') {
+        Some(i) => authority.split_at(i + 1),
+        None => ("", authority),
+    };
+    (auth, hosts.split(',').collect(), path)
+}
+
+/// The keys moved by a change of the key prefix.
+#[derive(Debug, Default, Serialize)]
+pub struct MovedKeys {
+    pub moved: usize,
+    // Already set under the new prefix, so left under the old one
+    pub kept: usize,
+    pub missing: usize,
+}
+
+/// The routing cache kept in redis, shared by every hub instance using it.
+pub struct RedisCache {
+    redis_url: String,
+    prefix: String,
+    // Opened on first use
+    conn: OnceCell<RedisConn>,
+}
+
+impl RedisCache {
+    /// The cache under the `REDIS_KEY_PREFIX` of the environment.
+    pub fn new(redis_url: &str) -> Self {
+        Self::with_prefix(redis_url, &REDIS_KEY_PREFIX)
+    }
+
+    pub fn with_prefix(redis_url: &str, prefix: &str) -> Self {
+        RedisCache {
+            redis_url: redis_url.to_string(),
+            prefix: prefix.to_string(),
+            conn: OnceCell::new(),
+        }
+    }
+
+    /// The shared connection. Clones are cheap and share the same socket.
+    pub async fn connection(&self) -> Result<RedisConn> {
+        let conn = self
+            .conn
+            .get_or_try_init(|| RedisConn::open(&self.redis_url))
+            .await?;
+        Ok(conn.clone())
+    }
+
+    // Run one of the weight scripts on the routing keys of the domain
+    async fn invoke_weights_script<T: FromRedisValue>(
+        &self,
+        script: &Script,
+        domain: &str,
+        node_id: &str,
+        weight: i64,
+    ) -> Result<T> {
+        let mut conn = self.connection().await?;
+        Ok(script
+            .key(compose_weights_key_name(&self.prefix, domain))
+            .key(compose_key_name(&self.prefix, domain))
+            .arg(*REDIS_LEGACY_WEIGHTS)
+            .arg(node_id)
+            .arg(weight)
+            .invoke_async(&mut conn)
+            .await?)
+    }
+
+    /// Move the keys of the domains and of the subdomains from `from_prefix` to the prefix
+    /// of this cache, keeping their expiry.
+    pub fn move_keys_from(
+        &self,
+        from_prefix: &str,
+        domains: &[String],
+        subdomains: &[String],
+    ) -> Result<MovedKeys> {
+        let names = domains
+            .iter()
+            .flat_map(|domain| {
+                [
+                    (
+                        compose_key_name(from_prefix, domain),
+                        compose_key_name(&self.prefix, domain),
+                    ),
+                    (
+                        compose_weights_key_name(from_prefix, domain),
+                        compose_weights_key_name(&self.prefix, domain),
+                    ),
+                ]
+            })
+            .chain(subdomains.iter().map(|subdomain| {
+                (
+                    format!("{}{}", from_prefix, subdomain),
+                    format!("{}{}", self.prefix, subdomain),
+                )
+            }));
+
+        block_on(async {
+            let mut conn = self.connection().await?;
+            let mut moved = MovedKeys::default();
+            for (from, to) in names {
+                let dump: Option<Vec<u8>> = cmd("DUMP").arg(&from).query_async(&mut conn).await?;
+                let Some(dump) = dump else {
+                    moved.missing += 1;
+                    continue;
+                };
+                // -1 for no expiry, which RESTORE takes as 0
+                let ttl: i64 = conn.pttl(&from).await?;
+                let restored: RedisResult<()> = cmd("RESTORE")
+                    .arg(&to)
+                    .arg(ttl.max(0))
+                    .arg(dump)
+                    .query_async(&mut conn)
+                    .await;
+                match restored {
+                    Ok(()) => {
+                        conn.del::<_, i32>(&from).await?;
+                        moved.moved += 1;
+                    }
+                    Err(e) if e.code() == Some("BUSYKEY") => moved.kept += 1,
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            Ok(moved)
+        })
+    }
+}
+
+/// Refuse to run on a runtime the redis calls can't be waited on from, as `block_in_place`
+/// panics on the current thread runtime.
+pub fn check_runtime() -> Result<()> {
+    match tokio::runtime::Handle::try_current().map(|handle| handle.runtime_flavor()) {
+        Ok(tokio::runtime::RuntimeFlavor::MultiThread) => Ok(()),
+        _ => Err("The redis cache needs the multi thread tokio runtime".into()),
+    }
+}
+
+// Wait for a redis call from the blocking code of the handlers and cronjobs, on the multi
+// thread runtime checked by `check_runtime`
+fn block_on<F: Future>(future: F) -> F::Output {
+    tokio::task::block_in_place(|| tokio::runtime::Handle::current().block_on(future))
+}
+
+fn compose_user_conns_key(prefix: &str, subdomain: &str, window: i64) -> String {
+    format!("{}{}_user_conns_{}", prefix, subdomain, window)
+}
+
+// The legacy key of the routing weights
+fn compose_key_name(prefix: &str, domain: &str) -> String {
+    format!("{}{}_nodes_weights", prefix, domain)
+}
+
+// The weights key is tagged with the legacy key name, so both land in the same cluster slot
+fn compose_weights_key_name(prefix: &str, domain: &str) -> String {
+    format!("{{{}}}:weights", compose_key_name(prefix, domain))
+}
+
+// Match the prefix literally in a SCAN pattern
+fn escape_pattern(prefix: &str) -> String {
+    prefix.chars().fold(String::new(), |mut escaped, c| {
+        if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+        escaped
+    })
+}
+
+impl RoutingCache for RedisCache {
+    fn is_shared(&self) -> bool {
+        true
+    }
+
+    fn acquire_lock(&self, lock_key: &str, value: &str, lock_duration: u64) -> Result<bool> {
+        block_on(async {
+            let mut conn = self.connection().await?;
+            let opts = SetOptions::default()
+                .conditional_set(ExistenceCheck::NX)
+                .get(true)
+                .with_expiration(SetExpiry::EX(lock_duration));
+            let lock_key = format!("{}{}", self.prefix, lock_key);
+            let previous: Option<String> = conn.set_options(lock_key, value, opts).await?;
+            Ok(previous.is_none())
+        })
+    }
+
+    fn set_subdomain_frps_id(&self, subdomain: &str, frps_id: &str) -> Result<()> {
+        block_on(async {
+            let mut conn = self.connection().await?;
+            let key = format!("{}{}", self.prefix, subdomain);
+            conn.set::<&str, &str, String>(&key, frps_id).await?;
+            Ok(())
+        })
+    }
+
+    fn del_subdomain(&self, subdomain: &str) -> Result<()> {
+        block_on(async {
+            let mut conn = self.connection().await?;
+            let key = format!("{}{}", self.prefix, subdomain);
+            conn.del::<&str, i32>(&key).await?;
+            Ok(())
+        })
+    }
+
+    fn incr_user_conns(&self, subdomain: &str, window_secs: u64) -> Result<i64> {
+        block_on(async {
+            let mut conn = self.connection().await?;
+            let window = chrono::Utc::now().timestamp() / window_secs as i64;
+            let key = compose_user_conns_key(&self.prefix, subdomain, window);
+            let count: i64 = INCR_WITH_EXPIRY
+                .key(&key)
+                .arg(window_secs * 2)
+                .invoke_async(&mut conn)
+                .await?;
+            Ok(count)
+        })
+    }
+
+    fn recent_user_conns(&self, subdomain: &str, window_secs: u64) -> Result<i64> {
+        block_on(async {
+            let mut conn = self.connection().await?;
+            let window = chrono::Utc::now().timestamp() / window_secs as i64;
+            let mut count = 0;
+            // One key at a time, they may live on different cluster nodes
+            for window in [window - 1, window] {
+                let key = compose_user_conns_key(&self.prefix, subdomain, window);
+                let conns: Option<i64> = cmd("GET").arg(&key).query_async(&mut conn).await?;
+                count += conns.unwrap_or(0);
+            }
+            Ok(count)
+        })
+    }
+
+    fn nodes_join(&self, domain: &str, node_id: &str, weight: i64) -> Result<()> {
+        block_on(self.invoke_weights_script(&SET_NODE_WEIGHT, domain, node_id, weight))
+    }
+
+    fn node_lefts(&self, domain: &str, node_id: &str) -> Result<()> {
+        block_on(self.invoke_weights_script(&REMOVE_NODE, domain, node_id, 0))
+    }
+
+    fn get_domain_nodes(&self, domain: &str) -> Result<Vec<(String, i64)>> {
+        block_on(self.invoke_weights_script(&READ_WEIGHTS, domain, "", 0))
+    }
+
+    fn patch_domain_nodes(&self, domain: &str, changes: &[NodeChange]) -> Result<usize> {
+        block_on(async {
+            let mut conn = self.connection().await?;
+            let mut invocation = PATCH_NODES.prepare_invoke();
+            invocation
+                .key(compose_weights_key_name(&self.prefix, domain))
+                .key(compose_key_name(&self.prefix, domain))
+                .arg(*REDIS_LEGACY_WEIGHTS);
+            for change in changes {
+                let weight =
+                    |weight: Option<i64>| weight.map(|w| w.to_string()).unwrap_or_default();
+                invocation
+                    .arg(&change.node_id)
+                    .arg(weight(change.from))
+                    .arg(weight(change.to));
+            }
+            Ok(invocation.invoke_async(&mut conn).await?)
+        })
+    }
+
+    fn routed_domains(&self) -> Result<Vec<String>> {
+        block_on(async {
+            let mut conn = self.connection().await?;
+            let prefix = escape_pattern(&self.prefix);
+            let mut keys = conn
+                .scan_match(&format!("{}*_nodes_weights", prefix))
+                .await?;
+            keys.extend(
+                conn.scan_match(&format!("{{{}*_nodes_weights}}:weights", prefix))
+                    .await?,
+            );
+
+            let domains: BTreeSet<String> = keys
+                .iter()
+                .filter_map(|key| {
+                    let key = key.strip_prefix('{').unwrap_or(key);
+                    let key = key.strip_suffix("}:weights").unwrap_or(key);
+                    key.strip_prefix(self.prefix.as_str())?
+                        .strip_suffix("_nodes_weights")
+                })
+                // Leave out the keys of other environments sharing an overlapping prefix
+                .filter(|domain| {
+                    !domain.is_empty()
+                        && domain
+                            .chars()
+                            .all(|c| c.is_alphanumeric() || c == '_' || c == '-')
+                })
+                .map(String::from)
+                .collect();
+            Ok(domains.into_iter().collect())
+        })
+    }
+
+    fn drop_domain(&self, domain: &str) -> Result<()> {
+        block_on(async {
+            let mut conn = self.connection().await?;
+            let keys = [
+                compose_weights_key_name(&self.prefix, domain),
+                compose_key_name(&self.prefix, domain),
+            ];
+            conn.del::<_, i32>(&keys).await?;
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn refuses_the_current_thread_runtime() {
+        assert!(check_runtime().is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn accepts_the_multi_thread_runtime() {
+        assert!(check_runtime().is_ok());
+    }
+
+    #[test]
+    fn refuses_to_run_outside_of_a_runtime() {
+        assert!(check_runtime().is_err());
+    }
+}
@@ -0,0 +1,832 @@
+use chrono::NaiveDateTime;
+use std::collections::BTreeMap;
+use std::sync::{Mutex, MutexGuard};
+
+use gaia_hub::*;
+
+use crate::db::{is_active, lived_secs, DomainNodeChange, NodeFilter, NodeStore, PoolStats};
+use crate::models;
+
+#[derive(Default)]
+struct Tables {
+    devices: Vec<models::Device>,
+    nodes: Vec<models::Node>,
+    domain_nodes: Vec<models::DomainNodes>,
+    // Keyed by name to list them in order
+    domains: BTreeMap<String, models::Domain>,
+    bans: Vec<models::Ban>,
+    // Keyed by frps_id to list them in order
+    frps_servers: BTreeMap<String, models::FrpsServer>,
+    last_id: i64,
+}
+
+impl Tables {
+    fn next_id(&mut self) -> i64 {
+        self.last_id += 1;
+        self.last_id
+    }
+
+    // Apply `update` to the nodes matching `filter` and count them
+    fn update_nodes(
+        &mut self,
+        filter: impl Fn(&models::Node) -> bool,
+        update: impl Fn(&mut models::Node),
+    ) -> usize {
+        let now = now();
+        let mut n = 0;
+        for node in self.nodes.iter_mut().filter(|node| filter(node)) {
+            update(node);
+            node.updated_at = now;
+            n += 1;
+        }
+        n
+    }
+}
+
+fn now() -> NaiveDateTime {
+    chrono::Utc::now().naive_utc()
+}
+
+/// A store keeping everything in the process, for tests and local runs.
+/// It honours the same unique keys as the sql schemas.
+#[derive(Default)]
+pub struct MemoryStore {
+    tables: Mutex<Tables>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn tables(&self) -> MutexGuard<'_, Tables> {
+        self.tables.lock().unwrap()
+    }
+}
+
+// The nodes in the order of their login times
+fn living_nodes<'a>(
+    nodes: impl Iterator<Item = &'a models::Node>,
+    lived_secs_at_least: u64,
+) -> Vec<&'a models::Node> {
+    let mut nodes: Vec<_> = nodes
+        .filter(|node| lived_secs(node) >= lived_secs_at_least as i64)
+        .collect();
+    nodes.sort_by_key(|node| node.login_time);
+    nodes
+}
+
+impl NodeStore for MemoryStore {
+    fn run_migrations(&self) -> Result<String> {
+        Ok(String::from("memory"))
+    }
+
+    fn pool_stats(&self) -> Option<PoolStats> {
+        None
+    }
+
+    fn create_device(&self, device: &models::NewDevice) -> Result<usize> {
+        let mut tables = self.tables();
+        if tables
+            .devices
+            .iter()
+            .any(|d| d.device_id == device.device_id)
+        {
+            return Err(format!("Duplicate device_id {}", device.device_id).into());
+        }
+        let now = now();
+        let id = tables.next_id();
+        tables.devices.push(models::Device {
+            id,
+            device_id: device.device_id.to_string(),
+            version: device.version.to_string(),
+            arch: device.arch.to_string(),
+            os: device.os.to_string(),
+            client_address: device.client_address.to_string(),
+            login_time: *device.login_time,
+            meta: device.meta.clone(),
+            created_at: now,
+            updated_at: now,
+            public_key: device.public_key.to_string(),
+            pending_public_key: String::new(),
+        });
+        Ok(1)
+    }
+
+    fn update_device(&self, device_id: &str, login_time: &NaiveDateTime) -> Result<usize> {
+        let mut tables = self.tables();
+        let now = now();
+        Ok(tables
+            .devices
+            .iter_mut()
+            .filter(|d| d.device_id == device_id)
+            .map(|d| {
+                d.login_time = *login_time;
+                d.updated_at = now;
+            })
+            .count())
+    }
+
+    fn update_device_public_key(&self, device_id: &str, public_key: &str) -> Result<usize> {
+        let mut tables = self.tables();
+        let now = now();
+        Ok(tables
+            .devices
+            .iter_mut()
+            .filter(|d| d.device_id == device_id)
+            .map(|d| {
+                d.public_key = public_key.to_string();
+                d.pending_public_key = String::new();
+                d.updated_at = now;
+            })
+            .count())
+    }
+
+    fn update_device_pending_public_key(&self, device_id: &str, public_key: &str) -> Result<usize> {
+        let mut tables = self.tables();
+        let now = now();
+        Ok(tables
+            .devices
+            .iter_mut()
+            .filter(|d| d.device_id == device_id)
+            .map(|d| {
+                d.pending_public_key = public_key.to_string();
+                d.updated_at = now;
+            })
+            .count())
+    }
+
+    fn query_devices_with_pending_key(&self) -> Result<Vec<models::Device>> {
+        let mut devices: Vec<models::Device> = self
+            .tables()
+            .devices
+            .iter()
+            .filter(|d| !d.pending_public_key.is_empty())
+            .cloned()
+            .collect();
+        devices.sort_by(|a, b| a.device_id.cmp(&b.device_id));
+        Ok(devices)
+    }
+
+    fn query_device_by_device_id(&self, device_id: &str) -> Result<Vec<models::Device>> {
+        Ok(self
+            .tables()
+            .devices
+            .iter()
+            .filter(|d| d.device_id == device_id)
+            .cloned()
+            .collect())
+    }
+
+    fn create_node_status(&self, node: &models::NewNode) -> Result<usize> {
+        let mut tables = self.tables();
+        if tables
+            .nodes
+            .iter()
+            .any(|n| n.node_id == node.node_id || n.subdomain == node.subdomain)
+        {
+            return Err(format!("Duplicate node {}", node.node_id).into());
+        }
+        let now = now();
+        let id = tables.next_id();
+        tables.nodes.push(models::Node {
+            id,
+            node_id: node.node_id.to_string(),
+            device_id: node.device_id.to_string(),
+            subdomain: node.subdomain.to_string(),
+            version: node.version.to_string(),
+            arch: node.arch.to_string(),
+            os: node.os.to_string(),
+            client_address: node.client_address.to_string(),
+            login_time: *node.login_time,
+            last_active_time: *node.last_active_time,
+            last_avail_time: None,
+            run_id: node.run_id.to_string(),
+            meta: node.meta.clone(),
+            node_version: String::new(),
+            chat_model: String::new(),
+            embedding_model: String::new(),
+            status: node.status.to_string(),
+            created_at: now,
+            updated_at: now,
+            frps_id: node.frps_id.to_string(),
+            country: String::new(),
+            subdivision: String::new(),
+            city: String::new(),
+            probe_latency_ms: None,
+        });
+        Ok(1)
+    }
+
+    fn update_node_status_more(&self, node: &models::NewNode) -> Result<usize> {
+        let mut tables = self.tables();
+        if tables
+            .nodes
+            .iter()
+            .any(|n| n.node_id != node.node_id && n.subdomain == node.subdomain)
+        {
+            return Err(format!("Duplicate subdomain {}", node.subdomain).into());
+        }
+        Ok(tables.update_nodes(
+            |n| n.node_id == node.node_id,
+            |n| {
+                n.device_id = node.device_id.to_string();
+                n.subdomain = node.subdomain.to_string();
+                n.version = node.version.to_string();
+                n.arch = node.arch.to_string();
+                n.os = node.os.to_string();
+                n.client_address = node.client_address.to_string();
+                n.status = node.status.to_string();
+                n.login_time = *node.login_time;
+                n.last_active_time = *node.last_active_time;
+                n.run_id = node.run_id.to_string();
+                n.meta = node.meta.clone();
+                n.frps_id = node.frps_id.to_string();
+            },
+        ))
+    }
+
+    fn update_online_node_last_active_time(
+        &self,
+        device_id: &str,
+        last_active_time: &NaiveDateTime,
+    ) -> Result<usize> {
+        Ok(self.tables().update_nodes(
+            |n| n.device_id == device_id && is_active(&n.status),
+            |n| n.last_active_time = *last_active_time,
+        ))
+    }
+
+    fn update_online_node_last_active_time_by_run_id(
+        &self,
+        run_id: &str,
+        last_active_time: &NaiveDateTime,
+    ) -> Result<usize> {
+        Ok(self.tables().update_nodes(
+            |n| n.run_id == run_id && is_active(&n.status),
+            |n| n.last_active_time = *last_active_time,
+        ))
+    }
+
+    fn update_node_active_status(
+        &self,
+        device_id: &str,
+        subdomain: &str,
+        last_active_time: &NaiveDateTime,
+        status: &str,
+    ) -> Result<usize> {
+        Ok(self.tables().update_nodes(
+            |n| n.device_id == device_id && n.subdomain == subdomain,
+            |n| {
+                n.last_active_time = *last_active_time;
+                n.status = status.to_string();
+            },
+        ))
+    }
+
+    fn update_node_avail_time_and_status(
+        &self,
+        node_id: &str,
+        last_avail_time: &NaiveDateTime,
+        status: &str,
+    ) -> Result<usize> {
+        Ok(self.tables().update_nodes(
+            |n| n.node_id == node_id,
+            |n| {
+                n.last_avail_time = Some(*last_avail_time);
+                n.status = status.to_string();
+            },
+        ))
+    }
+
+    fn update_node_status(&self, node_id: &str, status: &str) -> Result<usize> {
+        Ok(self
+            .tables()
+            .update_nodes(|n| n.node_id == node_id, |n| n.status = status.to_string()))
+    }
+
+    fn update_node_probe_latency(&self, node_id: &str, latency_ms: Option<i64>) -> Result<usize> {
+        Ok(self.tables().update_nodes(
+            |n| n.node_id == node_id,
+            |n| n.probe_latency_ms = latency_ms,
+        ))
+    }
+
+    fn update_nodes_status_by_device_id(&self, device_id: &str, status: &str) -> Result<usize> {
+        Ok(self.tables().update_nodes(
+            |n| n.device_id == device_id,
+            |n| n.status = status.to_string(),
+        ))
+    }
+
+    fn update_nodes_info_by_device_id(
+        &self,
+        device_id: &str,
+        node_version: &str,
+        chat_model: &str,
+        embedding_model: &str,
+    ) -> Result<usize> {
+        Ok(self.tables().update_nodes(
+            |n| n.device_id == device_id,
+            |n| {
+                n.node_version = node_version.to_string();
+                n.chat_model = chat_model.to_string();
+                n.embedding_model = embedding_model.to_string();
+            },
+        ))
+    }
+
+    fn update_node_location(
+        &self,
+        node_id: &str,
+        country: &str,
+        subdivision: &str,
+        city: &str,
+    ) -> Result<usize> {
+        Ok(self.tables().update_nodes(
+            |n| n.node_id == node_id,
+            |n| {
+                n.country = country.to_string();
+                n.subdivision = subdivision.to_string();
+                n.city = city.to_string();
+            },
+        ))
+    }
+
+    fn update_nodes_location_by_device_id(
+        &self,
+        device_id: &str,
+        country: &str,
+        subdivision: &str,
+        city: &str,
+    ) -> Result<usize> {
+        Ok(self.tables().update_nodes(
+            |n| n.device_id == device_id,
+            |n| {
+                n.country = country.to_string();
+                n.subdivision = subdivision.to_string();
+                n.city = city.to_string();
+            },
+        ))
+    }
+
+    fn query_node_by_node_id(&self, node_id: &str) -> Result<Option<models::Node>> {
+        Ok(self
+            .tables()
+            .nodes
+            .iter()
+            .find(|n| n.node_id == node_id)
+            .cloned())
+    }
+
+    fn query_nodes_by_device_id(&self, device_id: &str) -> Result<Vec<models::Node>> {
+        Ok(self
+            .tables()
+            .nodes
+            .iter()
+            .filter(|n| n.device_id == device_id)
+            .cloned()
+            .collect())
+    }
+
+    fn query_nodes_by_node_ids(&self, node_ids: &[String]) -> Result<Vec<models::Node>> {
+        Ok(self
+            .tables()
+            .nodes
+            .iter()
+            .filter(|n| node_ids.contains(&n.node_id))
+            .cloned()
+            .collect())
+    }
+
+    fn query_node_by_subdomain(&self, subdomain: &str) -> Result<Option<models::Node>> {
+        Ok(self
+            .tables()
+            .nodes
+            .iter()
+            .find(|n| n.subdomain == subdomain)
+            .cloned())
+    }
+
+    fn query_active_nodes(&self) -> Result<Vec<models::Node>> {
+        Ok(self
+            .tables()
+            .nodes
+            .iter()
+            .filter(|n| is_active(&n.status))
+            .cloned()
+            .collect())
+    }
+
+    fn query_active_nodes_by_frps_id(&self, frps_id: &str) -> Result<Vec<models::Node>> {
+        Ok(self
+            .tables()
+            .nodes
+            .iter()
+            .filter(|n| n.frps_id == frps_id && is_active(&n.status))
+            .cloned()
+            .collect())
+    }
+
+    fn count_active_nodes_by_frps_id(&self) -> Result<Vec<(String, i64)>> {
+        let mut counts: BTreeMap<String, i64> = BTreeMap::new();
+        for node in self.tables().nodes.iter().filter(|n| is_active(&n.status)) {
+            *counts.entry(node.frps_id.clone()).or_default() += 1;
+        }
+        Ok(counts.into_iter().collect())
+    }
+
+    fn query_nodes_by_filter(&self, filter: &NodeFilter) -> Result<Vec<models::NodeLimited>> {
+        Ok(self
+            .tables()
+            .nodes
+            .iter()
+            .filter(|n| filter.matches(n))
+            .cloned()
+            .map(models::NodeLimited::from)
+            .collect())
+    }
+
+    fn query_living_nodes(
+        &self,
+        lived_secs: u64,
+        page: i64,
+        size: i64,
+    ) -> Result<Vec<models::LivingNode>> {
+        let tables = self.tables();
+        let online = tables
+            .nodes
+            .iter()
+            .filter(|n| n.status == NODE_STATUS_ONLINE);
+        Ok(living_nodes(online, lived_secs)
+            .into_iter()
+            .skip((page * size).max(0) as usize)
+            .take(size.max(0) as usize)
+            .cloned()
+            .map(models::LivingNode::from)
+            .collect())
+    }
+
+    fn query_living_nodes_by_login_time(
+        &self,
+        lived_secs: u64,
+        page_size: i64,
+        earliest_login_time: &NaiveDateTime,
+    ) -> Result<Vec<models::LivingNode>> {
+        let tables = self.tables();
+        let active = tables
+            .nodes
+            .iter()
+            .filter(|n| is_active(&n.status) && n.login_time > *earliest_login_time);
+        Ok(living_nodes(active, lived_secs)
+            .into_iter()
+            .take(page_size.max(0) as usize)
+            .cloned()
+            .map(models::LivingNode::from)
+            .collect())
+    }
+
+    fn close_expired_nodes(&self, seconds_before: &NaiveDateTime) -> Result<usize> {
+        Ok(self.tables().update_nodes(
+            |n| n.status == NODE_STATUS_ONLINE && n.last_active_time < *seconds_before,
+            |n| n.status = NODE_STATUS_OFFLINE.to_string(),
+        ))
+    }
+
+    fn unavail_expired_nodes(&self, seconds_before: &NaiveDateTime) -> Result<usize> {
+        // Like sql, a node never been available doesn't match
+        Ok(self.tables().update_nodes(
+            |n| {
+                n.status == NODE_STATUS_ONLINE
+                    && n.last_avail_time.is_some_and(|t| t < *seconds_before)
+            },
+            |n| n.status = NODE_STATUS_UNAVAIL.to_string(),
+        ))
+    }
+
+    fn query_domain_nodes(&self, domain: &str) -> Result<Vec<models::DomainNodes>> {
+        Ok(self
+            .tables()
+            .domain_nodes
+            .iter()
+            .filter(|dn| dn.domain == domain)
+            .cloned()
+            .collect())
+    }
+
+    fn query_domain_node(
+        &self,
+        domain: &str,
+        node_id: &str,
+    ) -> Result<Option<models::DomainNodes>> {
+        Ok(self
+            .tables()
+            .domain_nodes
+            .iter()
+            .find(|dn| dn.domain == domain && dn.node_id == node_id)
+            .cloned())
+    }
+
+    fn query_domain_nodes_by_node_id(&self, node_id: &str) -> Result<Vec<models::DomainNodes>> {
+        let mut domain_nodes: Vec<models::DomainNodes> = self
+            .tables()
+            .domain_nodes
+            .iter()
+            .filter(|dn| dn.node_id == node_id)
+            .cloned()
+            .collect();
+        domain_nodes.sort_by(|a, b| a.domain.cmp(&b.domain));
+        Ok(domain_nodes)
+    }
+
+    fn apply_domain_node_changes(&self, changes: &[DomainNodeChange]) -> Result<()> {
+        let mut tables = self.tables();
+        // Work on a copy, kept only once every change applies
+        let mut domain_nodes = tables.domain_nodes.clone();
+        for change in changes {
+            match change {
+                DomainNodeChange::Insert(domain_node) => {
+                    if domain_nodes.iter().any(|dn| {
+                        dn.domain == domain_node.domain && dn.node_id == domain_node.node_id
+                    }) {
+                        return Err(format!("Duplicate domain node {}", domain_node.node_id).into());
+                    }
+                    domain_nodes.push(domain_node.clone());
+                }
+                DomainNodeChange::Update(domain_node) => match domain_nodes
+                    .iter_mut()
+                    .find(|dn| dn.domain == domain_node.domain && dn.node_id == domain_node.node_id)
+                {
+                    Some(dn) => *dn = domain_node.clone(),
+                    None => return Err(format!("No domain node {}", domain_node.node_id).into()),
+                },
+                DomainNodeChange::Delete { domain, node_id } => {
+                    let before = domain_nodes.len();
+                    domain_nodes.retain(|dn| !(dn.domain == *domain && dn.node_id == *node_id));
+                    if domain_nodes.len() == before {
+                        return Err(format!("No domain node {}", node_id).into());
+                    }
+                }
+            }
+        }
+        tables.domain_nodes = domain_nodes;
+        Ok(())
+    }
+
+    fn get_distinct_domains(&self) -> Result<Vec<String>> {
+        let mut domains: Vec<String> = self
+            .tables()
+            .domain_nodes
+            .iter()
+            .map(|dn| dn.domain.clone())
+            .collect();
+        domains.sort();
+        domains.dedup();
+        Ok(domains)
+    }
+
+    fn get_nodes_by_domain(&self, domain: &str) -> Result<Vec<(String, i64)>> {
+        let tables = self.tables();
+        Ok(tables
+            .domain_nodes
+            .iter()
+            .filter(|dn| dn.domain == domain)
+            .filter(|dn| {
+                tables
+                    .nodes
+                    .iter()
+                    .any(|n| n.node_id == dn.node_id && n.status == NODE_STATUS_ONLINE)
+            })
+            .map(|dn| (dn.node_id.clone(), dn.routed_weight()))
+            .collect())
+    }
+
+    fn query_user_conn_limits_by_subdomain(&self, subdomain: &str) -> Result<Vec<i64>> {
+        let tables = self.tables();
+        let node_ids: Vec<&str> = tables
+            .nodes
+            .iter()
+            .filter(|n| n.subdomain == subdomain && is_active(&n.status))
+            .map(|n| n.node_id.as_str())
+            .collect();
+        Ok(tables
+            .domain_nodes
+            .iter()
+            .filter(|dn| node_ids.contains(&dn.node_id.as_str()))
+            .filter_map(|dn| tables.domains.get(&dn.domain)?.user_conn_limit)
+            .collect())
+    }
+
+    fn query_draining_domain_nodes(&self) -> Result<Vec<models::DomainNodes>> {
+        Ok(self
+            .tables()
+            .domain_nodes
+            .iter()
+            .filter(|dn| dn.draining_since.is_some())
+            .cloned()
+            .collect())
+    }
+
+    fn update_draining_user_conn_time(
+        &self,
+        domain: &str,
+        node_id: &str,
+        time: &NaiveDateTime,
+    ) -> Result<usize> {
+        Ok(self
+            .tables()
+            .domain_nodes
+            .iter_mut()
+            .filter(|dn| dn.domain == domain && dn.node_id == node_id)
+            .filter(|dn| dn.draining_since.is_some())
+            .map(|dn| dn.last_user_conn_time = Some(*time))
+            .count())
+    }
+
+    fn delete_drained_domain_node(&self, domain: &str, node_id: &str) -> Result<usize> {
+        let mut tables = self.tables();
+        let before = tables.domain_nodes.len();
+        tables.domain_nodes.retain(|dn| {
+            !(dn.domain == domain && dn.node_id == node_id && dn.draining_since.is_some())
+        });
+        Ok(before - tables.domain_nodes.len())
+    }
+
+    fn insert_domain(&self, domain: &models::NewDomain) -> Result<usize> {
+        let mut tables = self.tables();
+        if tables.domains.contains_key(domain.name) {
+            return Err(format!("Duplicate domain {}", domain.name).into());
+        }
+        tables.domains.insert(
+            domain.name.to_string(),
+            models::Domain {
+                name: domain.name.to_string(),
+                owner: domain.owner.to_string(),
+                description: domain.description.to_string(),
+                chat_model: domain.chat_model.to_string(),
+                embedding_model: domain.embedding_model.to_string(),
+                status: domain.status.to_string(),
+                created_at: now(),
+                health_probe: domain.health_probe.cloned(),
+                user_conn_limit: domain.user_conn_limit,
+            },
+        );
+        Ok(1)
+    }
+
+    fn update_domain(&self, domain: &models::NewDomain) -> Result<usize> {
+        Ok(match self.tables().domains.get_mut(domain.name) {
+            Some(d) => {
+                d.owner = domain.owner.to_string();
+                d.description = domain.description.to_string();
+                d.chat_model = domain.chat_model.to_string();
+                d.embedding_model = domain.embedding_model.to_string();
+                d.status = domain.status.to_string();
+                d.health_probe = domain.health_probe.cloned();
+                d.user_conn_limit = domain.user_conn_limit;
+                1
+            }
+            None => 0,
+        })
+    }
+
+    fn query_domain(&self, name: &str) -> Result<Option<models::Domain>> {
+        Ok(self.tables().domains.get(name).cloned())
+    }
+
+    fn query_domains(&self, status: Option<&str>) -> Result<Vec<models::Domain>> {
+        Ok(self
+            .tables()
+            .domains
+            .values()
+            .filter(|d| status.map_or(true, |status| d.status == status))
+            .cloned()
+            .collect())
+    }
+
+    fn delete_domain(&self, name: &str) -> Result<usize> {
+        Ok(self.tables().domains.remove(name).map_or(0, |_| 1))
+    }
+
+    fn insert_ban(&self, kind: &str, value: &str, reason: &str) -> Result<usize> {
+        let mut tables = self.tables();
+        if tables
+            .bans
+            .iter()
+            .any(|ban| ban.kind == kind && ban.value == value)
+        {
+            return Err(format!("Duplicate ban {} {}", kind, value).into());
+        }
+        let id = tables.next_id();
+        tables.bans.push(models::Ban {
+            id,
+            kind: kind.to_string(),
+            value: value.to_string(),
+            reason: reason.to_string(),
+            created_at: now(),
+        });
+        Ok(1)
+    }
+
+    fn update_ban(&self, kind: &str, value: &str, reason: &str) -> Result<usize> {
+        let mut tables = self.tables();
+        let mut updated = 0;
+        for ban in tables
+            .bans
+            .iter_mut()
+            .filter(|ban| ban.kind == kind && ban.value == value)
+        {
+            ban.reason = reason.to_string();
+            updated += 1;
+        }
+        Ok(updated)
+    }
+
+    fn delete_ban(&self, kind: &str, value: &str) -> Result<usize> {
+        let mut tables = self.tables();
+        let before = tables.bans.len();
+        tables
+            .bans
+            .retain(|ban| !(ban.kind == kind && ban.value == value));
+        Ok(before - tables.bans.len())
+    }
+
+    fn query_bans(&self, kind: Option<&str>) -> Result<Vec<models::Ban>> {
+        Ok(self
+            .tables()
+            .bans
+            .iter()
+            .filter(|ban| match kind {
+                Some(kind) => ban.kind == kind,
+                None => true,
+            })
+            .cloned()
+            .collect())
+    }
+
+    fn query_ban(&self, kind: &str, value: &str) -> Result<Option<models::Ban>> {
+        Ok(self
+            .tables()
+            .bans
+            .iter()
+            .find(|ban| ban.kind == kind && ban.value == value)
+            .cloned())
+    }
+
+    fn upsert_frps_server(
+        &self,
+        frps_id: &str,
+        version: Option<&str>,
+        region: Option<&str>,
+        last_seen_time: &NaiveDateTime,
+    ) -> Result<usize> {
+        let mut tables = self.tables();
+        let server = tables
+            .frps_servers
+            .entry(frps_id.to_string())
+            .or_insert_with(|| models::FrpsServer {
+                frps_id: frps_id.to_string(),
+                version: String::new(),
+                region: String::new(),
+                status: String::new(),
+                last_seen_time: *last_seen_time,
+                created_at: now(),
+            });
+        if let Some(version) = version {
+            server.version = version.to_string();
+        }
+        if let Some(region) = region {
+            server.region = region.to_string();
+        }
+        server.status = NODE_STATUS_ONLINE.to_string();
+        server.last_seen_time = *last_seen_time;
+        Ok(1)
+    }
+
+    fn query_frps_servers(&self) -> Result<Vec<models::FrpsServer>> {
+        Ok(self.tables().frps_servers.values().cloned().collect())
+    }
+
+    fn query_silent_frps_servers(
+        &self,
+        seconds_before: &NaiveDateTime,
+    ) -> Result<Vec<models::FrpsServer>> {
+        Ok(self
+            .tables()
+            .frps_servers
+            .values()
+            .filter(|s| s.status == NODE_STATUS_ONLINE && s.last_seen_time < *seconds_before)
+            .cloned()
+            .collect())
+    }
+
+    fn update_frps_server_status(&self, frps_id: &str, status: &str) -> Result<usize> {
+        Ok(match self.tables().frps_servers.get_mut(frps_id) {
+            Some(server) => {
+                server.status = status.to_string();
+                1
+            }
+            None => 0,
+        })
+    }
+}
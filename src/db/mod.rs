@@ -0,0 +1,421 @@
+use chrono::NaiveDateTime;
+use diesel::r2d2;
+use lazy_static::lazy_static;
+use serde::Serialize;
+use serde_json::Value as JsonValue;
+use std::collections::HashMap;
+use std::env;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use gaia_hub::*;
+
+use crate::models;
+
+mod memory;
+#[cfg(feature = "mysql")]
+mod mysql;
+#[cfg(feature = "postgres")]
+mod postgres;
+#[cfg(any(feature = "sqlite", feature = "mysql", feature = "postgres"))]
+mod sql;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+#[cfg(test)]
+pub(crate) mod tests;
+
+pub use memory::MemoryStore;
+
+lazy_static! {
+    // The tests share one store in memory
+    static ref STORE: Box<dyn NodeStore> = {
+        let database_url = match cfg!(test) {
+            true => String::from("memory://"),
+            false => env::var("DATABASE_URL").expect("No DATABASE_URL in env"),
+        };
+        connect(&database_url).expect("Failed to create store.")
+    };
+}
+
+/// The store picked by `DATABASE_URL`.
+pub fn store() -> &'static dyn NodeStore {
+    STORE.as_ref()
+}
+
+/// Run database work on the blocking thread pool, so waiting for a connection or a slow
+/// query never stalls the async workers.
+pub async fn blocking<T, F>(work: F) -> Result<T>
+where
+    F: FnOnce() -> Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    tokio::task::spawn_blocking(work).await?
+}
+
+/// Open the store of a database url.
+///
+/// `mysql://` and `postgres://` urls go to MySQL and PostgreSQL, `memory://` keeps everything
+/// in the process, anything else is a SQLite database path with an optional `sqlite://` prefix.
+pub fn connect(database_url: &str) -> Result<Box<dyn NodeStore>> {
+    let scheme = database_url.split_once("://").map(|(scheme, _)| scheme);
+    match scheme {
+        Some("memory") => Ok(Box::new(MemoryStore::new())),
+        #[cfg(feature = "mysql")]
+        Some("mysql") => Ok(Box::new(mysql::MysqlStore::connect(database_url)?)),
+        #[cfg(feature = "postgres")]
+        Some("postgres") | Some("postgresql") => {
+            Ok(Box::new(postgres::PgStore::connect(database_url)?))
+        }
+        #[cfg(feature = "sqlite")]
+        Some("sqlite") | None => {
+            let path = database_url.trim_start_matches("sqlite://");
+            Ok(Box::new(sqlite::SqliteStore::connect(path)?))
+        }
+        _ => Err(format!(
+            "No storage backend for {} in this build",
+            scheme.unwrap_or("sqlite")
+        )
+        .into()),
+    }
+}
+
+// The pool settings shared by the sql backends
+fn pool_builder<M: r2d2::ManageConnection>() -> r2d2::Builder<M> {
+    let db_pool_size: u32 = env::var("DB_POOL_SIZE")
+        .unwrap_or_else(|_| String::from("20"))
+        .parse()
+        .expect("DB_POOL_SIZE must be a number");
+    let db_pool_min_size: u32 = env::var("DB_POOL_MIN_SIZE")
+        .unwrap_or_else(|_| String::from("20"))
+        .parse()
+        .expect("DB_POOL_MIN_SIZE must be a number");
+    // How long a checkout waits for a free connection before failing
+    let db_pool_timeout_secs: u64 = env::var("DB_POOL_TIMEOUT_SECS")
+        .unwrap_or_else(|_| String::from("30"))
+        .parse()
+        .expect("DB_POOL_TIMEOUT_SECS must be a number");
+
+    r2d2::Pool::builder()
+        .min_idle(Some(db_pool_min_size))
+        .max_size(db_pool_size)
+        .connection_timeout(Duration::from_secs(db_pool_timeout_secs))
+}
+
+/// The usage of a connection pool, served by `/inner/db_pool`.
+#[derive(Debug, Serialize)]
+pub struct PoolStats {
+    pub max_size: u32,
+    pub connections: u32,
+    pub idle_connections: u32,
+    pub in_use: u32,
+    /// The share of `max_size` in use, 1 means callers have to wait for a connection.
+    pub saturation: f64,
+    /// The callers waiting for a connection right now.
+    pub waiting: u64,
+    pub checkouts: u64,
+    /// The checkouts which gave up after `DB_POOL_TIMEOUT_SECS`.
+    pub timeouts: u64,
+    pub avg_wait_ms: f64,
+    pub max_wait_ms: f64,
+}
+
+// The checkout counters of a sql backend pool
+#[derive(Default)]
+pub(crate) struct PoolMetrics {
+    waiting: AtomicU64,
+    checkouts: AtomicU64,
+    timeouts: AtomicU64,
+    wait_micros: AtomicU64,
+    max_wait_micros: AtomicU64,
+}
+
+impl PoolMetrics {
+    // Check out a connection with `get`, recording how long it waited
+    pub(crate) fn checkout<C, E>(
+        &self,
+        get: impl FnOnce() -> std::result::Result<C, E>,
+    ) -> std::result::Result<C, E> {
+        let start = Instant::now();
+        self.waiting.fetch_add(1, Ordering::Relaxed);
+        let conn = get();
+        self.waiting.fetch_sub(1, Ordering::Relaxed);
+
+        let waited = start.elapsed().as_micros() as u64;
+        self.wait_micros.fetch_add(waited, Ordering::Relaxed);
+        self.max_wait_micros.fetch_max(waited, Ordering::Relaxed);
+        match conn {
+            Ok(_) => self.checkouts.fetch_add(1, Ordering::Relaxed),
+            Err(_) => self.timeouts.fetch_add(1, Ordering::Relaxed),
+        };
+        conn
+    }
+
+    pub(crate) fn stats(&self, state: r2d2::State, max_size: u32) -> PoolStats {
+        let checkouts = self.checkouts.load(Ordering::Relaxed);
+        let timeouts = self.timeouts.load(Ordering::Relaxed);
+        let wait_micros = self.wait_micros.load(Ordering::Relaxed);
+        let in_use = state.connections - state.idle_connections;
+        PoolStats {
+            max_size,
+            connections: state.connections,
+            idle_connections: state.idle_connections,
+            in_use,
+            saturation: in_use as f64 / max_size as f64,
+            waiting: self.waiting.load(Ordering::Relaxed),
+            checkouts,
+            timeouts,
+            avg_wait_ms: wait_micros as f64 / (checkouts + timeouts).max(1) as f64 / 1000.0,
+            max_wait_ms: self.max_wait_micros.load(Ordering::Relaxed) as f64 / 1000.0,
+        }
+    }
+}
+
+/// A write of one domain membership, applied along with the others of its batch.
+#[derive(Debug, Clone)]
+pub enum DomainNodeChange {
+    Insert(models::DomainNodes),
+    /// Overwrite the weight and the drain of the membership.
+    Update(models::DomainNodes),
+    Delete {
+        domain: String,
+        node_id: String,
+    },
+}
+
+/// The filters of `/inner/nodes`, all given ones must match.
+#[derive(Debug, Default)]
+pub struct NodeFilter {
+    pub status: Option<String>,
+    pub device_id: Option<String>,
+    pub chat_model: Option<String>,
+    pub country: Option<String>,
+    pub subdivision: Option<String>,
+    pub city: Option<String>,
+    pub ids: Option<Vec<String>>,
+    pub lived_secs: Option<u64>,
+}
+
+impl NodeFilter {
+    pub fn from_params(params: HashMap<String, JsonValue>) -> Self {
+        let mut filter = NodeFilter::default();
+        for (key, value) in params {
+            match (key.as_str(), value) {
+                ("status", JsonValue::String(v)) => filter.status = Some(v),
+                ("device_id", JsonValue::String(v)) => filter.device_id = Some(v),
+                ("chat_model", JsonValue::String(v)) => filter.chat_model = Some(v),
+                ("country", JsonValue::String(v)) => filter.country = Some(v),
+                ("subdivision", JsonValue::String(v)) => filter.subdivision = Some(v),
+                ("city", JsonValue::String(v)) => filter.city = Some(v),
+                ("ids", JsonValue::Array(v)) => {
+                    let id_list = v
+                        .into_iter()
+                        .filter_map(|val| match val {
+                            JsonValue::String(s) => Some(s),
+                            _ => None,
+                        })
+                        .collect();
+                    filter.ids = Some(id_list);
+                }
+                ("lived_secs", value) => filter.lived_secs = value.as_u64(),
+                _ => (),
+            }
+        }
+        filter
+    }
+
+    pub fn matches(&self, node: &models::Node) -> bool {
+        let fields = [
+            (&self.status, &node.status),
+            (&self.device_id, &node.device_id),
+            (&self.chat_model, &node.chat_model),
+            (&self.country, &node.country),
+            (&self.subdivision, &node.subdivision),
+            (&self.city, &node.city),
+        ];
+        if fields
+            .iter()
+            .any(|(expected, actual)| expected.as_ref().is_some_and(|v| v != *actual))
+        {
+            return false;
+        }
+        if let Some(ids) = &self.ids {
+            if !ids.contains(&node.node_id) {
+                return false;
+            }
+        }
+        if let Some(secs) = self.lived_secs {
+            if lived_secs(node) < secs as i64 {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+// The seconds a node has lived between its login and its last activity
+pub(crate) fn lived_secs(node: &models::Node) -> i64 {
+    (node.last_active_time - node.login_time).num_seconds()
+}
+
+pub(crate) fn is_active(status: &str) -> bool {
+    status == NODE_STATUS_ONLINE || status == NODE_STATUS_UNAVAIL
+}
+
+/// Storage of the devices, nodes, domain nodes, bans and frps servers.
+pub trait NodeStore: Send + Sync {
+    /// Apply the pending migrations and return the schema version the store ends up at.
+    fn run_migrations(&self) -> Result<String>;
+    /// The usage of the connection pool, `None` if the store has none.
+    fn pool_stats(&self) -> Option<PoolStats>;
+
+    fn create_device(&self, device: &models::NewDevice) -> Result<usize>;
+    fn update_device(&self, device_id: &str, login_time: &NaiveDateTime) -> Result<usize>;
+    /// Bind the key to the device, dropping the key waiting for approval.
+    fn update_device_public_key(&self, device_id: &str, public_key: &str) -> Result<usize>;
+    /// Keep a key offered by a device known without one, until an admin approves it.
+    fn update_device_pending_public_key(&self, device_id: &str, public_key: &str) -> Result<usize>;
+    /// The devices with a key waiting for approval, by device_id.
+    fn query_devices_with_pending_key(&self) -> Result<Vec<models::Device>>;
+    fn query_device_by_device_id(&self, device_id: &str) -> Result<Vec<models::Device>>;
+
+    fn create_node_status(&self, node: &models::NewNode) -> Result<usize>;
+    /// Overwrite the node found by `node.node_id`.
+    fn update_node_status_more(&self, node: &models::NewNode) -> Result<usize>;
+    /// Touch the online or unavail nodes of a device.
+    fn update_online_node_last_active_time(
+        &self,
+        device_id: &str,
+        last_active_time: &NaiveDateTime,
+    ) -> Result<usize>;
+    /// Touch the online or unavail nodes of a frpc run.
+    fn update_online_node_last_active_time_by_run_id(
+        &self,
+        run_id: &str,
+        last_active_time: &NaiveDateTime,
+    ) -> Result<usize>;
+    fn update_node_active_status(
+        &self,
+        device_id: &str,
+        subdomain: &str,
+        last_active_time: &NaiveDateTime,
+        status: &str,
+    ) -> Result<usize>;
+    fn update_node_avail_time_and_status(
+        &self,
+        node_id: &str,
+        last_avail_time: &NaiveDateTime,
+        status: &str,
+    ) -> Result<usize>;
+    fn update_node_status(&self, node_id: &str, status: &str) -> Result<usize>;
+    /// Record the response time of the last health check, none if it failed.
+    fn update_node_probe_latency(&self, node_id: &str, latency_ms: Option<i64>) -> Result<usize>;
+    fn update_nodes_status_by_device_id(&self, device_id: &str, status: &str) -> Result<usize>;
+    fn update_nodes_info_by_device_id(
+        &self,
+        device_id: &str,
+        node_version: &str,
+        chat_model: &str,
+        embedding_model: &str,
+    ) -> Result<usize>;
+    fn update_node_location(
+        &self,
+        node_id: &str,
+        country: &str,
+        subdivision: &str,
+        city: &str,
+    ) -> Result<usize>;
+    fn update_nodes_location_by_device_id(
+        &self,
+        device_id: &str,
+        country: &str,
+        subdivision: &str,
+        city: &str,
+    ) -> Result<usize>;
+
+    fn query_node_by_node_id(&self, node_id: &str) -> Result<Option<models::Node>>;
+    fn query_nodes_by_device_id(&self, device_id: &str) -> Result<Vec<models::Node>>;
+    fn query_nodes_by_node_ids(&self, node_ids: &[String]) -> Result<Vec<models::Node>>;
+    fn query_node_by_subdomain(&self, subdomain: &str) -> Result<Option<models::Node>>;
+    /// The online or unavail nodes.
+    fn query_active_nodes(&self) -> Result<Vec<models::Node>>;
+    fn query_active_nodes_by_frps_id(&self, frps_id: &str) -> Result<Vec<models::Node>>;
+    /// The number of online or unavail nodes connected through each frps.
+    fn count_active_nodes_by_frps_id(&self) -> Result<Vec<(String, i64)>>;
+    fn query_nodes_by_filter(&self, filter: &NodeFilter) -> Result<Vec<models::NodeLimited>>;
+    /// A page of the online nodes which have lived at least `lived_secs`, oldest login first.
+    fn query_living_nodes(
+        &self,
+        lived_secs: u64,
+        page: i64,
+        size: i64,
+    ) -> Result<Vec<models::LivingNode>>;
+    /// The online or unavail nodes which have lived at least `lived_secs` and logged in after
+    /// `earliest_login_time`, oldest login first.
+    fn query_living_nodes_by_login_time(
+        &self,
+        lived_secs: u64,
+        page_size: i64,
+        earliest_login_time: &NaiveDateTime,
+    ) -> Result<Vec<models::LivingNode>>;
+    /// Offline the online nodes which have not been active since the given time.
+    fn close_expired_nodes(&self, seconds_before: &NaiveDateTime) -> Result<usize>;
+    /// Unavail the online nodes which have not been available since the given time.
+    fn unavail_expired_nodes(&self, seconds_before: &NaiveDateTime) -> Result<usize>;
+
+    fn query_domain_nodes(&self, domain: &str) -> Result<Vec<models::DomainNodes>>;
+    fn query_domain_node(&self, domain: &str, node_id: &str)
+        -> Result<Option<models::DomainNodes>>;
+    /// The memberships of the node in every domain it belongs to, by domain.
+    fn query_domain_nodes_by_node_id(&self, node_id: &str) -> Result<Vec<models::DomainNodes>>;
+    /// Apply all the changes in one transaction, or none of them if one fails or finds no
+    /// membership to update or delete.
+    fn apply_domain_node_changes(&self, changes: &[DomainNodeChange]) -> Result<()>;
+    fn get_distinct_domains(&self) -> Result<Vec<String>>;
+    /// The online nodes of the domain with their routed weights.
+    fn get_nodes_by_domain(&self, domain: &str) -> Result<Vec<(String, i64)>>;
+    /// The user connection limits set by the domains of the online or unavail node at the
+    /// subdomain.
+    fn query_user_conn_limits_by_subdomain(&self, subdomain: &str) -> Result<Vec<i64>>;
+    /// The memberships being drained, in every domain.
+    fn query_draining_domain_nodes(&self) -> Result<Vec<models::DomainNodes>>;
+    /// Record a user connection to a node being drained out of the domain.
+    fn update_draining_user_conn_time(
+        &self,
+        domain: &str,
+        node_id: &str,
+        time: &NaiveDateTime,
+    ) -> Result<usize>;
+    /// Delete the membership only if it is still being drained.
+    fn delete_drained_domain_node(&self, domain: &str, node_id: &str) -> Result<usize>;
+
+    fn insert_domain(&self, domain: &models::NewDomain) -> Result<usize>;
+    /// Overwrite the metadata and the status of the domain.
+    fn update_domain(&self, domain: &models::NewDomain) -> Result<usize>;
+    fn query_domain(&self, name: &str) -> Result<Option<models::Domain>>;
+    fn query_domains(&self, status: Option<&str>) -> Result<Vec<models::Domain>>;
+    fn delete_domain(&self, name: &str) -> Result<usize>;
+
+    fn insert_ban(&self, kind: &str, value: &str, reason: &str) -> Result<usize>;
+    /// Change the reason of the ban, which holds all along.
+    fn update_ban(&self, kind: &str, value: &str, reason: &str) -> Result<usize>;
+    fn delete_ban(&self, kind: &str, value: &str) -> Result<usize>;
+    fn query_bans(&self, kind: Option<&str>) -> Result<Vec<models::Ban>>;
+    fn query_ban(&self, kind: &str, value: &str) -> Result<Option<models::Ban>>;
+
+    /// Record the frps as seen, registering it on the first event.
+    /// The recorded `version` and `region` are kept if not given.
+    fn upsert_frps_server(
+        &self,
+        frps_id: &str,
+        version: Option<&str>,
+        region: Option<&str>,
+        last_seen_time: &NaiveDateTime,
+    ) -> Result<usize>;
+    fn query_frps_servers(&self) -> Result<Vec<models::FrpsServer>>;
+    /// The online frps which have not sent any event since the given time.
+    fn query_silent_frps_servers(
+        &self,
+        seconds_before: &NaiveDateTime,
+    ) -> Result<Vec<models::FrpsServer>>;
+    fn update_frps_server_status(&self, frps_id: &str, status: &str) -> Result<usize>;
+}
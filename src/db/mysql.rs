@@ -0,0 +1,47 @@
+use chrono::NaiveDateTime;
+use diesel::r2d2::ConnectionManager;
+use diesel::MysqlConnection;
+use diesel_migrations::{embed_migrations, EmbeddedMigrations};
+use serde_json::Value;
+
+use crate::db::pool_builder;
+use crate::db::sql::sql_store;
+use gaia_hub::*;
+
+sql_store! {
+    store: MysqlStore,
+    schema: crate::schema::mysql,
+    backend: diesel::mysql::Mysql,
+    conn: diesel::MysqlConnection,
+    time: chrono::NaiveDateTime => (to_db_time, from_db_time),
+    meta: serde_json::Value => (to_db_meta, from_db_meta),
+    lived_secs_sql: "TIMESTAMPDIFF(SECOND, login_time, last_active_time)",
+    frps_conflict_target: diesel::mysql::query_builder::DuplicatedKeys,
+    migrations: MIGRATIONS,
+}
+
+const MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations/mysql");
+
+fn to_db_time(time: &NaiveDateTime) -> NaiveDateTime {
+    *time
+}
+
+fn from_db_time(time: NaiveDateTime) -> NaiveDateTime {
+    time
+}
+
+fn to_db_meta(meta: &Value) -> Value {
+    meta.clone()
+}
+
+fn from_db_meta(meta: Value) -> Value {
+    meta
+}
+
+impl MysqlStore {
+    pub fn connect(database_url: &str) -> Result<Self> {
+        let manager = ConnectionManager::<MysqlConnection>::new(database_url);
+        let pool = pool_builder().build(manager)?;
+        Ok(MysqlStore::new(pool))
+    }
+}
@@ -0,0 +1,47 @@
+use chrono::NaiveDateTime;
+use diesel::r2d2::ConnectionManager;
+use diesel::PgConnection;
+use diesel_migrations::{embed_migrations, EmbeddedMigrations};
+use serde_json::Value;
+
+use crate::db::pool_builder;
+use crate::db::sql::sql_store;
+use gaia_hub::*;
+
+sql_store! {
+    store: PgStore,
+    schema: crate::schema::postgres,
+    backend: diesel::pg::Pg,
+    conn: diesel::PgConnection,
+    time: chrono::NaiveDateTime => (to_db_time, from_db_time),
+    meta: serde_json::Value => (to_db_meta, from_db_meta),
+    lived_secs_sql: "EXTRACT(EPOCH FROM (last_active_time - login_time))",
+    frps_conflict_target: crate::schema::postgres::frps_servers::frps_id,
+    migrations: MIGRATIONS,
+}
+
+const MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations/postgres");
+
+fn to_db_time(time: &NaiveDateTime) -> NaiveDateTime {
+    *time
+}
+
+fn from_db_time(time: NaiveDateTime) -> NaiveDateTime {
+    time
+}
+
+fn to_db_meta(meta: &Value) -> Value {
+    meta.clone()
+}
+
+fn from_db_meta(meta: Value) -> Value {
+    meta
+}
+
+impl PgStore {
+    pub fn connect(database_url: &str) -> Result<Self> {
+        let manager = ConnectionManager::<PgConnection>::new(database_url);
+        let pool = pool_builder().build(manager)?;
+        Ok(PgStore::new(pool))
+    }
+}
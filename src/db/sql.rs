@@ -0,0 +1,1262 @@
+// The diesel implementation of the store, shared by the sql backends.
+//
+// Each backend module expands `sql_store!` with the name of its store, its schema module, its
+// diesel backend and connection, the column types of the times and metas with the conversions
+// from and to the models, the SQL of the seconds a node lived and its embedded migrations.
+// Every name the implementation takes from the backend is one of these parameters.
+
+macro_rules! sql_store {
+    (
+        store: $store:ident,
+        schema: $($schema:ident)::+,
+        backend: $backend:ty,
+        conn: $conn:ty,
+        time: $time:ty => ($to_db_time:ident, $from_db_time:ident),
+        meta: $meta:ty => ($to_db_meta:ident, $from_db_meta:ident),
+        lived_secs_sql: $lived_secs_sql:expr,
+        frps_conflict_target: $frps_conflict_target:expr,
+        migrations: $migrations:ident $(,)?
+    ) => {
+        pub use diesel_store::$store;
+
+        mod diesel_store {
+            use chrono::NaiveDateTime;
+            use diesel::dsl::sql;
+            use diesel::expression::SqlLiteral;
+            use diesel::prelude::*;
+            use diesel::r2d2::{self, ConnectionManager};
+            use diesel::sql_types::Bool;
+            use diesel_migrations::MigrationHarness;
+
+            use crate::db::{DomainNodeChange, NodeFilter, NodeStore, PoolMetrics, PoolStats};
+            use crate::models;
+            use gaia_hub::*;
+
+            use super::{
+                $from_db_meta as from_db_meta, $from_db_time as from_db_time,
+                $migrations as MIGRATIONS, $to_db_meta as to_db_meta, $to_db_time as to_db_time,
+            };
+            use $($schema)::+ as schema;
+
+            // Only referenced by the field checks of the diesel derives
+            #[allow(dead_code)]
+            type Backend = $backend;
+            type Conn = $conn;
+            type Time = $time;
+            type Meta = $meta;
+            type Pool = r2d2::Pool<ConnectionManager<Conn>>;
+
+            static LIVED_SECS_SQL: &str = $lived_secs_sql;
+
+            pub struct $store {
+                pool: Pool,
+                metrics: PoolMetrics,
+            }
+
+            #[derive(Queryable, Selectable)]
+            #[diesel(table_name = schema::node_status)]
+            #[diesel(check_for_backend(Backend))]
+            struct NodeRow {
+                id: i64,
+                node_id: String,
+                device_id: String,
+                subdomain: String,
+                version: String,
+                arch: String,
+                os: String,
+                client_address: String,
+                login_time: Time,
+                last_active_time: Time,
+                last_avail_time: Option<Time>,
+                run_id: String,
+                meta: Meta,
+                node_version: String,
+                chat_model: String,
+                embedding_model: String,
+                status: String,
+                created_at: Time,
+                updated_at: Time,
+                frps_id: String,
+                country: String,
+                subdivision: String,
+                city: String,
+                probe_latency_ms: Option<i64>,
+            }
+
+            impl From<NodeRow> for models::Node {
+                fn from(row: NodeRow) -> Self {
+                    models::Node {
+                        id: row.id,
+                        node_id: row.node_id,
+                        device_id: row.device_id,
+                        subdomain: row.subdomain,
+                        version: row.version,
+                        arch: row.arch,
+                        os: row.os,
+                        client_address: row.client_address,
+                        login_time: from_db_time(row.login_time),
+                        last_active_time: from_db_time(row.last_active_time),
+                        last_avail_time: row.last_avail_time.map(from_db_time),
+                        run_id: row.run_id,
+                        meta: from_db_meta(row.meta),
+                        node_version: row.node_version,
+                        chat_model: row.chat_model,
+                        embedding_model: row.embedding_model,
+                        status: row.status,
+                        created_at: from_db_time(row.created_at),
+                        updated_at: from_db_time(row.updated_at),
+                        frps_id: row.frps_id,
+                        country: row.country,
+                        subdivision: row.subdivision,
+                        city: row.city,
+                        probe_latency_ms: row.probe_latency_ms,
+                    }
+                }
+            }
+
+            #[derive(Queryable, Selectable)]
+            #[diesel(table_name = schema::node_status)]
+            #[diesel(check_for_backend(Backend))]
+            struct NodeLimitedRow {
+                subdomain: String,
+                node_id: String,
+                status: String,
+                node_version: String,
+                chat_model: String,
+                embedding_model: String,
+                device_id: String,
+                client_address: String,
+                country: String,
+                subdivision: String,
+                city: String,
+            }
+
+            impl From<NodeLimitedRow> for models::NodeLimited {
+                fn from(row: NodeLimitedRow) -> Self {
+                    models::NodeLimited {
+                        subdomain: row.subdomain,
+                        node_id: row.node_id,
+                        status: row.status,
+                        node_version: row.node_version,
+                        chat_model: row.chat_model,
+                        embedding_model: row.embedding_model,
+                        device_id: row.device_id,
+                        client_address: row.client_address,
+                        country: row.country,
+                        subdivision: row.subdivision,
+                        city: row.city,
+                    }
+                }
+            }
+
+            #[derive(Queryable, Selectable)]
+            #[diesel(table_name = schema::node_status)]
+            #[diesel(check_for_backend(Backend))]
+            struct LivingNodeRow {
+                node_id: String,
+                subdomain: String,
+                chat_model: String,
+                embedding_model: String,
+                login_time: Time,
+                status: String,
+            }
+
+            impl From<LivingNodeRow> for models::LivingNode {
+                fn from(row: LivingNodeRow) -> Self {
+                    models::LivingNode {
+                        node_id: row.node_id,
+                        subdomain: row.subdomain,
+                        chat_model: row.chat_model,
+                        embedding_model: row.embedding_model,
+                        login_time: from_db_time(row.login_time),
+                        status: row.status,
+                    }
+                }
+            }
+
+            #[derive(Insertable, AsChangeset)]
+            #[diesel(table_name = schema::node_status)]
+            struct NewNodeRow<'a> {
+                node_id: &'a str,
+                device_id: &'a str,
+                subdomain: &'a str,
+                version: &'a str,
+                arch: &'a str,
+                os: &'a str,
+                client_address: &'a str,
+                status: &'a str,
+                login_time: Time,
+                last_active_time: Time,
+                run_id: &'a str,
+                meta: Meta,
+                frps_id: &'a str,
+            }
+
+            impl<'a> From<&models::NewNode<'a>> for NewNodeRow<'a> {
+                fn from(node: &models::NewNode<'a>) -> Self {
+                    NewNodeRow {
+                        node_id: node.node_id,
+                        device_id: node.device_id,
+                        subdomain: node.subdomain,
+                        version: node.version,
+                        arch: node.arch,
+                        os: node.os,
+                        client_address: node.client_address,
+                        status: node.status,
+                        login_time: to_db_time(node.login_time),
+                        last_active_time: to_db_time(node.last_active_time),
+                        run_id: node.run_id,
+                        meta: to_db_meta(node.meta),
+                        frps_id: node.frps_id,
+                    }
+                }
+            }
+
+            #[derive(Queryable, Selectable)]
+            #[diesel(table_name = schema::devices)]
+            #[diesel(check_for_backend(Backend))]
+            struct DeviceRow {
+                id: i64,
+                device_id: String,
+                version: String,
+                arch: String,
+                os: String,
+                client_address: String,
+                login_time: Time,
+                meta: Meta,
+                created_at: Time,
+                updated_at: Time,
+                public_key: String,
+                pending_public_key: String,
+            }
+
+            impl From<DeviceRow> for models::Device {
+                fn from(row: DeviceRow) -> Self {
+                    models::Device {
+                        id: row.id,
+                        device_id: row.device_id,
+                        version: row.version,
+                        arch: row.arch,
+                        os: row.os,
+                        client_address: row.client_address,
+                        login_time: from_db_time(row.login_time),
+                        meta: from_db_meta(row.meta),
+                        created_at: from_db_time(row.created_at),
+                        updated_at: from_db_time(row.updated_at),
+                        public_key: row.public_key,
+                        pending_public_key: row.pending_public_key,
+                    }
+                }
+            }
+
+            #[derive(Insertable)]
+            #[diesel(table_name = schema::devices)]
+            struct NewDeviceRow<'a> {
+                device_id: &'a str,
+                version: &'a str,
+                arch: &'a str,
+                os: &'a str,
+                client_address: &'a str,
+                login_time: Time,
+                meta: Meta,
+                public_key: &'a str,
+            }
+
+            #[derive(Queryable, Selectable, Insertable, AsChangeset)]
+            #[diesel(table_name = schema::domain_nodes)]
+            #[diesel(primary_key(domain, node_id))]
+            #[diesel(treat_none_as_null = true)]
+            #[diesel(check_for_backend(Backend))]
+            struct DomainNodeRow {
+                domain: String,
+                node_id: String,
+                weight: i64,
+                draining_since: Option<Time>,
+                drain_deadline: Option<Time>,
+                last_user_conn_time: Option<Time>,
+            }
+
+            impl From<DomainNodeRow> for models::DomainNodes {
+                fn from(row: DomainNodeRow) -> Self {
+                    models::DomainNodes {
+                        domain: row.domain,
+                        node_id: row.node_id,
+                        weight: row.weight,
+                        draining_since: row.draining_since.map(from_db_time),
+                        drain_deadline: row.drain_deadline.map(from_db_time),
+                        last_user_conn_time: row.last_user_conn_time.map(from_db_time),
+                    }
+                }
+            }
+
+            impl From<&models::DomainNodes> for DomainNodeRow {
+                fn from(domain_node: &models::DomainNodes) -> Self {
+                    DomainNodeRow {
+                        domain: domain_node.domain.clone(),
+                        node_id: domain_node.node_id.clone(),
+                        weight: domain_node.weight,
+                        draining_since: domain_node.draining_since.as_ref().map(to_db_time),
+                        drain_deadline: domain_node.drain_deadline.as_ref().map(to_db_time),
+                        last_user_conn_time: domain_node.last_user_conn_time.as_ref().map(to_db_time),
+                    }
+                }
+            }
+
+            #[derive(Queryable, Selectable)]
+            #[diesel(table_name = schema::domains)]
+            #[diesel(check_for_backend(Backend))]
+            struct DomainRow {
+                name: String,
+                owner: String,
+                description: String,
+                chat_model: String,
+                embedding_model: String,
+                status: String,
+                created_at: Time,
+                health_probe: Option<Meta>,
+                user_conn_limit: Option<i64>,
+            }
+
+            impl From<DomainRow> for models::Domain {
+                fn from(row: DomainRow) -> Self {
+                    models::Domain {
+                        name: row.name,
+                        owner: row.owner,
+                        description: row.description,
+                        chat_model: row.chat_model,
+                        embedding_model: row.embedding_model,
+                        status: row.status,
+                        created_at: from_db_time(row.created_at),
+                        health_probe: row.health_probe.map(from_db_meta),
+                        user_conn_limit: row.user_conn_limit,
+                    }
+                }
+            }
+
+            #[derive(Insertable, AsChangeset)]
+            #[diesel(table_name = schema::domains)]
+            #[diesel(treat_none_as_null = true)]
+            struct NewDomainRow<'a> {
+                name: &'a str,
+                owner: &'a str,
+                description: &'a str,
+                chat_model: &'a str,
+                embedding_model: &'a str,
+                status: &'a str,
+                health_probe: Option<Meta>,
+                user_conn_limit: Option<i64>,
+            }
+
+            impl<'a> From<&models::NewDomain<'a>> for NewDomainRow<'a> {
+                fn from(domain: &models::NewDomain<'a>) -> Self {
+                    NewDomainRow {
+                        name: domain.name,
+                        owner: domain.owner,
+                        description: domain.description,
+                        chat_model: domain.chat_model,
+                        embedding_model: domain.embedding_model,
+                        status: domain.status,
+                        health_probe: domain.health_probe.map(to_db_meta),
+                        user_conn_limit: domain.user_conn_limit,
+                    }
+                }
+            }
+
+            #[derive(Queryable, Selectable)]
+            #[diesel(table_name = schema::bans)]
+            #[diesel(check_for_backend(Backend))]
+            struct BanRow {
+                id: i64,
+                kind: String,
+                value: String,
+                reason: String,
+                created_at: Time,
+            }
+
+            impl From<BanRow> for models::Ban {
+                fn from(row: BanRow) -> Self {
+                    models::Ban {
+                        id: row.id,
+                        kind: row.kind,
+                        value: row.value,
+                        reason: row.reason,
+                        created_at: from_db_time(row.created_at),
+                    }
+                }
+            }
+
+            #[derive(Insertable)]
+            #[diesel(table_name = schema::bans)]
+            struct NewBanRow<'a> {
+                kind: &'a str,
+                value: &'a str,
+                reason: &'a str,
+            }
+
+            #[derive(Queryable, Selectable)]
+            #[diesel(table_name = schema::frps_servers)]
+            #[diesel(check_for_backend(Backend))]
+            struct FrpsServerRow {
+                frps_id: String,
+                version: String,
+                region: String,
+                status: String,
+                last_seen_time: Time,
+                created_at: Time,
+            }
+
+            impl From<FrpsServerRow> for models::FrpsServer {
+                fn from(row: FrpsServerRow) -> Self {
+                    models::FrpsServer {
+                        frps_id: row.frps_id,
+                        version: row.version,
+                        region: row.region,
+                        status: row.status,
+                        last_seen_time: from_db_time(row.last_seen_time),
+                        created_at: from_db_time(row.created_at),
+                    }
+                }
+            }
+
+            #[derive(Insertable, AsChangeset)]
+            #[diesel(table_name = schema::frps_servers)]
+            struct FrpsServerSeenRow<'a> {
+                frps_id: &'a str,
+                // Keep the recorded metadata if the frps doesn't report it
+                version: Option<&'a str>,
+                region: Option<&'a str>,
+                status: &'a str,
+                last_seen_time: Time,
+            }
+
+            fn lived_at_least(lived_secs: u64) -> SqlLiteral<Bool> {
+                sql::<Bool>(&format!("{} >= {}", LIVED_SECS_SQL, lived_secs))
+            }
+
+            fn into_models<R, M: From<R>>(rows: Vec<R>) -> Vec<M> {
+                rows.into_iter().map(M::from).collect()
+            }
+
+            impl $store {
+                pub(super) fn new(pool: Pool) -> Self {
+                    $store {
+                        pool,
+                        metrics: PoolMetrics::default(),
+                    }
+                }
+
+                fn establish_connection(&self) -> Result<r2d2::PooledConnection<ConnectionManager<Conn>>> {
+                    Ok(self.metrics.checkout(|| self.pool.get()).map_err(|e| {
+                        log::error!("Failed to fetch db connection: {}", e);
+                        e
+                    })?)
+                }
+            }
+
+            impl NodeStore for $store {
+                fn run_migrations(&self) -> Result<String> {
+                    let mut conn = self.establish_connection()?;
+                    for version in conn.run_pending_migrations(MIGRATIONS)? {
+                        log::info!("Applied migration {}", version);
+                    }
+
+                    let version = conn
+                        .applied_migrations()?
+                        .into_iter()
+                        .max()
+                        .map(|version| version.to_string())
+                        .unwrap_or_default();
+                    Ok(version)
+                }
+
+                fn pool_stats(&self) -> Option<PoolStats> {
+                    Some(self.metrics.stats(self.pool.state(), self.pool.max_size()))
+                }
+
+                fn create_device(&self, device: &models::NewDevice) -> Result<usize> {
+                    use schema::devices;
+                    let device = NewDeviceRow {
+                        device_id: device.device_id,
+                        version: device.version,
+                        arch: device.arch,
+                        os: device.os,
+                        client_address: device.client_address,
+                        login_time: to_db_time(device.login_time),
+                        meta: to_db_meta(device.meta),
+                        public_key: device.public_key,
+                    };
+
+                    let mut conn = self.establish_connection()?;
+
+                    Ok(diesel::insert_into(devices::table)
+                        .values(&device)
+                        .execute(&mut conn)?)
+                }
+
+                fn update_device(&self, device_id: &str, login_time: &NaiveDateTime) -> Result<usize> {
+                    let mut conn = self.establish_connection()?;
+                    use schema::devices;
+
+                    Ok(
+                        diesel::update(devices::table.filter(devices::device_id.eq(device_id)))
+                            .set((devices::login_time.eq(to_db_time(login_time)),))
+                            .execute(&mut conn)?,
+                    )
+                }
+
+                fn update_device_public_key(&self, device_id: &str, public_key: &str) -> Result<usize> {
+                    let mut conn = self.establish_connection()?;
+                    use schema::devices;
+
+                    Ok(
+                        diesel::update(devices::table.filter(devices::device_id.eq(device_id)))
+                            .set((
+                                devices::public_key.eq(public_key),
+                                devices::pending_public_key.eq(""),
+                            ))
+                            .execute(&mut conn)?,
+                    )
+                }
+
+                fn update_device_pending_public_key(
+                    &self,
+                    device_id: &str,
+                    public_key: &str,
+                ) -> Result<usize> {
+                    let mut conn = self.establish_connection()?;
+                    use schema::devices;
+
+                    Ok(
+                        diesel::update(devices::table.filter(devices::device_id.eq(device_id)))
+                            .set((devices::pending_public_key.eq(public_key),))
+                            .execute(&mut conn)?,
+                    )
+                }
+
+                fn query_devices_with_pending_key(&self) -> Result<Vec<models::Device>> {
+                    let mut conn = self.establish_connection()?;
+                    use schema::devices::dsl::{device_id, devices, pending_public_key};
+                    let rows = devices
+                        .filter(pending_public_key.ne(""))
+                        .order(device_id.asc())
+                        .select(DeviceRow::as_select())
+                        .load(&mut conn)?;
+                    Ok(into_models(rows))
+                }
+
+                fn query_device_by_device_id(&self, device_id: &str) -> Result<Vec<models::Device>> {
+                    let mut conn = self.establish_connection()?;
+                    use schema::devices::dsl::{device_id as di, devices};
+                    let rows = devices
+                        .filter(di.eq(device_id))
+                        .select(DeviceRow::as_select())
+                        .load(&mut conn)?;
+                    Ok(into_models(rows))
+                }
+
+                fn create_node_status(&self, node: &models::NewNode) -> Result<usize> {
+                    let mut conn = self.establish_connection()?;
+                    use schema::node_status;
+
+                    Ok(diesel::insert_into(node_status::table)
+                        .values(&NewNodeRow::from(node))
+                        .execute(&mut conn)?)
+                }
+
+                fn update_node_status_more(&self, node: &models::NewNode) -> Result<usize> {
+                    let mut conn = self.establish_connection()?;
+                    use schema::node_status;
+
+                    Ok(
+                        diesel::update(node_status::table.filter(node_status::node_id.eq(node.node_id)))
+                            .set(&NewNodeRow::from(node))
+                            .execute(&mut conn)?,
+                    )
+                }
+
+                fn update_online_node_last_active_time(
+                    &self,
+                    device_id: &str,
+                    last_active_time: &NaiveDateTime,
+                ) -> Result<usize> {
+                    let mut conn = self.establish_connection()?;
+                    use schema::node_status;
+
+                    Ok(
+                        diesel::update(node_status::table.filter(node_status::device_id.eq(device_id)))
+                            .filter(
+                                node_status::status
+                                    .eq(NODE_STATUS_ONLINE)
+                                    .or(node_status::status.eq(NODE_STATUS_UNAVAIL)),
+                            )
+                            .set(node_status::last_active_time.eq(to_db_time(last_active_time)))
+                            .execute(&mut conn)?,
+                    )
+                }
+
+                fn update_online_node_last_active_time_by_run_id(
+                    &self,
+                    run_id: &str,
+                    last_active_time: &NaiveDateTime,
+                ) -> Result<usize> {
+                    let mut conn = self.establish_connection()?;
+                    use schema::node_status;
+
+                    Ok(
+                        diesel::update(node_status::table.filter(node_status::run_id.eq(run_id)))
+                            .filter(
+                                node_status::status
+                                    .eq(NODE_STATUS_ONLINE)
+                                    .or(node_status::status.eq(NODE_STATUS_UNAVAIL)),
+                            )
+                            .set(node_status::last_active_time.eq(to_db_time(last_active_time)))
+                            .execute(&mut conn)?,
+                    )
+                }
+
+                fn update_node_active_status(
+                    &self,
+                    device_id: &str,
+                    subdomain: &str,
+                    last_active_time: &NaiveDateTime,
+                    status: &str,
+                ) -> Result<usize> {
+                    let mut conn = self.establish_connection()?;
+                    use schema::node_status;
+
+                    Ok(
+                        diesel::update(node_status::table.filter(node_status::device_id.eq(device_id)))
+                            .filter(node_status::subdomain.eq(subdomain))
+                            .set((
+                                node_status::last_active_time.eq(to_db_time(last_active_time)),
+                                node_status::status.eq(status),
+                            ))
+                            .execute(&mut conn)?,
+                    )
+                }
+
+                fn update_node_avail_time_and_status(
+                    &self,
+                    node_id: &str,
+                    last_avail_time: &NaiveDateTime,
+                    status: &str,
+                ) -> Result<usize> {
+                    let mut conn = self.establish_connection()?;
+                    use schema::node_status;
+
+                    Ok(
+                        diesel::update(node_status::table.filter(node_status::node_id.eq(node_id)))
+                            .set((
+                                node_status::last_avail_time.eq(to_db_time(last_avail_time)),
+                                node_status::status.eq(status),
+                            ))
+                            .execute(&mut conn)?,
+                    )
+                }
+
+                fn update_node_status(&self, node_id: &str, status: &str) -> Result<usize> {
+                    let mut conn = self.establish_connection()?;
+                    use schema::node_status;
+
+                    Ok(
+                        diesel::update(node_status::table.filter(node_status::node_id.eq(node_id)))
+                            .set((node_status::status.eq(status),))
+                            .execute(&mut conn)?,
+                    )
+                }
+
+                fn update_node_probe_latency(&self, node_id: &str, latency_ms: Option<i64>) -> Result<usize> {
+                    let mut conn = self.establish_connection()?;
+                    use schema::node_status;
+
+                    Ok(
+                        diesel::update(node_status::table.filter(node_status::node_id.eq(node_id)))
+                            .set(node_status::probe_latency_ms.eq(latency_ms))
+                            .execute(&mut conn)?,
+                    )
+                }
+
+                fn update_nodes_status_by_device_id(&self, device_id: &str, status: &str) -> Result<usize> {
+                    let mut conn = self.establish_connection()?;
+                    use schema::node_status;
+
+                    Ok(
+                        diesel::update(node_status::table.filter(node_status::device_id.eq(device_id)))
+                            .set((node_status::status.eq(status),))
+                            .execute(&mut conn)?,
+                    )
+                }
+
+                fn update_nodes_info_by_device_id(
+                    &self,
+                    device_id: &str,
+                    node_version: &str,
+                    chat_model: &str,
+                    embedding_model: &str,
+                ) -> Result<usize> {
+                    let mut conn = self.establish_connection()?;
+                    use schema::node_status;
+
+                    Ok(
+                        diesel::update(node_status::table.filter(node_status::device_id.eq(device_id)))
+                            .set((
+                                node_status::node_version.eq(node_version),
+                                node_status::chat_model.eq(chat_model),
+                                node_status::embedding_model.eq(embedding_model),
+                            ))
+                            .execute(&mut conn)?,
+                    )
+                }
+
+                fn update_node_location(
+                    &self,
+                    node_id: &str,
+                    country: &str,
+                    subdivision: &str,
+                    city: &str,
+                ) -> Result<usize> {
+                    let mut conn = self.establish_connection()?;
+                    use schema::node_status;
+
+                    Ok(
+                        diesel::update(node_status::table.filter(node_status::node_id.eq(node_id)))
+                            .set((
+                                node_status::country.eq(country),
+                                node_status::subdivision.eq(subdivision),
+                                node_status::city.eq(city),
+                            ))
+                            .execute(&mut conn)?,
+                    )
+                }
+
+                fn update_nodes_location_by_device_id(
+                    &self,
+                    device_id: &str,
+                    country: &str,
+                    subdivision: &str,
+                    city: &str,
+                ) -> Result<usize> {
+                    let mut conn = self.establish_connection()?;
+                    use schema::node_status;
+
+                    Ok(
+                        diesel::update(node_status::table.filter(node_status::device_id.eq(device_id)))
+                            .set((
+                                node_status::country.eq(country),
+                                node_status::subdivision.eq(subdivision),
+                                node_status::city.eq(city),
+                            ))
+                            .execute(&mut conn)?,
+                    )
+                }
+
+                fn query_node_by_node_id(&self, node_id: &str) -> Result<Option<models::Node>> {
+                    let mut conn = self.establish_connection()?;
+                    use schema::node_status::dsl::{node_id as ni, node_status};
+                    Ok(node_status
+                        .filter(ni.eq(node_id))
+                        .select(NodeRow::as_select())
+                        .first(&mut conn)
+                        .optional()?
+                        .map(models::Node::from))
+                }
+
+                fn query_nodes_by_device_id(&self, device_id: &str) -> Result<Vec<models::Node>> {
+                    let mut conn = self.establish_connection()?;
+                    use schema::node_status::dsl::{device_id as di, node_status};
+                    let rows = node_status
+                        .filter(di.eq(device_id))
+                        .select(NodeRow::as_select())
+                        .load(&mut conn)?;
+                    Ok(into_models(rows))
+                }
+
+                fn query_nodes_by_node_ids(&self, node_ids: &[String]) -> Result<Vec<models::Node>> {
+                    let mut conn = self.establish_connection()?;
+                    use schema::node_status::dsl::{node_id as ni, node_status};
+                    let rows = node_status
+                        .filter(ni.eq_any(node_ids))
+                        .select(NodeRow::as_select())
+                        .load(&mut conn)?;
+                    Ok(into_models(rows))
+                }
+
+                fn query_node_by_subdomain(&self, subdomain: &str) -> Result<Option<models::Node>> {
+                    let mut conn = self.establish_connection()?;
+                    use schema::node_status::dsl::{node_status, subdomain as sd};
+                    Ok(node_status
+                        .filter(sd.eq(subdomain))
+                        .select(NodeRow::as_select())
+                        .first(&mut conn)
+                        .optional()?
+                        .map(models::Node::from))
+                }
+
+                fn query_active_nodes(&self) -> Result<Vec<models::Node>> {
+                    let mut conn = self.establish_connection()?;
+                    use schema::node_status::dsl::{node_status, status};
+                    let rows = node_status
+                        .filter(
+                            status
+                                .eq(NODE_STATUS_ONLINE)
+                                .or(status.eq(NODE_STATUS_UNAVAIL)),
+                        )
+                        .select(NodeRow::as_select())
+                        .load(&mut conn)?;
+                    Ok(into_models(rows))
+                }
+
+                fn query_active_nodes_by_frps_id(&self, frps_id: &str) -> Result<Vec<models::Node>> {
+                    let mut conn = self.establish_connection()?;
+                    use schema::node_status::dsl::{frps_id as fi, node_status, status};
+                    let rows = node_status
+                        .filter(fi.eq(frps_id))
+                        .filter(
+                            status
+                                .eq(NODE_STATUS_ONLINE)
+                                .or(status.eq(NODE_STATUS_UNAVAIL)),
+                        )
+                        .select(NodeRow::as_select())
+                        .load(&mut conn)?;
+                    Ok(into_models(rows))
+                }
+
+                fn count_active_nodes_by_frps_id(&self) -> Result<Vec<(String, i64)>> {
+                    let mut conn = self.establish_connection()?;
+                    use schema::node_status::dsl::{frps_id, node_status, status};
+                    Ok(node_status
+                        .filter(
+                            status
+                                .eq(NODE_STATUS_ONLINE)
+                                .or(status.eq(NODE_STATUS_UNAVAIL)),
+                        )
+                        .group_by(frps_id)
+                        .select((frps_id, diesel::dsl::count_star()))
+                        .load::<(String, i64)>(&mut conn)?)
+                }
+
+                fn query_nodes_by_filter(&self, filter: &NodeFilter) -> Result<Vec<models::NodeLimited>> {
+                    let mut conn = self.establish_connection()?;
+                    use schema::node_status::dsl::*;
+
+                    let mut query = node_status.into_boxed();
+
+                    if let Some(v) = &filter.status {
+                        query = query.filter(status.eq(v));
+                    }
+                    if let Some(v) = &filter.device_id {
+                        query = query.filter(device_id.eq(v));
+                    }
+                    if let Some(v) = &filter.chat_model {
+                        query = query.filter(chat_model.eq(v));
+                    }
+                    if let Some(v) = &filter.country {
+                        query = query.filter(country.eq(v));
+                    }
+                    if let Some(v) = &filter.subdivision {
+                        query = query.filter(subdivision.eq(v));
+                    }
+                    if let Some(v) = &filter.city {
+                        query = query.filter(city.eq(v));
+                    }
+                    if let Some(ids) = &filter.ids {
+                        query = query.filter(node_id.eq_any(ids));
+                    }
+                    if let Some(lived_secs) = filter.lived_secs {
+                        query = query.filter(lived_at_least(lived_secs));
+                    }
+
+                    let rows = query.select(NodeLimitedRow::as_select()).load(&mut conn)?;
+                    Ok(into_models(rows))
+                }
+
+                fn query_living_nodes(
+                    &self,
+                    lived_secs: u64,
+                    page: i64,
+                    size: i64,
+                ) -> Result<Vec<models::LivingNode>> {
+                    use schema::node_status::dsl::*;
+                    let mut conn = self.establish_connection()?;
+
+                    let rows = node_status
+                        .filter(status.eq(NODE_STATUS_ONLINE))
+                        .filter(lived_at_least(lived_secs))
+                        .order(login_time.asc())
+                        .limit(size)
+                        .offset(page * size)
+                        .select(LivingNodeRow::as_select())
+                        .load(&mut conn)?;
+                    Ok(into_models(rows))
+                }
+
+                fn query_living_nodes_by_login_time(
+                    &self,
+                    lived_secs: u64,
+                    page_size: i64,
+                    earliest_login_time: &NaiveDateTime,
+                ) -> Result<Vec<models::LivingNode>> {
+                    use schema::node_status::dsl::*;
+                    let mut conn = self.establish_connection()?;
+
+                    let rows = node_status
+                        .filter(
+                            status
+                                .eq(NODE_STATUS_ONLINE)
+                                .or(status.eq(NODE_STATUS_UNAVAIL)),
+                        )
+                        .filter(login_time.gt(to_db_time(earliest_login_time)))
+                        .filter(lived_at_least(lived_secs))
+                        .order(login_time.asc())
+                        .limit(page_size)
+                        .select(LivingNodeRow::as_select())
+                        .load(&mut conn)?;
+                    Ok(into_models(rows))
+                }
+
+                fn close_expired_nodes(&self, seconds_before: &NaiveDateTime) -> Result<usize> {
+                    let mut conn = self.establish_connection()?;
+                    use schema::node_status;
+
+                    Ok(diesel::update(node_status::table)
+                        .filter(node_status::last_active_time.lt(to_db_time(seconds_before)))
+                        .filter(node_status::status.eq(NODE_STATUS_ONLINE))
+                        .set((node_status::status.eq(NODE_STATUS_OFFLINE),))
+                        .execute(&mut conn)?)
+                }
+
+                fn unavail_expired_nodes(&self, seconds_before: &NaiveDateTime) -> Result<usize> {
+                    let mut conn = self.establish_connection()?;
+                    use schema::node_status;
+
+                    Ok(diesel::update(node_status::table)
+                        .filter(node_status::last_avail_time.lt(to_db_time(seconds_before)))
+                        .filter(node_status::status.eq(NODE_STATUS_ONLINE))
+                        .set((node_status::status.eq(NODE_STATUS_UNAVAIL),))
+                        .execute(&mut conn)?)
+                }
+
+                fn query_domain_nodes(&self, domain: &str) -> Result<Vec<models::DomainNodes>> {
+                    use schema::domain_nodes::dsl::{domain as d, domain_nodes};
+                    let mut conn = self.establish_connection()?;
+                    let rows = domain_nodes
+                        .filter(d.eq(domain))
+                        .select(DomainNodeRow::as_select())
+                        .load(&mut conn)?;
+                    Ok(into_models(rows))
+                }
+
+                fn query_domain_node(
+                    &self,
+                    domain: &str,
+                    node_id: &str,
+                ) -> Result<Option<models::DomainNodes>> {
+                    use schema::domain_nodes::dsl::{domain as d, domain_nodes, node_id as ni};
+                    let mut conn = self.establish_connection()?;
+                    Ok(domain_nodes
+                        .filter(d.eq(domain))
+                        .filter(ni.eq(node_id))
+                        .select(DomainNodeRow::as_select())
+                        .first(&mut conn)
+                        .optional()?
+                        .map(models::DomainNodes::from))
+                }
+
+                fn query_domain_nodes_by_node_id(&self, node_id: &str) -> Result<Vec<models::DomainNodes>> {
+                    use schema::domain_nodes::dsl::{domain as d, domain_nodes, node_id as ni};
+                    let mut conn = self.establish_connection()?;
+                    let rows = domain_nodes
+                        .filter(ni.eq(node_id))
+                        .order(d.asc())
+                        .select(DomainNodeRow::as_select())
+                        .load(&mut conn)?;
+                    Ok(into_models(rows))
+                }
+
+                fn apply_domain_node_changes(&self, changes: &[DomainNodeChange]) -> Result<()> {
+                    use schema::domain_nodes::dsl::{domain as d, domain_nodes, node_id as ni};
+                    let mut conn = self.establish_connection()?;
+
+                    conn.transaction::<_, diesel::result::Error, _>(|conn| {
+                        for change in changes {
+                            let written = match change {
+                                DomainNodeChange::Insert(domain_node) => diesel::insert_into(domain_nodes)
+                                    .values(&DomainNodeRow::from(domain_node))
+                                    .execute(conn)?,
+                                DomainNodeChange::Update(domain_node) => diesel::update(
+                                    domain_nodes
+                                        .filter(d.eq(&domain_node.domain))
+                                        .filter(ni.eq(&domain_node.node_id)),
+                                )
+                                .set(&DomainNodeRow::from(domain_node))
+                                .execute(conn)?,
+                                DomainNodeChange::Delete { domain, node_id } => {
+                                    diesel::delete(domain_nodes.filter(d.eq(domain)).filter(ni.eq(node_id)))
+                                        .execute(conn)?
+                                }
+                            };
+                            // The membership went away meanwhile
+                            if written == 0 {
+                                return Err(diesel::result::Error::NotFound);
+                            }
+                        }
+                        Ok(())
+                    })?;
+                    Ok(())
+                }
+
+                fn get_distinct_domains(&self) -> Result<Vec<String>> {
+                    use schema::domain_nodes::dsl::{domain, domain_nodes};
+                    let mut conn = self.establish_connection()?;
+                    Ok(domain_nodes
+                        .select(domain)
+                        .distinct()
+                        .load::<String>(&mut conn)?)
+                }
+
+                fn get_nodes_by_domain(&self, domain: &str) -> Result<Vec<(String, i64)>> {
+                    use schema::domain_nodes::dsl::{
+                        domain as d, domain_nodes, draining_since, node_id as dni, weight,
+                    };
+                    use schema::node_status::dsl::{node_id as nid, node_status, status};
+                    let mut conn = self.establish_connection()?;
+                    Ok(domain_nodes
+                        .inner_join(node_status.on(nid.eq(dni)))
+                        .filter(d.eq(domain))
+                        .filter(status.eq(NODE_STATUS_ONLINE))
+                        .select((dni, weight, draining_since))
+                        .load::<(String, i64, Option<Time>)>(&mut conn)?
+                        .into_iter()
+                        .map(|(node_id, w, since)| match since {
+                            Some(_) => (node_id, 0),
+                            None => (node_id, w),
+                        })
+                        .collect())
+                }
+
+                fn query_user_conn_limits_by_subdomain(&self, subdomain: &str) -> Result<Vec<i64>> {
+                    use schema::domain_nodes::dsl::{domain as d, domain_nodes, node_id as dni};
+                    use schema::domains::dsl::{domains, name, user_conn_limit};
+                    use schema::node_status::dsl::{node_id as nid, node_status, status, subdomain as sd};
+                    let mut conn = self.establish_connection()?;
+                    Ok(domain_nodes
+                        .inner_join(node_status.on(nid.eq(dni)))
+                        .inner_join(domains.on(name.eq(d)))
+                        .filter(sd.eq(subdomain))
+                        .filter(status.eq_any([NODE_STATUS_ONLINE, NODE_STATUS_UNAVAIL]))
+                        .filter(user_conn_limit.is_not_null())
+                        .select(user_conn_limit)
+                        .load::<Option<i64>>(&mut conn)?
+                        .into_iter()
+                        .flatten()
+                        .collect())
+                }
+
+                fn query_draining_domain_nodes(&self) -> Result<Vec<models::DomainNodes>> {
+                    use schema::domain_nodes::dsl::{domain_nodes, draining_since};
+                    let mut conn = self.establish_connection()?;
+                    let rows = domain_nodes
+                        .filter(draining_since.is_not_null())
+                        .select(DomainNodeRow::as_select())
+                        .load(&mut conn)?;
+                    Ok(into_models(rows))
+                }
+
+                fn update_draining_user_conn_time(
+                    &self,
+                    domain: &str,
+                    node_id: &str,
+                    time: &NaiveDateTime,
+                ) -> Result<usize> {
+                    use schema::domain_nodes::dsl::{
+                        domain as d, domain_nodes, draining_since, last_user_conn_time, node_id as ni,
+                    };
+                    let mut conn = self.establish_connection()?;
+                    Ok(diesel::update(domain_nodes)
+                        .filter(d.eq(domain))
+                        .filter(ni.eq(node_id))
+                        .filter(draining_since.is_not_null())
+                        .set(last_user_conn_time.eq(to_db_time(time)))
+                        .execute(&mut conn)?)
+                }
+
+                fn delete_drained_domain_node(&self, domain: &str, node_id: &str) -> Result<usize> {
+                    use schema::domain_nodes::dsl::{domain as d, domain_nodes, draining_since, node_id as ni};
+                    let mut conn = self.establish_connection()?;
+                    Ok(diesel::delete(
+                        domain_nodes
+                            .filter(d.eq(domain))
+                            .filter(ni.eq(node_id))
+                            .filter(draining_since.is_not_null()),
+                    )
+                    .execute(&mut conn)?)
+                }
+
+                fn insert_domain(&self, domain: &models::NewDomain) -> Result<usize> {
+                    use schema::domains;
+                    let mut conn = self.establish_connection()?;
+                    Ok(diesel::insert_into(domains::table)
+                        .values(&NewDomainRow::from(domain))
+                        .execute(&mut conn)?)
+                }
+
+                fn update_domain(&self, domain: &models::NewDomain) -> Result<usize> {
+                    use schema::domains;
+                    let mut conn = self.establish_connection()?;
+                    Ok(diesel::update(domains::table.filter(domains::name.eq(domain.name)))
+                        .set(&NewDomainRow::from(domain))
+                        .execute(&mut conn)?)
+                }
+
+                fn query_domain(&self, name: &str) -> Result<Option<models::Domain>> {
+                    use schema::domains::dsl::{domains, name as n};
+                    let mut conn = self.establish_connection()?;
+                    Ok(domains
+                        .filter(n.eq(name))
+                        .select(DomainRow::as_select())
+                        .first(&mut conn)
+                        .optional()?
+                        .map(models::Domain::from))
+                }
+
+                fn query_domains(&self, status: Option<&str>) -> Result<Vec<models::Domain>> {
+                    use schema::domains::dsl::{domains, name, status as s};
+                    let mut conn = self.establish_connection()?;
+                    let mut query = domains.into_boxed();
+                    if let Some(status) = status {
+                        query = query.filter(s.eq(status));
+                    }
+                    let rows = query
+                        .order(name.asc())
+                        .select(DomainRow::as_select())
+                        .load(&mut conn)?;
+                    Ok(into_models(rows))
+                }
+
+                fn delete_domain(&self, name: &str) -> Result<usize> {
+                    use schema::domains::dsl::{domains, name as n};
+                    let mut conn = self.establish_connection()?;
+                    Ok(diesel::delete(domains.filter(n.eq(name))).execute(&mut conn)?)
+                }
+
+                fn insert_ban(&self, kind: &str, value: &str, reason: &str) -> Result<usize> {
+                    use schema::bans;
+                    let ban = NewBanRow {
+                        kind,
+                        value,
+                        reason,
+                    };
+
+                    let mut conn = self.establish_connection()?;
+
+                    Ok(diesel::insert_into(bans::table)
+                        .values(&ban)
+                        .execute(&mut conn)?)
+                }
+
+                fn update_ban(&self, kind: &str, value: &str, reason: &str) -> Result<usize> {
+                    use schema::bans::dsl::{bans, kind as k, reason as r, value as v};
+                    let mut conn = self.establish_connection()?;
+                    Ok(diesel::update(bans.filter(k.eq(kind)).filter(v.eq(value)))
+                        .set(r.eq(reason))
+                        .execute(&mut conn)?)
+                }
+
+                fn delete_ban(&self, kind: &str, value: &str) -> Result<usize> {
+                    use schema::bans::dsl::{bans, kind as k, value as v};
+                    let mut conn = self.establish_connection()?;
+                    Ok(diesel::delete(bans.filter(k.eq(kind)).filter(v.eq(value))).execute(&mut conn)?)
+                }
+
+                fn query_bans(&self, kind: Option<&str>) -> Result<Vec<models::Ban>> {
+                    use schema::bans::dsl::{bans, id, kind as k};
+                    let mut conn = self.establish_connection()?;
+                    let mut query = bans.into_boxed();
+                    if let Some(kind) = kind {
+                        query = query.filter(k.eq(kind));
+                    }
+                    let rows = query
+                        .order(id.asc())
+                        .select(BanRow::as_select())
+                        .load(&mut conn)?;
+                    Ok(into_models(rows))
+                }
+
+                fn query_ban(&self, kind: &str, value: &str) -> Result<Option<models::Ban>> {
+                    use schema::bans::dsl::{bans, kind as k, value as v};
+                    let mut conn = self.establish_connection()?;
+                    Ok(bans
+                        .filter(k.eq(kind))
+                        .filter(v.eq(value))
+                        .select(BanRow::as_select())
+                        .first(&mut conn)
+                        .optional()?
+                        .map(models::Ban::from))
+                }
+
+                fn upsert_frps_server(
+                    &self,
+                    frps_id: &str,
+                    version: Option<&str>,
+                    region: Option<&str>,
+                    last_seen_time: &NaiveDateTime,
+                ) -> Result<usize> {
+                    use schema::frps_servers;
+                    let server = FrpsServerSeenRow {
+                        frps_id,
+                        version,
+                        region,
+                        status: NODE_STATUS_ONLINE,
+                        last_seen_time: to_db_time(last_seen_time),
+                    };
+
+                    let mut conn = self.establish_connection()?;
+
+                    // A single statement, so that instances seeing the same frps don't race
+                    Ok(diesel::insert_into(frps_servers::table)
+                        .values(&server)
+                        .on_conflict($frps_conflict_target)
+                        .do_update()
+                        .set(&server)
+                        .execute(&mut conn)?)
+                }
+
+                fn query_frps_servers(&self) -> Result<Vec<models::FrpsServer>> {
+                    use schema::frps_servers::dsl::{frps_id, frps_servers};
+                    let mut conn = self.establish_connection()?;
+                    let rows = frps_servers
+                        .order(frps_id.asc())
+                        .select(FrpsServerRow::as_select())
+                        .load(&mut conn)?;
+                    Ok(into_models(rows))
+                }
+
+                fn query_silent_frps_servers(
+                    &self,
+                    seconds_before: &NaiveDateTime,
+                ) -> Result<Vec<models::FrpsServer>> {
+                    use schema::frps_servers::dsl::{frps_servers, last_seen_time, status};
+                    let mut conn = self.establish_connection()?;
+                    let rows = frps_servers
+                        .filter(status.eq(NODE_STATUS_ONLINE))
+                        .filter(last_seen_time.lt(to_db_time(seconds_before)))
+                        .select(FrpsServerRow::as_select())
+                        .load(&mut conn)?;
+                    Ok(into_models(rows))
+                }
+
+                fn update_frps_server_status(&self, frps_id: &str, status: &str) -> Result<usize> {
+                    use schema::frps_servers;
+                    let mut conn = self.establish_connection()?;
+                    Ok(
+                        diesel::update(frps_servers::table.filter(frps_servers::frps_id.eq(frps_id)))
+                            .set((frps_servers::status.eq(status),))
+                            .execute(&mut conn)?,
+                    )
+                }
+            }
+        }
+    };
+}
+
+pub(super) use sql_store;
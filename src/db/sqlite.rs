@@ -0,0 +1,232 @@
+use chrono::NaiveDateTime;
+use diesel::connection::SimpleConnection;
+use diesel::r2d2::ConnectionManager;
+use diesel::SqliteConnection;
+use diesel_migrations::{embed_migrations, EmbeddedMigrations};
+use serde_json::Value;
+use std::time::Duration;
+
+use crate::db::pool_builder;
+use crate::db::sql::sql_store;
+use gaia_hub::*;
+
+// SQLite stores the times as unix timestamps and the metas as JSON text
+sql_store! {
+    store: SqliteStore,
+    schema: crate::schema::sqlite,
+    backend: diesel::sqlite::Sqlite,
+    conn: diesel::SqliteConnection,
+    time: i64 => (to_db_time, from_db_time),
+    meta: String => (to_db_meta, from_db_meta),
+    lived_secs_sql: "(last_active_time - login_time)",
+    frps_conflict_target: crate::schema::sqlite::frps_servers::frps_id,
+    migrations: MIGRATIONS,
+}
+
+const MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations/sqlite");
+
+fn to_db_time(time: &NaiveDateTime) -> i64 {
+    time.and_utc().timestamp()
+}
+
+fn from_db_time(time: i64) -> NaiveDateTime {
+    chrono::DateTime::from_timestamp(time, 0)
+        .unwrap_or_default()
+        .naive_utc()
+}
+
+fn to_db_meta(meta: &Value) -> String {
+    meta.to_string()
+}
+
+fn from_db_meta(meta: String) -> Value {
+    serde_json::from_str(&meta).unwrap_or_default()
+}
+
+// To prevent error: database is locked
+// https://stackoverflow.com/questions/57123453/how-to-use-diesel-with-sqlite-connections-and-avoid-database-is-locked-type-of
+#[derive(Debug)]
+pub struct ConnectionOptions {
+    pub enable_wal: bool,
+    pub enable_foreign_keys: bool,
+    pub busy_timeout: Option<Duration>,
+}
+
+impl diesel::r2d2::CustomizeConnection<SqliteConnection, diesel::r2d2::Error>
+    for ConnectionOptions
+{
+    fn on_acquire(
+        &self,
+        conn: &mut SqliteConnection,
+    ) -> std::result::Result<(), diesel::r2d2::Error> {
+        (|| {
+            if self.enable_wal {
+                conn.batch_execute("PRAGMA journal_mode = WAL; PRAGMA synchronous = NORMAL;")?;
+            }
+            if self.enable_foreign_keys {
+                conn.batch_execute("PRAGMA foreign_keys = ON;")?;
+            }
+            if let Some(d) = self.busy_timeout {
+                conn.batch_execute(&format!("PRAGMA busy_timeout = {};", d.as_millis()))?;
+            }
+            Ok(())
+        })()
+        .map_err(diesel::r2d2::Error::QueryError)
+    }
+}
+
+impl SqliteStore {
+    pub fn connect(database_url: &str) -> Result<Self> {
+        let manager = ConnectionManager::<SqliteConnection>::new(database_url);
+        let mut builder = pool_builder().connection_customizer(Box::new(ConnectionOptions {
+            enable_wal: true,
+            enable_foreign_keys: true,
+            busy_timeout: Some(Duration::from_secs(30)),
+        }));
+        // Every connection to `:memory:` opens a database of its own, so keep a single one
+        // for the life of the pool
+        if database_url == ":memory:" {
+            builder = builder
+                .max_size(1)
+                .min_idle(Some(1))
+                .idle_timeout(None)
+                .max_lifetime(None);
+        }
+        Ok(SqliteStore::new(builder.build(manager)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{NodeFilter, NodeStore};
+    use crate::models;
+
+    fn store() -> SqliteStore {
+        let store = SqliteStore::connect(":memory:").unwrap();
+        store.run_migrations().unwrap();
+        store
+    }
+
+    fn login_time() -> NaiveDateTime {
+        chrono::DateTime::from_timestamp(1_700_000_000, 0)
+            .unwrap()
+            .naive_utc()
+    }
+
+    // A node last active `lived` seconds after it logged in at `login_time`
+    fn add_node(
+        store: &SqliteStore,
+        node_id: &str,
+        status: &str,
+        login_time: NaiveDateTime,
+        lived: i64,
+    ) {
+        let last_active_time = login_time + chrono::Duration::seconds(lived);
+        let subdomain = format!("{}.gaia", node_id);
+        store
+            .create_node_status(&models::NewNode {
+                node_id,
+                device_id: "dev1",
+                subdomain: &subdomain,
+                version: "0.1.0",
+                arch: "x86_64",
+                os: "linux",
+                client_address: "127.0.0.1",
+                status,
+                login_time: &login_time,
+                last_active_time: &last_active_time,
+                run_id: node_id,
+                meta: &Value::Null,
+                frps_id: "",
+            })
+            .unwrap();
+    }
+
+    // Nodes which lived just under, exactly and just over 10 minutes
+    fn add_lived_nodes(store: &SqliteStore, status: &str) {
+        add_node(store, "under", status, login_time(), 599);
+        add_node(
+            store,
+            "exact",
+            status,
+            login_time() + chrono::Duration::seconds(1),
+            600,
+        );
+        add_node(
+            store,
+            "over",
+            status,
+            login_time() + chrono::Duration::seconds(2),
+            601,
+        );
+    }
+
+    #[test]
+    fn filter_keeps_nodes_which_lived_the_threshold() {
+        let store = store();
+        add_lived_nodes(&store, NODE_STATUS_ONLINE);
+
+        let filter = NodeFilter {
+            lived_secs: Some(600),
+            ..Default::default()
+        };
+        let mut node_ids: Vec<String> = store
+            .query_nodes_by_filter(&filter)
+            .unwrap()
+            .into_iter()
+            .map(|node| node.node_id)
+            .collect();
+        node_ids.sort();
+        assert_eq!(node_ids, ["exact", "over"]);
+
+        let filter = NodeFilter {
+            lived_secs: Some(0),
+            ..Default::default()
+        };
+        assert_eq!(store.query_nodes_by_filter(&filter).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn living_nodes_lived_the_threshold() {
+        let store = store();
+        add_lived_nodes(&store, NODE_STATUS_ONLINE);
+        add_node(&store, "unavail", NODE_STATUS_UNAVAIL, login_time(), 3600);
+
+        let node_ids: Vec<String> = store
+            .query_living_nodes(600, 0, 10)
+            .unwrap()
+            .into_iter()
+            .map(|node| node.node_id)
+            .collect();
+        assert_eq!(node_ids, ["exact", "over"]);
+        assert_eq!(store.query_living_nodes(601, 0, 10).unwrap().len(), 1);
+        assert!(store.query_living_nodes(600, 1, 10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn living_nodes_by_login_time_lived_the_threshold() {
+        let store = store();
+        add_lived_nodes(&store, NODE_STATUS_UNAVAIL);
+        add_node(&store, "offline", NODE_STATUS_OFFLINE, login_time(), 3600);
+
+        let earliest = chrono::DateTime::UNIX_EPOCH.naive_utc();
+        let node_ids: Vec<String> = store
+            .query_living_nodes_by_login_time(600, 10, &earliest)
+            .unwrap()
+            .into_iter()
+            .map(|node| node.node_id)
+            .collect();
+        assert_eq!(node_ids, ["exact", "over"]);
+
+        // The next page starts after the last login time seen
+        let earliest = login_time() + chrono::Duration::seconds(1);
+        let node_ids: Vec<String> = store
+            .query_living_nodes_by_login_time(600, 10, &earliest)
+            .unwrap()
+            .into_iter()
+            .map(|node| node.node_id)
+            .collect();
+        assert_eq!(node_ids, ["over"]);
+    }
+}
@@ -0,0 +1,581 @@
+// The contract of the stores, run against the memory store and an in-memory sqlite store so
+// both keep the same semantics.
+
+use chrono::NaiveDateTime;
+use serde_json::Value;
+
+use gaia_hub::*;
+
+use crate::db::{DomainNodeChange, MemoryStore, NodeFilter, NodeStore};
+use crate::models;
+
+/// Every store of this build, empty and migrated, with its name for the assertion messages.
+pub(crate) fn stores() -> Vec<(&'static str, Box<dyn NodeStore>)> {
+    let stores: Vec<(&'static str, Box<dyn NodeStore>)> = vec![
+        ("memory", Box::new(MemoryStore::new())),
+        #[cfg(feature = "sqlite")]
+        (
+            "sqlite",
+            Box::new(super::sqlite::SqliteStore::connect(":memory:").unwrap()),
+        ),
+    ];
+    for (_, store) in stores.iter() {
+        store.run_migrations().unwrap();
+    }
+    stores
+}
+
+/// A fixed time in whole seconds, as sqlite keeps them.
+pub(crate) fn time(secs: i64) -> NaiveDateTime {
+    chrono::DateTime::from_timestamp(1_700_000_000 + secs, 0)
+        .unwrap()
+        .naive_utc()
+}
+
+/// Record a node of `dev1` served at `{node_id}.gaia`, logged in at `time(0)`.
+pub(crate) fn add_node(store: &dyn NodeStore, node_id: &str, status: &str) {
+    let subdomain = format!("{}.gaia", node_id);
+    store
+        .create_node_status(&models::NewNode {
+            node_id,
+            device_id: "dev1",
+            subdomain: &subdomain,
+            version: "0.1.0",
+            arch: "x86_64",
+            os: "linux",
+            client_address: "127.0.0.1",
+            status,
+            login_time: &time(0),
+            last_active_time: &time(60),
+            run_id: node_id,
+            meta: &Value::Null,
+            frps_id: "frps_1",
+        })
+        .unwrap();
+}
+
+pub(crate) fn domain_node(domain: &str, node_id: &str, weight: i64) -> models::DomainNodes {
+    models::DomainNodes {
+        domain: domain.to_string(),
+        node_id: node_id.to_string(),
+        weight,
+        draining_since: None,
+        drain_deadline: None,
+        last_user_conn_time: None,
+    }
+}
+
+fn sorted<T: Ord>(mut items: Vec<T>) -> Vec<T> {
+    items.sort();
+    items
+}
+
+#[test]
+fn devices_are_unique_and_updated_in_place() {
+    for (name, store) in stores() {
+        let device = models::NewDevice {
+            device_id: "dev1",
+            version: "0.1.0",
+            arch: "x86_64",
+            os: "linux",
+            client_address: "127.0.0.1",
+            login_time: &time(0),
+            meta: &serde_json::json!({"gpu": "none"}),
+            public_key: "",
+        };
+        assert_eq!(store.create_device(&device).unwrap(), 1, "{}", name);
+        assert!(store.create_device(&device).is_err(), "{}", name);
+
+        assert_eq!(
+            store.update_device("dev1", &time(10)).unwrap(),
+            1,
+            "{}",
+            name
+        );
+        assert_eq!(
+            store.update_device_public_key("dev1", "ab").unwrap(),
+            1,
+            "{}",
+            name
+        );
+        assert_eq!(
+            store.update_device("dev2", &time(10)).unwrap(),
+            0,
+            "{}",
+            name
+        );
+
+        let devices = store.query_device_by_device_id("dev1").unwrap();
+        assert_eq!(devices.len(), 1, "{}", name);
+        assert_eq!(devices[0].login_time, time(10), "{}", name);
+        assert_eq!(devices[0].public_key, "ab", "{}", name);
+        assert_eq!(
+            devices[0].meta,
+            serde_json::json!({"gpu": "none"}),
+            "{}",
+            name
+        );
+    }
+}
+
+#[test]
+fn nodes_are_unique_by_node_id_and_subdomain() {
+    for (name, store) in stores() {
+        add_node(store.as_ref(), "n1", NODE_STATUS_ONLINE);
+        let duplicate = models::NewNode {
+            node_id: "n2",
+            device_id: "dev1",
+            subdomain: "n1.gaia",
+            version: "0.1.0",
+            arch: "x86_64",
+            os: "linux",
+            client_address: "127.0.0.1",
+            status: NODE_STATUS_ONLINE,
+            login_time: &time(0),
+            last_active_time: &time(0),
+            run_id: "n2",
+            meta: &Value::Null,
+            frps_id: "",
+        };
+        assert!(store.create_node_status(&duplicate).is_err(), "{}", name);
+
+        let node = store.query_node_by_subdomain("n1.gaia").unwrap().unwrap();
+        assert_eq!(node.node_id, "n1", "{}", name);
+        assert_eq!(node.login_time, time(0), "{}", name);
+        assert_eq!(node.last_avail_time, None, "{}", name);
+        assert!(
+            store.query_node_by_node_id("n2").unwrap().is_none(),
+            "{}",
+            name
+        );
+    }
+}
+
+#[test]
+fn active_nodes_are_online_or_unavail() {
+    for (name, store) in stores() {
+        add_node(store.as_ref(), "n1", NODE_STATUS_ONLINE);
+        add_node(store.as_ref(), "n2", NODE_STATUS_UNAVAIL);
+        add_node(store.as_ref(), "n3", NODE_STATUS_OFFLINE);
+
+        let active: Vec<String> = store
+            .query_active_nodes()
+            .unwrap()
+            .into_iter()
+            .map(|node| node.node_id)
+            .collect();
+        assert_eq!(sorted(active), ["n1", "n2"], "{}", name);
+        assert_eq!(
+            store.count_active_nodes_by_frps_id().unwrap(),
+            [(String::from("frps_1"), 2)],
+            "{}",
+            name
+        );
+
+        assert_eq!(
+            store.update_node_status("n2", NODE_STATUS_OFFLINE).unwrap(),
+            1,
+            "{}",
+            name
+        );
+        assert_eq!(store.query_active_nodes().unwrap().len(), 1, "{}", name);
+    }
+}
+
+#[test]
+fn expired_nodes_are_closed_or_unavail() {
+    for (name, store) in stores() {
+        add_node(store.as_ref(), "n1", NODE_STATUS_ONLINE);
+        add_node(store.as_ref(), "n2", NODE_STATUS_ONLINE);
+        store
+            .update_node_avail_time_and_status("n1", &time(30), NODE_STATUS_ONLINE)
+            .unwrap();
+
+        // n2 has never been available, so it isn't unavail yet
+        assert_eq!(
+            store.unavail_expired_nodes(&time(31)).unwrap(),
+            1,
+            "{}",
+            name
+        );
+        let n1 = store.query_node_by_node_id("n1").unwrap().unwrap();
+        assert_eq!(n1.status, NODE_STATUS_UNAVAIL, "{}", name);
+        assert_eq!(n1.last_avail_time, Some(time(30)), "{}", name);
+
+        // Both were last active at time(60), only online ones are closed
+        assert_eq!(store.close_expired_nodes(&time(60)).unwrap(), 0, "{}", name);
+        assert_eq!(store.close_expired_nodes(&time(61)).unwrap(), 1, "{}", name);
+        let n2 = store.query_node_by_node_id("n2").unwrap().unwrap();
+        assert_eq!(n2.status, NODE_STATUS_OFFLINE, "{}", name);
+    }
+}
+
+#[test]
+fn nodes_filter_on_every_field() {
+    for (name, store) in stores() {
+        add_node(store.as_ref(), "n1", NODE_STATUS_ONLINE);
+        add_node(store.as_ref(), "n2", NODE_STATUS_OFFLINE);
+        store
+            .update_nodes_info_by_device_id("dev1", "0.2.0", "llama", "nomic")
+            .unwrap();
+        store
+            .update_node_location("n1", "FR", "IDF", "Paris")
+            .unwrap();
+
+        let node_ids = |filter: NodeFilter| -> Vec<String> {
+            sorted(
+                store
+                    .query_nodes_by_filter(&filter)
+                    .unwrap()
+                    .into_iter()
+                    .map(|node| node.node_id)
+                    .collect(),
+            )
+        };
+        assert_eq!(node_ids(NodeFilter::default()), ["n1", "n2"], "{}", name);
+        let filter = NodeFilter {
+            status: Some(NODE_STATUS_OFFLINE.to_string()),
+            ..Default::default()
+        };
+        assert_eq!(node_ids(filter), ["n2"], "{}", name);
+        let filter = NodeFilter {
+            chat_model: Some(String::from("llama")),
+            city: Some(String::from("Paris")),
+            ..Default::default()
+        };
+        assert_eq!(node_ids(filter), ["n1"], "{}", name);
+        let filter = NodeFilter {
+            ids: Some(vec![String::from("n2"), String::from("n3")]),
+            ..Default::default()
+        };
+        assert_eq!(node_ids(filter), ["n2"], "{}", name);
+    }
+}
+
+#[test]
+fn domain_node_changes_apply_all_or_none() {
+    for (name, store) in stores() {
+        store
+            .apply_domain_node_changes(&[
+                DomainNodeChange::Insert(domain_node("d1", "n1", 1)),
+                DomainNodeChange::Insert(domain_node("d2", "n1", 2)),
+            ])
+            .unwrap();
+
+        // The duplicate fails the batch, so n2 isn't inserted and n1 keeps its weight
+        let failed = store.apply_domain_node_changes(&[
+            DomainNodeChange::Insert(domain_node("d1", "n2", 1)),
+            DomainNodeChange::Update(domain_node("d1", "n1", 5)),
+            DomainNodeChange::Insert(domain_node("d2", "n1", 3)),
+        ]);
+        assert!(failed.is_err(), "{}", name);
+        assert!(
+            store.query_domain_node("d1", "n2").unwrap().is_none(),
+            "{}",
+            name
+        );
+        assert_eq!(
+            store.query_domain_node("d1", "n1").unwrap().unwrap().weight,
+            1,
+            "{}",
+            name
+        );
+
+        // Updating or deleting a missing membership fails the batch too
+        let missing = [
+            DomainNodeChange::Update(domain_node("d3", "n1", 1)),
+            DomainNodeChange::Delete {
+                domain: String::from("d1"),
+                node_id: String::from("n9"),
+            },
+        ];
+        for change in missing {
+            let failed = store.apply_domain_node_changes(&[
+                DomainNodeChange::Delete {
+                    domain: String::from("d2"),
+                    node_id: String::from("n1"),
+                },
+                change,
+            ]);
+            assert!(failed.is_err(), "{}", name);
+            assert!(
+                store.query_domain_node("d2", "n1").unwrap().is_some(),
+                "{}",
+                name
+            );
+        }
+
+        store
+            .apply_domain_node_changes(&[
+                DomainNodeChange::Update(domain_node("d1", "n1", 5)),
+                DomainNodeChange::Delete {
+                    domain: String::from("d2"),
+                    node_id: String::from("n1"),
+                },
+            ])
+            .unwrap();
+        let memberships = store.query_domain_nodes_by_node_id("n1").unwrap();
+        assert_eq!(memberships.len(), 1, "{}", name);
+        assert_eq!(memberships[0].weight, 5, "{}", name);
+        assert_eq!(store.get_distinct_domains().unwrap(), ["d1"], "{}", name);
+    }
+}
+
+#[test]
+fn domains_route_their_online_nodes_with_routed_weights() {
+    for (name, store) in stores() {
+        add_node(store.as_ref(), "n1", NODE_STATUS_ONLINE);
+        add_node(store.as_ref(), "n2", NODE_STATUS_ONLINE);
+        add_node(store.as_ref(), "n3", NODE_STATUS_UNAVAIL);
+        let mut draining = domain_node("d1", "n2", 4);
+        draining.draining_since = Some(time(0));
+        store
+            .apply_domain_node_changes(&[
+                DomainNodeChange::Insert(domain_node("d1", "n1", 3)),
+                DomainNodeChange::Insert(draining),
+                DomainNodeChange::Insert(domain_node("d1", "n3", 2)),
+                DomainNodeChange::Insert(domain_node("d2", "n1", 7)),
+            ])
+            .unwrap();
+
+        assert_eq!(
+            sorted(store.get_nodes_by_domain("d1").unwrap()),
+            [(String::from("n1"), 3), (String::from("n2"), 0)],
+            "{}",
+            name
+        );
+        assert_eq!(
+            store.get_nodes_by_domain("d2").unwrap(),
+            [(String::from("n1"), 7)],
+            "{}",
+            name
+        );
+        let domains: Vec<String> = store
+            .query_domain_nodes_by_node_id("n1")
+            .unwrap()
+            .into_iter()
+            .map(|dn| dn.domain)
+            .collect();
+        assert_eq!(domains, ["d1", "d2"], "{}", name);
+    }
+}
+
+#[test]
+fn user_conn_limits_come_from_the_domains_of_active_nodes() {
+    for (name, store) in stores() {
+        add_node(store.as_ref(), "n1", NODE_STATUS_ONLINE);
+        add_node(store.as_ref(), "n2", NODE_STATUS_OFFLINE);
+        for (domain, user_conn_limit) in [("d1", Some(10)), ("d2", None), ("d3", Some(0))] {
+            store
+                .insert_domain(&models::NewDomain {
+                    name: domain,
+                    owner: "",
+                    description: "",
+                    chat_model: "",
+                    embedding_model: "",
+                    status: DOMAIN_STATUS_ACTIVE,
+                    health_probe: None,
+                    user_conn_limit,
+                })
+                .unwrap();
+        }
+        store
+            .apply_domain_node_changes(&[
+                DomainNodeChange::Insert(domain_node("d1", "n1", 1)),
+                DomainNodeChange::Insert(domain_node("d2", "n1", 1)),
+                DomainNodeChange::Insert(domain_node("d3", "n1", 1)),
+                DomainNodeChange::Insert(domain_node("d1", "n2", 1)),
+            ])
+            .unwrap();
+
+        let limits = |subdomain| {
+            sorted(
+                store
+                    .query_user_conn_limits_by_subdomain(subdomain)
+                    .unwrap(),
+            )
+        };
+        assert_eq!(limits("n1.gaia"), [0, 10], "{}", name);
+        assert!(limits("n2.gaia").is_empty(), "{}", name);
+        assert!(limits("n3.gaia").is_empty(), "{}", name);
+    }
+}
+
+#[test]
+fn drained_memberships_change_only_while_draining() {
+    for (name, store) in stores() {
+        let mut draining = domain_node("d1", "n1", 4);
+        draining.draining_since = Some(time(0));
+        draining.drain_deadline = Some(time(600));
+        store
+            .apply_domain_node_changes(&[
+                DomainNodeChange::Insert(draining),
+                DomainNodeChange::Insert(domain_node("d1", "n2", 1)),
+            ])
+            .unwrap();
+
+        let drained = store.query_draining_domain_nodes().unwrap();
+        assert_eq!(drained.len(), 1, "{}", name);
+        assert_eq!(drained[0].drain_deadline, Some(time(600)), "{}", name);
+
+        let update = |node_id| store.update_draining_user_conn_time("d1", node_id, &time(5));
+        assert_eq!(update("n1").unwrap(), 1, "{}", name);
+        assert_eq!(update("n2").unwrap(), 0, "{}", name);
+        let n1 = store.query_domain_node("d1", "n1").unwrap().unwrap();
+        assert_eq!(n1.last_user_conn_time, Some(time(5)), "{}", name);
+
+        assert_eq!(
+            store.delete_drained_domain_node("d1", "n2").unwrap(),
+            0,
+            "{}",
+            name
+        );
+        assert_eq!(
+            store.delete_drained_domain_node("d1", "n1").unwrap(),
+            1,
+            "{}",
+            name
+        );
+        assert!(
+            store.query_draining_domain_nodes().unwrap().is_empty(),
+            "{}",
+            name
+        );
+        assert_eq!(store.query_domain_nodes("d1").unwrap().len(), 1, "{}", name);
+    }
+}
+
+#[test]
+fn domains_keep_their_fields_and_probe() {
+    for (name, store) in stores() {
+        let probe = serde_json::json!({"kind": "tcp", "port": 22});
+        let mut domain = models::NewDomain {
+            name: "d1",
+            owner: "alice",
+            description: "",
+            chat_model: "llama*",
+            embedding_model: "",
+            status: DOMAIN_STATUS_ACTIVE,
+            health_probe: Some(&probe),
+            user_conn_limit: Some(100),
+        };
+        assert_eq!(store.insert_domain(&domain).unwrap(), 1, "{}", name);
+        assert!(store.insert_domain(&domain).is_err(), "{}", name);
+
+        domain.status = DOMAIN_STATUS_SUSPENDED;
+        domain.health_probe = None;
+        domain.user_conn_limit = None;
+        assert_eq!(store.update_domain(&domain).unwrap(), 1, "{}", name);
+        let d1 = store.query_domain("d1").unwrap().unwrap();
+        assert_eq!(d1.chat_model, "llama*", "{}", name);
+        assert_eq!(d1.status, DOMAIN_STATUS_SUSPENDED, "{}", name);
+        assert_eq!(d1.health_probe, None, "{}", name);
+        assert_eq!(d1.user_conn_limit, None, "{}", name);
+
+        domain.name = "d0";
+        domain.status = DOMAIN_STATUS_ACTIVE;
+        domain.health_probe = Some(&probe);
+        domain.user_conn_limit = Some(100);
+        store.insert_domain(&domain).unwrap();
+        let names = |status| -> Vec<String> {
+            store
+                .query_domains(status)
+                .unwrap()
+                .into_iter()
+                .map(|d| d.name)
+                .collect()
+        };
+        assert_eq!(names(None), ["d0", "d1"], "{}", name);
+        assert_eq!(names(Some(DOMAIN_STATUS_ACTIVE)), ["d0"], "{}", name);
+        let d0 = store.query_domain("d0").unwrap().unwrap();
+        assert_eq!(d0.health_probe, Some(probe.clone()), "{}", name);
+        assert_eq!(d0.user_conn_limit, Some(100), "{}", name);
+
+        assert_eq!(store.delete_domain("d1").unwrap(), 1, "{}", name);
+        assert_eq!(store.delete_domain("d1").unwrap(), 0, "{}", name);
+    }
+}
+
+#[test]
+fn bans_are_unique_by_kind_and_value() {
+    for (name, store) in stores() {
+        assert_eq!(
+            store.insert_ban("ip", "10.0.0.0/8", "abuse").unwrap(),
+            1,
+            "{}",
+            name
+        );
+        assert!(
+            store.insert_ban("ip", "10.0.0.0/8", "again").is_err(),
+            "{}",
+            name
+        );
+        store.insert_ban("device", "dev1", "").unwrap();
+
+        assert_eq!(store.query_bans(None).unwrap().len(), 2, "{}", name);
+        let ip_bans = store.query_bans(Some("ip")).unwrap();
+        assert_eq!(ip_bans.len(), 1, "{}", name);
+        assert_eq!(ip_bans[0].reason, "abuse", "{}", name);
+        assert!(
+            store.query_ban("device", "dev1").unwrap().is_some(),
+            "{}",
+            name
+        );
+
+        assert_eq!(
+            store.update_ban("ip", "10.0.0.0/8", "spam").unwrap(),
+            1,
+            "{}",
+            name
+        );
+        let ban = store.query_ban("ip", "10.0.0.0/8").unwrap().unwrap();
+        assert_eq!(ban.id, ip_bans[0].id, "{}", name);
+        assert_eq!(ban.reason, "spam", "{}", name);
+        assert_eq!(
+            store.update_ban("ip", "fd00::/8", "spam").unwrap(),
+            0,
+            "{}",
+            name
+        );
+
+        assert_eq!(store.delete_ban("ip", "10.0.0.0/8").unwrap(), 1, "{}", name);
+        assert_eq!(store.delete_ban("ip", "10.0.0.0/8").unwrap(), 0, "{}", name);
+    }
+}
+
+#[test]
+fn frps_servers_are_upserted_and_silenced() {
+    for (name, store) in stores() {
+        store
+            .upsert_frps_server("frps_1", Some("0.58.0"), Some("eu"), &time(0))
+            .unwrap();
+        store
+            .upsert_frps_server("frps_1", None, None, &time(10))
+            .unwrap();
+        store
+            .upsert_frps_server("frps_2", None, None, &time(20))
+            .unwrap();
+
+        let servers = store.query_frps_servers().unwrap();
+        assert_eq!(servers.len(), 2, "{}", name);
+        assert_eq!(servers[0].version, "0.58.0", "{}", name);
+        assert_eq!(servers[0].region, "eu", "{}", name);
+        assert_eq!(servers[0].last_seen_time, time(10), "{}", name);
+
+        let silent = store.query_silent_frps_servers(&time(15)).unwrap();
+        assert_eq!(silent.len(), 1, "{}", name);
+        assert_eq!(silent[0].frps_id, "frps_1", "{}", name);
+
+        store
+            .update_frps_server_status("frps_1", NODE_STATUS_OFFLINE)
+            .unwrap();
+        assert!(
+            store
+                .query_silent_frps_servers(&time(15))
+                .unwrap()
+                .is_empty(),
+            "{}",
+            name
+        );
+    }
+}
@@ -1,17 +1,31 @@
 use bytes::Buf;
+use chrono::NaiveDateTime;
 use http_body_util::BodyExt;
 use hyper::{body::Incoming as IncomingBody, header, Request, Response, StatusCode};
 use lazy_static::lazy_static;
-use regex::Regex;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 
+use crate::adaptive::effective_weight;
+use crate::cache::{cache, NodeChange};
 use crate::db::*;
+use crate::domains::{routable_domain_nodes, serves_models, DOMAIN_NAME_RE};
+use crate::frps::USER_CONN_WINDOW_SECS;
+use crate::models;
 use gaia_hub::*;
 
 lazy_static! {
-    static ref DOMAIN_NAME_RE: Regex = Regex::new(r"^[\w\-]+$").unwrap();
+    // How long a draining node goes without user connections before it is removed
+    static ref DRAIN_IDLE_SECS: i64 = std::env::var("DRAIN_IDLE_SECS")
+        .unwrap_or_else(|_| String::from("600"))
+        .parse()
+        .expect("DRAIN_IDLE_SECS must be a number");
 }
 
+pub static DRAIN_CHECK_INTERVAL: u64 = 60;
+
+/// The highest weight of a node, so the weights of a domain sum up exactly in the routing.
+pub static MAX_NODE_WEIGHT: i64 = 1_000_000;
+
 #[derive(Debug, serde::Deserialize)]
 struct NodeWeight {
     node_id: String,
@@ -28,14 +42,28 @@ struct DomainNodesWeights {
 struct DomainNodes {
     domain: String,
     nodes_ids: Vec<String>,
+    // When the drained nodes are removed at the latest
+    #[serde(default)]
+    deadline: Option<NaiveDateTime>,
 }
 
-#[derive(Debug, serde::Serialize)]
+#[derive(Debug, Clone, serde::Serialize)]
 #[serde(rename_all = "snake_case")]
 enum CreateResultCode {
     Created,
     NodeNotExist,
     NodeOffline,
+    DomainNotExist,
+    DomainSuspended,
+    ModelIncompatible,
+    InvalidDomain,
+    // Below 0 or above MAX_NODE_WEIGHT
+    InvalidWeight,
+    // The node is given more than once for the domain
+    Duplicate,
+    // Not written since another item of the atomic batch was refused or failed
+    Aborted,
+    Failed,
 }
 
 #[derive(Debug, serde::Serialize)]
@@ -45,7 +73,320 @@ struct CreateResult {
     code: CreateResultCode,
 }
 
+#[derive(Debug, Clone, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+enum RemoveResultCode {
+    Deleted,
+    Draining,
+    NotExist,
+    InvalidDomain,
+    Duplicate,
+    Aborted,
+    Failed,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct RemoveResult {
+    domain: String,
+    node_id: String,
+    code: RemoveResultCode,
+}
+
+// The result of an item of an atomic batch
+trait BatchResult {
+    // Whether the item was accepted, to be written along with the batch
+    fn done(&self) -> bool;
+    fn set_aborted(&mut self);
+    fn set_failed(&mut self);
+}
+
+impl BatchResult for CreateResult {
+    fn done(&self) -> bool {
+        matches!(self.code, CreateResultCode::Created)
+    }
+
+    fn set_aborted(&mut self) {
+        self.code = CreateResultCode::Aborted;
+    }
+
+    fn set_failed(&mut self) {
+        self.code = CreateResultCode::Failed;
+    }
+}
+
+impl BatchResult for RemoveResult {
+    fn done(&self) -> bool {
+        matches!(
+            self.code,
+            RemoveResultCode::Deleted | RemoveResultCode::Draining
+        )
+    }
+
+    fn set_aborted(&mut self) {
+        self.code = RemoveResultCode::Aborted;
+    }
+
+    fn set_failed(&mut self) {
+        self.code = RemoveResultCode::Failed;
+    }
+}
+
+#[derive(Debug, serde::Serialize)]
+struct DomainNodeWeights {
+    #[serde(flatten)]
+    domain_node: models::DomainNodes,
+    // The weight the node is routed with, none while it isn't routed
+    effective_weight: Option<i64>,
+}
+
+// The write of one item in the db and in the routing, with the one undoing it in the db
+struct Write {
+    domain: String,
+    node_id: String,
+    change: DomainNodeChange,
+    undo: DomainNodeChange,
+    // The weight the node is routed with once written, None to pull it, or no change at all
+    route: Option<Option<i64>>,
+}
+
+// A boolean query param, false by default
+fn bool_param(req: &Request<IncomingBody>, name: &str) -> Option<bool> {
+    let query = req.uri().query().unwrap_or("");
+    let params: HashMap<_, _> = form_urlencoded::parse(query.as_bytes())
+        .into_owned()
+        .collect();
+    match params.get(name).map(|v| v.as_str()) {
+        None | Some("false") => Some(false),
+        Some("true") => Some(true),
+        Some(_) => None,
+    }
+}
+
+fn invalid_bool_param(name: &str) -> Result<Response<BoxBody>> {
+    Ok(Response::builder()
+        .status(StatusCode::BAD_REQUEST)
+        .body(full(format!("{} must be true or false", name)))?)
+}
+
+// Patch the routing of one domain, undoing the patch if a node moved meanwhile
+fn patch_domain_routing(domain: &str, routes: &[(&str, Option<i64>)]) -> Result<Vec<NodeChange>> {
+    let cached: HashMap<String, i64> = cache().get_domain_nodes(domain)?.into_iter().collect();
+    let changes: Vec<NodeChange> = routes
+        .iter()
+        .filter(|(node_id, to)| cached.get(*node_id).copied() != *to)
+        .map(|(node_id, to)| NodeChange {
+            node_id: node_id.to_string(),
+            from: cached.get(*node_id).copied(),
+            to: *to,
+        })
+        .collect();
+    if changes.is_empty() {
+        return Ok(changes);
+    }
+
+    let applied = cache().patch_domain_nodes(domain, &changes)?;
+    if applied < changes.len() {
+        undo_domain_routing(domain, &changes);
+        return Err(format!("The routing of domain {} changed meanwhile", domain).into());
+    }
+    Ok(changes)
+}
+
+// Put back the nodes patched in the routing of the domain, unless they moved since
+fn undo_domain_routing(domain: &str, changes: &[NodeChange]) {
+    let undo: Vec<NodeChange> = changes
+        .iter()
+        .map(|change| NodeChange {
+            node_id: change.node_id.clone(),
+            from: change.to,
+            to: change.from,
+        })
+        .collect();
+    if let Err(e) = cache().patch_domain_nodes(domain, &undo) {
+        log::error!(
+            "Failed to roll back the routing of domain {}: {}",
+            domain,
+            e
+        );
+    }
+}
+
+// Route the written nodes with one patch per domain, all of them or none.
+// The error comes with the domain whose routing failed.
+fn patch_routing(writes: &[Write]) -> std::result::Result<(), (&str, GenericError)> {
+    let mut routes: BTreeMap<&str, Vec<(&str, Option<i64>)>> = BTreeMap::new();
+    for write in writes {
+        if let Some(to) = write.route {
+            routes
+                .entry(write.domain.as_str())
+                .or_default()
+                .push((write.node_id.as_str(), to));
+        }
+    }
+
+    let mut patched: Vec<(&str, Vec<NodeChange>)> = vec![];
+    for (domain, routes) in routes {
+        match patch_domain_routing(domain, &routes) {
+            Ok(changes) => patched.push((domain, changes)),
+            Err(e) => {
+                for (domain, changes) in patched {
+                    undo_domain_routing(domain, &changes);
+                }
+                return Err((domain, e));
+            }
+        }
+    }
+    Ok(())
+}
+
+// Whether the change still finds the membership as it was checked
+fn still_applies(change: &DomainNodeChange) -> Result<bool> {
+    Ok(match change {
+        DomainNodeChange::Insert(dn) => store()
+            .query_domain_node(&dn.domain, &dn.node_id)?
+            .is_none(),
+        DomainNodeChange::Update(dn) => store()
+            .query_domain_node(&dn.domain, &dn.node_id)?
+            .is_some(),
+        DomainNodeChange::Delete { domain, node_id } => {
+            store().query_domain_node(domain, node_id)?.is_some()
+        }
+    })
+}
+
+// Write the items in the db in one transaction, then route them, rolling back the db in one
+// transaction if the routing fails. The error comes with the indexes of the items which
+// failed, all of them when it can't tell.
+fn apply_writes(writes: &[Write]) -> std::result::Result<(), (Vec<usize>, GenericError)> {
+    let every_item = || (0..writes.len()).collect::<Vec<usize>>();
+    let changes: Vec<DomainNodeChange> = writes.iter().map(|w| w.change.clone()).collect();
+    if let Err(e) = store().apply_domain_node_changes(&changes) {
+        let gone: Vec<usize> = writes
+            .iter()
+            .enumerate()
+            .filter(|(_, w)| !still_applies(&w.change).unwrap_or(true))
+            .map(|(i, _)| i)
+            .collect();
+        return Err((if gone.is_empty() { every_item() } else { gone }, e));
+    }
+
+    if let Err((domain, e)) = patch_routing(writes) {
+        let undo: Vec<DomainNodeChange> = writes.iter().rev().map(|w| w.undo.clone()).collect();
+        if let Err(e) = store().apply_domain_node_changes(&undo) {
+            log::error!("Failed to roll back domain nodes in db: {}", e);
+        }
+        let unrouted: Vec<usize> = writes
+            .iter()
+            .enumerate()
+            .filter(|(_, w)| w.domain == domain && w.route.is_some())
+            .map(|(i, _)| i)
+            .collect();
+        return Err((unrouted, e));
+    }
+    Ok(())
+}
+
+// Write the batch, each write given with the index of its item, if every item was accepted.
+// Otherwise nothing is left written: the failed items get `failed`, the accepted ones `aborted`.
+fn apply_batch<R: BatchResult>(results: &mut [R], writes: Vec<(usize, Write)>) {
+    let failed: Vec<usize> = match results.iter().all(R::done) {
+        false => vec![],
+        true => {
+            let (items, writes): (Vec<usize>, Vec<Write>) = writes.into_iter().unzip();
+            match apply_writes(&writes) {
+                Ok(()) => return,
+                Err((failed, e)) => {
+                    log::error!("Failed to apply the batch of domain nodes: {}", e);
+                    failed.into_iter().map(|i| items[i]).collect()
+                }
+            }
+        }
+    };
+    for (i, r) in results.iter_mut().enumerate() {
+        if failed.contains(&i) {
+            r.set_failed();
+        } else if r.done() {
+            r.set_aborted();
+        }
+    }
+}
+
+// The write attaching the node to the active domain, or why it is refused.
+// Nothing is written when the node already has this weight, a draining node is kept.
+fn plan_create(
+    record: &models::Domain,
+    node_weight: &NodeWeight,
+) -> Result<(CreateResultCode, Option<Write>)> {
+    let domain = record.name.as_str();
+    let node_id = node_weight.node_id.as_str();
+    let weight = node_weight.weight;
+
+    let node = store().query_node_by_node_id(node_id)?;
+    if node
+        .as_ref()
+        .is_some_and(|node| !serves_models(record, &node.chat_model, &node.embedding_model))
+    {
+        return Ok((CreateResultCode::ModelIncompatible, None));
+    }
+    let online = node
+        .as_ref()
+        .is_some_and(|node| node.status == NODE_STATUS_ONLINE);
+    let routed = node
+        .as_ref()
+        .map(|node| effective_weight(weight, node))
+        .unwrap_or(weight);
+
+    let attached = models::DomainNodes {
+        domain: domain.to_string(),
+        node_id: node_id.to_string(),
+        weight,
+        draining_since: None,
+        drain_deadline: None,
+        last_user_conn_time: None,
+    };
+
+    if let Some(domain_node) = store().query_domain_node(domain, node_id)? {
+        if domain_node.weight == weight && domain_node.draining_since.is_none() {
+            return Ok((CreateResultCode::Created, None));
+        }
+        let write = Write {
+            domain: domain.to_string(),
+            node_id: node_id.to_string(),
+            change: DomainNodeChange::Update(attached),
+            undo: DomainNodeChange::Update(domain_node),
+            // An offline node is routed with its new weight once it is back
+            route: online.then_some(Some(routed)),
+        };
+        return Ok((CreateResultCode::Created, Some(write)));
+    }
+
+    // Only online nodes can be added to domain
+    if node.is_none() {
+        return Ok((CreateResultCode::NodeNotExist, None));
+    }
+    if !online {
+        return Ok((CreateResultCode::NodeOffline, None));
+    }
+
+    let write = Write {
+        domain: domain.to_string(),
+        node_id: node_id.to_string(),
+        change: DomainNodeChange::Insert(attached),
+        undo: DomainNodeChange::Delete {
+            domain: domain.to_string(),
+            node_id: node_id.to_string(),
+        },
+        route: Some(Some(routed)),
+    };
+    Ok((CreateResultCode::Created, Some(write)))
+}
+
+/// Attach the nodes to their domains with their weights, or change the weights.
+/// With `atomic=true`, either every item is written, or none is.
 pub async fn create_domain_node(req: Request<IncomingBody>) -> Result<Response<BoxBody>> {
+    let Some(atomic) = bool_param(&req, "atomic") else {
+        return invalid_bool_param("atomic");
+    };
     let whole_body = req.collect().await?.aggregate();
     let domain_nodes: Vec<DomainNodesWeights> = match serde_json::from_reader(whole_body.reader()) {
         Ok(data) => data,
@@ -56,63 +397,84 @@ pub async fn create_domain_node(req: Request<IncomingBody>) -> Result<Response<B
         }
     };
 
-    let mut results = vec![];
+    let results = blocking(move || {
+        let mut results = vec![];
+        let mut writes = vec![];
+        let mut seen = HashSet::new();
 
-    for domain_node in domain_nodes {
-        let domain = domain_node.domain;
-        if !DOMAIN_NAME_RE.is_match(&domain) {
-            continue;
-        }
-        // domain must be lowercase
-        let domain = domain.to_lowercase();
-
-        let nodes_weights = domain_node.nodes_weights;
+        for domain_node in domain_nodes {
+            // domain must be lowercase
+            let domain = domain_node.domain.to_lowercase();
 
-        for node_weight in nodes_weights {
-            let r = CreateResult {
-                domain: domain.clone(),
-                node_id: node_weight.node_id.clone(),
-                code: CreateResultCode::Created,
-            };
-            results.push(r);
-            let l = results.len();
-            let r = results.get_mut(l - 1).unwrap();
-
-            let domain_node = query_domain_node(&domain, &node_weight.node_id)?;
-
-            if domain_node.is_some() {
-                if domain_node.unwrap().weight != node_weight.weight {
-                    let updated =
-                        update_domain_node(&domain, &node_weight.node_id, node_weight.weight)?;
-                    if updated > 0 {
-                        crate::redism::nodes_upjoin(
-                            &domain,
-                            &node_weight.node_id,
-                            node_weight.weight,
-                        )?;
+            // Nodes only go to recorded domains which are not suspended
+            let record = match DOMAIN_NAME_RE.is_match(&domain) {
+                false => Err(CreateResultCode::InvalidDomain),
+                true => match store().query_domain(&domain) {
+                    Ok(None) => Err(CreateResultCode::DomainNotExist),
+                    Ok(Some(d)) if d.status == DOMAIN_STATUS_SUSPENDED => {
+                        Err(CreateResultCode::DomainSuspended)
                     }
-                }
-                continue;
-            }
-
-            let node = query_node_by_node_id(&node_weight.node_id)?;
+                    Ok(Some(d)) => Ok(d),
+                    Err(e) => {
+                        log::error!("Failed to query domain {}: {}", domain, e);
+                        Err(CreateResultCode::Failed)
+                    }
+                },
+            };
 
-            // Only online nodes can be added to domain
-            if node.is_none() {
-                r.code = CreateResultCode::NodeNotExist;
-                continue;
-            }
-            if node.unwrap().status != NODE_STATUS_ONLINE {
-                r.code = CreateResultCode::NodeOffline;
-                continue;
+            for node_weight in domain_node.nodes_weights {
+                let code = match &record {
+                    Err(code) => code.clone(),
+                    Ok(_) if !(0..=MAX_NODE_WEIGHT).contains(&node_weight.weight) => {
+                        CreateResultCode::InvalidWeight
+                    }
+                    Ok(_) if !seen.insert((domain.clone(), node_weight.node_id.clone())) => {
+                        CreateResultCode::Duplicate
+                    }
+                    Ok(record) => match plan_create(record, &node_weight) {
+                        Ok((code, None)) => code,
+                        Ok((code, Some(write))) if atomic => {
+                            writes.push((results.len(), write));
+                            code
+                        }
+                        Ok((code, Some(write))) => match apply_writes(&[write]) {
+                            Ok(()) => code,
+                            Err((_, e)) => {
+                                log::error!(
+                                    "Failed to add node {} to domain {}: {}",
+                                    node_weight.node_id,
+                                    domain,
+                                    e
+                                );
+                                CreateResultCode::Failed
+                            }
+                        },
+                        Err(e) => {
+                            log::error!(
+                                "Failed to check node {} for domain {}: {}",
+                                node_weight.node_id,
+                                domain,
+                                e
+                            );
+                            CreateResultCode::Failed
+                        }
+                    },
+                };
+                results.push(CreateResult {
+                    domain: domain.clone(),
+                    node_id: node_weight.node_id,
+                    code,
+                });
             }
+        }
 
-            let inserted = insert_domain_node(&domain, &node_weight.node_id, node_weight.weight)?;
-            if inserted > 0 {
-                crate::redism::nodes_join(&domain, &node_weight.node_id, node_weight.weight)?;
-            }
+        if !atomic {
+            return Ok(results);
         }
-    }
+        apply_batch(&mut results, writes);
+        Ok(results)
+    })
+    .await?;
 
     Ok(Response::builder()
         .status(StatusCode::OK)
@@ -135,7 +497,18 @@ pub async fn get_domain_nodes(req: Request<IncomingBody>) -> Result<Response<Box
         }
     };
 
-    let nodes = query_domain_nodes(&domain)?;
+    let nodes = blocking(move || {
+        let routed: HashMap<String, i64> = routable_domain_nodes(&domain)?.into_iter().collect();
+        Ok(store()
+            .query_domain_nodes(&domain)?
+            .into_iter()
+            .map(|domain_node| DomainNodeWeights {
+                effective_weight: routed.get(&domain_node.node_id).copied(),
+                domain_node,
+            })
+            .collect::<Vec<_>>())
+    })
+    .await?;
 
     let data = serde_json::json!({"code": 0, "msg": "OK", "data": nodes });
 
@@ -147,7 +520,82 @@ pub async fn get_domain_nodes(req: Request<IncomingBody>) -> Result<Response<Box
     Ok(response)
 }
 
+// Whether the node is routed in the domain, being online, active and serving its models
+fn is_routable(domain: &str, node_id: &str) -> Result<bool> {
+    let Some(node) = store().query_node_by_node_id(node_id)? else {
+        return Ok(false);
+    };
+    if node.status != NODE_STATUS_ONLINE {
+        return Ok(false);
+    }
+    Ok(match store().query_domain(domain)? {
+        Some(record) => {
+            record.status != DOMAIN_STATUS_SUSPENDED
+                && serves_models(&record, &node.chat_model, &node.embedding_model)
+        }
+        None => true,
+    })
+}
+
+// The write detaching the node from the domain, none if it isn't attached
+fn plan_remove(domain: &str, node_id: &str) -> Result<Option<(RemoveResultCode, Option<Write>)>> {
+    let Some(domain_node) = store().query_domain_node(domain, node_id)? else {
+        return Ok(None);
+    };
+    let write = Write {
+        domain: domain.to_string(),
+        node_id: node_id.to_string(),
+        change: DomainNodeChange::Delete {
+            domain: domain.to_string(),
+            node_id: node_id.to_string(),
+        },
+        undo: DomainNodeChange::Insert(domain_node),
+        route: Some(None),
+    };
+    Ok(Some((RemoveResultCode::Deleted, Some(write))))
+}
+
+// The write draining the node out of the domain, none if it isn't attached.
+// Draining it again only moves the deadline.
+fn plan_drain(
+    domain: &str,
+    node_id: &str,
+    deadline: Option<NaiveDateTime>,
+) -> Result<Option<(RemoveResultCode, Option<Write>)>> {
+    let Some(domain_node) = store().query_domain_node(domain, node_id)? else {
+        return Ok(None);
+    };
+    if domain_node.draining_since.is_some() && domain_node.drain_deadline == deadline {
+        return Ok(Some((RemoveResultCode::Draining, None)));
+    }
+
+    let now = chrono::Utc::now().naive_utc();
+    let draining = models::DomainNodes {
+        draining_since: domain_node.draining_since.or(Some(now)),
+        drain_deadline: deadline,
+        ..domain_node.clone()
+    };
+    let write = Write {
+        domain: domain.to_string(),
+        node_id: node_id.to_string(),
+        change: DomainNodeChange::Update(draining),
+        undo: DomainNodeChange::Update(domain_node),
+        // Kept in the routing without new traffic
+        route: is_routable(domain, node_id)?.then_some(Some(0)),
+    };
+    Ok(Some((RemoveResultCode::Draining, Some(write))))
+}
+
+/// Detach the nodes from their domains.
+/// With `drain=true`, the nodes stay at a weight of 0 until they go idle or their deadline.
+/// With `atomic=true`, either every item is written, or none is.
 pub async fn remove_domain_node(req: Request<IncomingBody>) -> Result<Response<BoxBody>> {
+    let Some(atomic) = bool_param(&req, "atomic") else {
+        return invalid_bool_param("atomic");
+    };
+    let Some(drain) = bool_param(&req, "drain") else {
+        return invalid_bool_param("drain");
+    };
     let whole_body = req.collect().await?.aggregate();
     let domain_nodes: Vec<DomainNodes> = match serde_json::from_reader(whole_body.reader()) {
         Ok(data) => data,
@@ -158,24 +606,395 @@ pub async fn remove_domain_node(req: Request<IncomingBody>) -> Result<Response<B
         }
     };
 
-    for domain_node in domain_nodes {
-        let domain = domain_node.domain;
-        if !DOMAIN_NAME_RE.is_match(&domain) {
-            continue;
-        }
-        // domain must be lowercase
-        let domain = domain.to_lowercase();
+    let results = blocking(move || {
+        let mut results = vec![];
+        let mut writes = vec![];
+        let mut seen = HashSet::new();
 
-        let nodes_ids = domain_node.nodes_ids;
+        for domain_node in domain_nodes {
+            // domain must be lowercase
+            let domain = domain_node.domain.to_lowercase();
+            let valid = DOMAIN_NAME_RE.is_match(&domain);
 
-        for node_id in nodes_ids {
-            if let Some(deleted) = delete_domain_node(&domain, &node_id)? {
-                crate::redism::node_lefts(&domain, &node_id, deleted.weight)?;
+            for node_id in domain_node.nodes_ids {
+                let code = if !valid {
+                    RemoveResultCode::InvalidDomain
+                } else if !seen.insert((domain.clone(), node_id.clone())) {
+                    RemoveResultCode::Duplicate
+                } else {
+                    let planned = match drain {
+                        true => plan_drain(&domain, &node_id, domain_node.deadline),
+                        false => plan_remove(&domain, &node_id),
+                    };
+                    match planned {
+                        Ok(None) => RemoveResultCode::NotExist,
+                        Ok(Some((code, None))) => code,
+                        Ok(Some((code, Some(write)))) if atomic => {
+                            writes.push((results.len(), write));
+                            code
+                        }
+                        Ok(Some((code, Some(write)))) => match apply_writes(&[write]) {
+                            Ok(()) => code,
+                            Err((_, e)) => {
+                                log::error!(
+                                    "Failed to remove node {} from domain {}: {}",
+                                    node_id,
+                                    domain,
+                                    e
+                                );
+                                RemoveResultCode::Failed
+                            }
+                        },
+                        Err(e) => {
+                            log::error!(
+                                "Failed to query node {} of domain {}: {}",
+                                node_id,
+                                domain,
+                                e
+                            );
+                            RemoveResultCode::Failed
+                        }
+                    }
+                };
+                results.push(RemoveResult {
+                    domain: domain.clone(),
+                    node_id,
+                    code,
+                });
             }
         }
-    }
+
+        if !atomic {
+            return Ok(results);
+        }
+        apply_batch(&mut results, writes);
+        Ok(results)
+    })
+    .await?;
 
     Ok(Response::builder()
         .status(StatusCode::OK)
-        .body(full(String::from("Domain node deleted")))?)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(full(serde_json::to_string(&results)?))?)
+}
+
+// Route the node in the domain if it is online and serves the models of the active domain,
+// or else pull it out
+fn refresh_domain_routing(node: &models::Node, domain_node: &models::DomainNodes) -> Result<()> {
+    let domain = domain_node.domain.as_str();
+    let record = store().query_domain(domain)?;
+
+    if let Some(record) = &record {
+        if !serves_models(record, &node.chat_model, &node.embedding_model) {
+            log::warn!(
+                "Pulled node {} from domain {}, its models {} and {} don't meet the domain's",
+                node.node_id,
+                domain,
+                node.chat_model,
+                node.embedding_model
+            );
+            return cache().node_lefts(domain, &node.node_id);
+        }
+    }
+    let suspended = record.is_some_and(|record| record.status == DOMAIN_STATUS_SUSPENDED);
+    match node.status == NODE_STATUS_ONLINE && !suspended {
+        true => {
+            let weight = effective_weight(domain_node.routed_weight(), node);
+            cache().nodes_join(domain, &node.node_id, weight)
+        }
+        false => cache().node_lefts(domain, &node.node_id),
+    }
+}
+
+/// Refresh the routing of the node in every domain it belongs to, logging the failures.
+pub fn refresh_node_routing(node: &models::Node) -> Result<()> {
+    for domain_node in store().query_domain_nodes_by_node_id(&node.node_id)? {
+        if let Err(e) = refresh_domain_routing(node, &domain_node) {
+            log::error!(
+                "Failed to refresh node {} in domain {}: {}",
+                node.node_id,
+                domain_node.domain,
+                e
+            );
+        }
+    }
+    Ok(())
+}
+
+// Remove the drained membership once it went idle or passed its deadline, true if removed
+fn expire_drained_node(domain_node: &models::DomainNodes, now: &NaiveDateTime) -> Result<bool> {
+    let mut idle_since = domain_node
+        .last_user_conn_time
+        .or(domain_node.draining_since)
+        .unwrap_or(*now);
+    if let Some(node) = store().query_node_by_node_id(&domain_node.node_id)? {
+        if cache().recent_user_conns(&node.subdomain, USER_CONN_WINDOW_SECS)? > 0 {
+            store().update_draining_user_conn_time(
+                &domain_node.domain,
+                &domain_node.node_id,
+                now,
+            )?;
+            idle_since = *now;
+        }
+    }
+
+    let idle = (*now - idle_since).num_seconds() >= *DRAIN_IDLE_SECS;
+    let overdue = domain_node
+        .drain_deadline
+        .is_some_and(|deadline| deadline <= *now);
+    if !idle && !overdue {
+        return Ok(false);
+    }
+    // It may have been attached again meanwhile
+    if store().delete_drained_domain_node(&domain_node.domain, &domain_node.node_id)? == 0 {
+        return Ok(false);
+    }
+    cache().node_lefts(&domain_node.domain, &domain_node.node_id)?;
+    Ok(true)
+}
+
+pub async fn drain_domain_nodes(now: NaiveDateTime) {
+    let draining = match blocking(|| store().query_draining_domain_nodes()).await {
+        Ok(draining) => draining,
+        Err(e) => {
+            log::error!("Failed to query draining domain nodes: {}", e);
+            return;
+        }
+    };
+    for domain_node in draining {
+        let (domain, node_id) = (domain_node.domain.clone(), domain_node.node_id.clone());
+        match blocking(move || expire_drained_node(&domain_node, &now)).await {
+            Ok(true) => log::info!("Removed drained node {} from domain {}", node_id, domain),
+            Ok(false) => {}
+            Err(e) => log::error!(
+                "Failed to expire drained node {} of domain {}: {}",
+                node_id,
+                domain,
+                e
+            ),
+        }
+    }
+}
+
+/// Pull the node from the routing of every domain it belongs to, logging the failures.
+pub fn pull_node_routing(node_id: &str) -> Result<()> {
+    for domain_node in store().query_domain_nodes_by_node_id(node_id)? {
+        let domain = domain_node.domain.as_str();
+        if let Err(e) = cache().node_lefts(domain, node_id) {
+            log::error!(
+                "Failed to leave domain nodes in redis: {}. Error msg: {}",
+                domain,
+                e
+            );
+        }
+    }
+    Ok(())
+}
+
+// Force the nodes offline and pull them from the routing of their domains
+pub fn offline_nodes(nodes: Vec<models::Node>) -> Result<usize> {
+    let mut closed = 0;
+    for node in nodes {
+        if node.status == NODE_STATUS_OFFLINE {
+            continue;
+        }
+        store().update_node_status(&node.node_id, NODE_STATUS_OFFLINE)?;
+        closed += 1;
+        pull_node_routing(&node.node_id)?;
+    }
+    Ok(closed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::{add_node, domain_node};
+
+    fn created(domain: &str, node_id: &str) -> CreateResult {
+        CreateResult {
+            domain: domain.to_string(),
+            node_id: node_id.to_string(),
+            code: CreateResultCode::Created,
+        }
+    }
+
+    fn insert(domain: &str, node_id: &str, weight: i64) -> Write {
+        Write {
+            domain: domain.to_string(),
+            node_id: node_id.to_string(),
+            change: DomainNodeChange::Insert(domain_node(domain, node_id, weight)),
+            undo: DomainNodeChange::Delete {
+                domain: domain.to_string(),
+                node_id: node_id.to_string(),
+            },
+            route: Some(Some(weight)),
+        }
+    }
+
+    fn codes(results: &[CreateResult]) -> Vec<String> {
+        results
+            .iter()
+            .map(|r| {
+                serde_json::to_value(&r.code)
+                    .unwrap()
+                    .as_str()
+                    .unwrap()
+                    .to_string()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn atomic_batches_write_every_item() {
+        let domain = "atomic-written";
+        add_node(store(), "atomic-written-n1", NODE_STATUS_ONLINE);
+        add_node(store(), "atomic-written-n2", NODE_STATUS_ONLINE);
+        let mut results = vec![
+            created(domain, "atomic-written-n1"),
+            created(domain, "atomic-written-n2"),
+        ];
+        let writes = vec![
+            (0, insert(domain, "atomic-written-n1", 3)),
+            (1, insert(domain, "atomic-written-n2", 2)),
+        ];
+
+        apply_batch(&mut results, writes);
+        assert_eq!(codes(&results), ["created", "created"]);
+        assert_eq!(store().query_domain_nodes(domain).unwrap().len(), 2);
+        assert_eq!(cache().get_domain_nodes(domain).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn atomic_batches_roll_back_once_an_item_fails() {
+        let domain = "atomic-failed";
+        for node_id in ["atomic-failed-n1", "atomic-failed-n2", "atomic-failed-n3"] {
+            add_node(store(), node_id, NODE_STATUS_ONLINE);
+        }
+        // The membership of n2 went away since the batch was checked
+        let gone = Write {
+            domain: domain.to_string(),
+            node_id: String::from("atomic-failed-n2"),
+            change: DomainNodeChange::Update(domain_node(domain, "atomic-failed-n2", 5)),
+            undo: DomainNodeChange::Update(domain_node(domain, "atomic-failed-n2", 1)),
+            route: Some(Some(5)),
+        };
+        let mut results = vec![
+            created(domain, "atomic-failed-n1"),
+            created(domain, "atomic-failed-n2"),
+            created(domain, "atomic-failed-n3"),
+        ];
+        let writes = vec![
+            (0, insert(domain, "atomic-failed-n1", 3)),
+            (1, gone),
+            (2, insert(domain, "atomic-failed-n3", 2)),
+        ];
+
+        apply_batch(&mut results, writes);
+        assert_eq!(codes(&results), ["aborted", "failed", "aborted"]);
+        assert!(store().query_domain_nodes(domain).unwrap().is_empty());
+        assert!(cache().get_domain_nodes(domain).unwrap().is_empty());
+    }
+
+    #[test]
+    fn refused_items_abort_atomic_batches() {
+        let domain = "atomic-refused";
+        add_node(store(), "atomic-refused-n1", NODE_STATUS_ONLINE);
+        let mut results = vec![
+            created(domain, "atomic-refused-n1"),
+            CreateResult {
+                code: CreateResultCode::NodeOffline,
+                ..created(domain, "atomic-refused-n2")
+            },
+        ];
+        let writes = vec![(0, insert(domain, "atomic-refused-n1", 3))];
+
+        apply_batch(&mut results, writes);
+        assert_eq!(codes(&results), ["aborted", "node_offline"]);
+        assert!(store().query_domain_nodes(domain).unwrap().is_empty());
+        assert!(cache().get_domain_nodes(domain).unwrap().is_empty());
+    }
+
+    // Record the membership as draining since `since`, routed at 0
+    fn drain(domain: &str, node_id: &str, since: NaiveDateTime) -> models::DomainNodes {
+        add_node(store(), node_id, NODE_STATUS_ONLINE);
+        let draining = models::DomainNodes {
+            draining_since: Some(since),
+            ..domain_node(domain, node_id, 3)
+        };
+        store()
+            .apply_domain_node_changes(&[DomainNodeChange::Insert(draining.clone())])
+            .unwrap();
+        cache().nodes_join(domain, node_id, 0).unwrap();
+        draining
+    }
+
+    fn is_member(domain: &str, node_id: &str) -> bool {
+        store()
+            .query_domain_node(domain, node_id)
+            .unwrap()
+            .is_some()
+    }
+
+    #[test]
+    fn drained_nodes_leave_at_their_deadline() {
+        let (domain, now) = ("drain-deadline", chrono::Utc::now().naive_utc());
+        let since = now - chrono::Duration::seconds(10);
+        let pending = models::DomainNodes {
+            drain_deadline: Some(now + chrono::Duration::seconds(1)),
+            ..drain(domain, "drain-deadline-n1", since)
+        };
+        let overdue = models::DomainNodes {
+            drain_deadline: Some(now),
+            ..drain(domain, "drain-deadline-n2", since)
+        };
+
+        assert!(!expire_drained_node(&pending, &now).unwrap());
+        assert!(is_member(domain, "drain-deadline-n1"));
+        assert!(expire_drained_node(&overdue, &now).unwrap());
+        assert!(!is_member(domain, "drain-deadline-n2"));
+        assert_eq!(
+            cache().get_domain_nodes(domain).unwrap(),
+            [(String::from("drain-deadline-n1"), 0)]
+        );
+    }
+
+    #[test]
+    fn drained_nodes_leave_once_idle_since_their_last_user_conn() {
+        let (domain, now) = ("drain-idle", chrono::Utc::now().naive_utc());
+        let idle = chrono::Duration::seconds(*DRAIN_IDLE_SECS);
+        let since = now - idle * 2;
+        let busy = models::DomainNodes {
+            last_user_conn_time: Some(now - idle + chrono::Duration::seconds(1)),
+            ..drain(domain, "drain-idle-n1", since)
+        };
+        let quiet = models::DomainNodes {
+            last_user_conn_time: Some(now - idle),
+            ..drain(domain, "drain-idle-n2", since)
+        };
+        let fresh = drain(domain, "drain-idle-n3", now - chrono::Duration::seconds(1));
+
+        assert!(!expire_drained_node(&busy, &now).unwrap());
+        assert!(is_member(domain, "drain-idle-n1"));
+        assert!(expire_drained_node(&quiet, &now).unwrap());
+        assert!(!is_member(domain, "drain-idle-n2"));
+        assert!(!expire_drained_node(&fresh, &now).unwrap());
+        assert!(is_member(domain, "drain-idle-n3"));
+    }
+
+    #[test]
+    fn user_conns_keep_drained_nodes_busy() {
+        let (domain, now) = ("drain-busy", chrono::Utc::now().naive_utc());
+        let since = now - chrono::Duration::seconds(*DRAIN_IDLE_SECS * 2);
+        let draining = drain(domain, "drain-busy-n1", since);
+        cache()
+            .incr_user_conns("drain-busy-n1.gaia", USER_CONN_WINDOW_SECS)
+            .unwrap();
+
+        assert!(!expire_drained_node(&draining, &now).unwrap());
+        let domain_node = store()
+            .query_domain_node(domain, "drain-busy-n1")
+            .unwrap()
+            .unwrap();
+        assert_eq!(domain_node.last_user_conn_time, Some(now));
+    }
 }
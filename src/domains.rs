@@ -0,0 +1,383 @@
+use bytes::Buf;
+use http_body_util::BodyExt;
+use hyper::{body::Incoming as IncomingBody, header, Request, Response, StatusCode};
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::collections::HashMap;
+
+use crate::adaptive::{adaptive_weights, effective_weight};
+use crate::cache::cache;
+use crate::db::*;
+use crate::models;
+use crate::probes::ProbeConfig;
+use crate::reconcile::reconcile_domain;
+use gaia_hub::*;
+
+lazy_static! {
+    pub(crate) static ref DOMAIN_NAME_RE: Regex = Regex::new(r"^[\w\-]+$").unwrap();
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct DomainItem {
+    name: String,
+    owner: Option<String>,
+    description: Option<String>,
+    chat_model: Option<String>,
+    embedding_model: Option<String>,
+    status: Option<String>,
+    // null for the default probe
+    #[serde(default, deserialize_with = "given")]
+    health_probe: Option<serde_json::Value>,
+    // null for NODE_USER_CONN_LIMIT
+    #[serde(default, deserialize_with = "given")]
+    user_conn_limit: Option<Option<i64>>,
+}
+
+// A given field, even null, as opposed to a missing one
+fn given<'de, D: serde::Deserializer<'de>, T: serde::Deserialize<'de>>(
+    deserializer: D,
+) -> std::result::Result<Option<T>, D::Error> {
+    serde::Deserialize::deserialize(deserializer).map(Some)
+}
+
+#[derive(Debug, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+enum DomainResultCode {
+    Created,
+    Updated,
+    Deleted,
+    InvalidDomain,
+    InvalidStatus,
+    InvalidProbe,
+    InvalidUserConnLimit,
+    DomainNotExist,
+    DomainHasNodes,
+    Failed,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct DomainResult {
+    name: String,
+    code: DomainResultCode,
+}
+
+// Whether the model meets a requirement of a domain, which is empty for any model,
+// a pattern if it has `*` or `?`, or else the exact name. Both ignore the case.
+fn model_matches(required: &str, model: &str) -> bool {
+    if required.is_empty() {
+        return true;
+    }
+    let required: Vec<char> = required.to_lowercase().chars().collect();
+    let model: Vec<char> = model.to_lowercase().chars().collect();
+
+    // Match the pattern, going back to the last `*` on a mismatch
+    let (mut p, mut m) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while m < model.len() {
+        match required.get(p) {
+            Some('*') => {
+                star = Some((p, m));
+                p += 1;
+            }
+            Some(c) if *c == '?' || *c == model[m] => {
+                p += 1;
+                m += 1;
+            }
+            _ => match star {
+                Some((star_p, star_m)) => {
+                    p = star_p + 1;
+                    m = star_m + 1;
+                    star = Some((star_p, star_m + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    required[p..].iter().all(|c| *c == '*')
+}
+
+/// Whether a node serving these models meets the requirements of the domain.
+pub fn serves_models(domain: &models::Domain, chat_model: &str, embedding_model: &str) -> bool {
+    model_matches(&domain.chat_model, chat_model)
+        && model_matches(&domain.embedding_model, embedding_model)
+}
+
+/// The online nodes of the domain with their effective weights, without the nodes which
+/// don't serve the models of the domain, and none while the domain is suspended.
+/// Domains without a record are the ones attached before domains were recorded.
+pub fn routable_domain_nodes(domain: &str) -> Result<Vec<(String, i64)>> {
+    let nodes = store().get_nodes_by_domain(domain)?;
+    let record = match store().query_domain(domain)? {
+        Some(record) if record.status == DOMAIN_STATUS_SUSPENDED => return Ok(vec![]),
+        Some(record) if !record.chat_model.is_empty() || !record.embedding_model.is_empty() => {
+            Some(record)
+        }
+        _ if !adaptive_weights() => return Ok(nodes),
+        _ => None,
+    };
+
+    let node_ids: Vec<String> = nodes.iter().map(|(node_id, _)| node_id.clone()).collect();
+    let serving: HashMap<String, models::Node> = store()
+        .query_nodes_by_node_ids(&node_ids)?
+        .into_iter()
+        .filter(|node| {
+            record.as_ref().map_or(true, |record| {
+                serves_models(record, &node.chat_model, &node.embedding_model)
+            })
+        })
+        .map(|node| (node.node_id.clone(), node))
+        .collect();
+    Ok(nodes
+        .into_iter()
+        .filter_map(|(node_id, weight)| {
+            let weight = effective_weight(weight, serving.get(&node_id)?);
+            Some((node_id, weight))
+        })
+        .collect())
+}
+
+// Record the domain or change the given fields, then follow a status change in the routing
+fn save_domain(name: &str, item: &DomainItem) -> Result<DomainResultCode> {
+    let existing = store().query_domain(name)?;
+    let keep = |given: &Option<String>, current: fn(&models::Domain) -> &String| -> String {
+        given
+            .clone()
+            .or_else(|| existing.as_ref().map(|d| current(d).clone()))
+            .unwrap_or_default()
+    };
+
+    let status = match keep(&item.status, |d| &d.status).as_str() {
+        "" => DOMAIN_STATUS_ACTIVE,
+        s if s == DOMAIN_STATUS_ACTIVE => DOMAIN_STATUS_ACTIVE,
+        s if s == DOMAIN_STATUS_SUSPENDED => DOMAIN_STATUS_SUSPENDED,
+        _ => return Ok(DomainResultCode::InvalidStatus),
+    };
+    let owner = keep(&item.owner, |d| &d.owner);
+    let description = keep(&item.description, |d| &d.description);
+    let chat_model = keep(&item.chat_model, |d| &d.chat_model);
+    let embedding_model = keep(&item.embedding_model, |d| &d.embedding_model);
+    // Store the probe with its defaults filled in
+    let health_probe = match &item.health_probe {
+        None => existing.as_ref().and_then(|d| d.health_probe.clone()),
+        Some(serde_json::Value::Null) => None,
+        Some(value) => match ProbeConfig::parse(value) {
+            Some(probe) => Some(serde_json::to_value(probe)?),
+            None => return Ok(DomainResultCode::InvalidProbe),
+        },
+    };
+    let user_conn_limit = match item.user_conn_limit {
+        None => existing.as_ref().and_then(|d| d.user_conn_limit),
+        Some(Some(limit)) if limit < 0 => return Ok(DomainResultCode::InvalidUserConnLimit),
+        Some(limit) => limit,
+    };
+    let domain = models::NewDomain {
+        name,
+        owner: &owner,
+        description: &description,
+        chat_model: &chat_model,
+        embedding_model: &embedding_model,
+        status,
+        health_probe: health_probe.as_ref(),
+        user_conn_limit,
+    };
+
+    let Some(existing) = existing else {
+        store().insert_domain(&domain)?;
+        return Ok(DomainResultCode::Created);
+    };
+    store().update_domain(&domain)?;
+    // The domain is updated even if its routing can't follow yet
+    if existing.status != status {
+        if status == DOMAIN_STATUS_SUSPENDED {
+            match cache().drop_domain(name) {
+                Ok(()) => log::info!("Pulled the routing of the suspended domain {}", name),
+                Err(e) => log::error!(
+                    "Failed to pull the routing of the suspended domain {}: {}",
+                    name,
+                    e
+                ),
+            }
+        } else {
+            match reconcile_domain(name, false) {
+                Ok(_) => log::info!("Restored the routing of the reactivated domain {}", name),
+                Err(e) => log::error!(
+                    "Failed to restore the routing of the reactivated domain {}: {}",
+                    name,
+                    e
+                ),
+            }
+        }
+    }
+    Ok(DomainResultCode::Updated)
+}
+
+pub async fn create_domains(req: Request<IncomingBody>) -> Result<Response<BoxBody>> {
+    let whole_body = req.collect().await?.aggregate();
+    let items: Vec<DomainItem> = match serde_json::from_reader(whole_body.reader()) {
+        Ok(data) => data,
+        Err(e) => {
+            return Ok(Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(full(format!("Invalid JSON: {}", e)))?)
+        }
+    };
+
+    let results = blocking(move || {
+        let mut results = vec![];
+
+        for item in items {
+            // domain must be lowercase
+            let name = item.name.to_lowercase();
+            let code = match DOMAIN_NAME_RE.is_match(&name) {
+                true => match save_domain(&name, &item) {
+                    Ok(code) => code,
+                    Err(e) => {
+                        log::error!("Failed to save domain {}: {}", name, e);
+                        DomainResultCode::Failed
+                    }
+                },
+                false => DomainResultCode::InvalidDomain,
+            };
+            results.push(DomainResult { name, code });
+        }
+        Ok(results)
+    })
+    .await?;
+
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(full(serde_json::to_string(&results)?))?)
+}
+
+pub async fn get_domains(req: Request<IncomingBody>) -> Result<Response<BoxBody>> {
+    let query = req.uri().query().unwrap_or("");
+    let params: HashMap<_, _> = form_urlencoded::parse(query.as_bytes())
+        .into_owned()
+        .collect();
+
+    let status = params.get("status").cloned();
+    let domains = blocking(move || store().query_domains(status.as_deref())).await?;
+
+    let data = serde_json::json!({"code": 0, "msg": "OK", "data": domains });
+
+    let json = serde_json::to_string(&data)?;
+    let response = Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(full(json))?;
+    Ok(response)
+}
+
+// Delete the domain unless it still has nodes
+fn delete_domain(name: &str) -> Result<DomainResultCode> {
+    Ok(if !store().query_domain_nodes(name)?.is_empty() {
+        DomainResultCode::DomainHasNodes
+    } else if store().delete_domain(name)? > 0 {
+        DomainResultCode::Deleted
+    } else {
+        DomainResultCode::DomainNotExist
+    })
+}
+
+/// Delete the domains, which must have no node left.
+pub async fn remove_domains(req: Request<IncomingBody>) -> Result<Response<BoxBody>> {
+    let whole_body = req.collect().await?.aggregate();
+    let names: Vec<String> = match serde_json::from_reader(whole_body.reader()) {
+        Ok(data) => data,
+        Err(e) => {
+            return Ok(Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(full(format!("Invalid JSON: {}", e)))?)
+        }
+    };
+
+    let results = blocking(move || {
+        let mut results = vec![];
+
+        for name in names {
+            let name = name.to_lowercase();
+            let code = match delete_domain(&name) {
+                Ok(code) => code,
+                Err(e) => {
+                    log::error!("Failed to delete domain {}: {}", name, e);
+                    DomainResultCode::Failed
+                }
+            };
+            results.push(DomainResult { name, code });
+        }
+        Ok(results)
+    })
+    .await?;
+
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(full(serde_json::to_string(&results)?))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn domain(chat_model: &str, embedding_model: &str) -> models::Domain {
+        models::Domain {
+            name: String::from("d1"),
+            owner: String::new(),
+            description: String::new(),
+            chat_model: chat_model.to_string(),
+            embedding_model: embedding_model.to_string(),
+            status: DOMAIN_STATUS_ACTIVE.to_string(),
+            created_at: chrono::DateTime::from_timestamp(0, 0).unwrap().naive_utc(),
+            health_probe: None,
+            user_conn_limit: None,
+        }
+    }
+
+    #[test]
+    fn empty_requirements_take_any_model() {
+        assert!(model_matches("", "llama-3.2-1b"));
+        assert!(model_matches("", ""));
+    }
+
+    #[test]
+    fn stars_take_any_model() {
+        assert!(model_matches("*", "llama-3.2-1b"));
+        assert!(model_matches("*", ""));
+        assert!(model_matches("**", "qwen"));
+    }
+
+    #[test]
+    fn exact_names_ignore_the_case() {
+        assert!(model_matches("Llama-3.2-1B", "llama-3.2-1b"));
+        assert!(model_matches("llama-3.2-1b", "LLAMA-3.2-1B"));
+        assert!(!model_matches("llama-3.2-1b", "llama-3.2-1b-instruct"));
+        assert!(!model_matches("llama-3.2-1b", "llama-3.2"));
+        assert!(!model_matches("llama", ""));
+    }
+
+    #[test]
+    fn patterns_match_prefixes_suffixes_and_single_chars() {
+        assert!(model_matches("llama-3.2-*", "llama-3.2-1b"));
+        assert!(model_matches("LLAMA-*", "llama-3.2-1b"));
+        assert!(model_matches("llama-*", "llama-"));
+        assert!(!model_matches("llama-3.2-*", "llama-3.1-8b"));
+        assert!(model_matches("*-instruct", "qwen-7b-instruct"));
+        assert!(!model_matches("*-instruct", "qwen-7b-instruct-q4"));
+        assert!(model_matches("*-7b-*", "qwen-7b-instruct"));
+        assert!(model_matches("*a*a*", "banana"));
+        assert!(!model_matches("*a*a*a*a*", "banana"));
+        assert!(model_matches("qwen-?b", "qwen-7b"));
+        assert!(!model_matches("qwen-?b", "qwen-14b"));
+        assert!(!model_matches("llama-*", ""));
+    }
+
+    #[test]
+    fn nodes_serve_both_models_of_the_domain() {
+        let record = domain("llama-*", "bge-m3");
+        assert!(serves_models(&record, "Llama-3.2-1b", "BGE-M3"));
+        assert!(!serves_models(&record, "llama-3.2-1b", "nomic-embed"));
+        assert!(!serves_models(&record, "qwen-7b", "bge-m3"));
+        assert!(serves_models(&domain("", ""), "", ""));
+    }
+}
@@ -5,8 +5,17 @@ use http_body_util::BodyExt;
 use hyper::{body::Incoming as IncomingBody, header, Request, Response, StatusCode};
 use log::{info, warn};
 use std::collections::HashMap;
+use std::sync::Mutex;
 
+use crate::bans::{find_ban, find_cached_ban, BanKind};
+use crate::cache::cache;
 use crate::db::*;
+use crate::domain_nodes::{pull_node_routing, refresh_node_routing};
+use crate::frps_messages::*;
+use crate::frps_servers::touch_frps_server;
+use crate::geoip;
+use crate::identity;
+use crate::models;
 use gaia_hub::*;
 use serde_json::Value;
 
@@ -17,22 +26,38 @@ use regex::Regex;
 lazy_static! {
     pub(crate) static ref FRPS_PATH_RE: Regex =
         Regex::new(r"^/inner/frps(?:/(?<id>frps_\d+))?$").unwrap();
+    static ref SUBDOMAIN_RE: Regex = Regex::new(r"^[\w\-\.]+$").unwrap();
+    // Max user connections per node in every USER_CONN_WINDOW_SECS, 0 means unlimited,
+    // unless the domains of the node set their own
+    static ref NODE_USER_CONN_LIMIT: i64 = std::env::var("NODE_USER_CONN_LIMIT")
+        .unwrap_or_else(|_| String::from("0"))
+        .parse()
+        .expect("NODE_USER_CONN_LIMIT must be a number");
+    // The user connection limit of each subdomain, with the time it was loaded by this instance
+    static ref CACHED_USER_CONN_LIMITS: Mutex<HashMap<String, (i64, i64)>> =
+        Mutex::new(HashMap::new());
 }
 
-fn process_json_data_and_build_response(data: serde_json::Value) -> Result<Response<BoxBody>> {
-    let mut modified_data = data;
+pub static USER_CONN_WINDOW_SECS: u64 = 60;
+// Reload the user connection limit of a node at most this often
+pub static USER_CONN_LIMIT_REFRESH_SECS: i64 = 5;
 
-    // Modify the JSON data...
-    modified_data["reject"] = serde_json::Value::Bool(false);
-    modified_data["unchange"] = serde_json::Value::Bool(true);
+/// Reason for refusing a frps operation, sent back to frps as `reject_reason`.
+#[derive(Debug)]
+struct Rejection(String);
 
-    // Log the modified JSON...
-    let formatted_json = serde_json::to_string(&modified_data)?;
+impl std::fmt::Display for Rejection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for Rejection {}
 
-    // Construct the response...
-    let json = serde_json::to_string(&modified_data)?;
+fn build_response(status: StatusCode, data: &impl serde::Serialize) -> Result<Response<BoxBody>> {
+    let json = serde_json::to_string(data)?;
     let response = Response::builder()
-        .status(StatusCode::OK)
+        .status(status)
         .header(header::CONTENT_TYPE, "application/json")
         .body(full(json))?;
 
@@ -41,230 +66,427 @@ fn process_json_data_and_build_response(data: serde_json::Value) -> Result<Respo
 
 pub async fn handler(req: Request<IncomingBody>) -> Result<Response<BoxBody>> {
     let path = req.uri().path().to_owned();
+    let query = req.uri().query().map(|q| q.to_owned());
     let frps_id = FRPS_PATH_RE
         .captures(&path)
         .and_then(|caps| caps.name("id"))
-        .and_then(|m| Some(m.as_str()));
+        .map(|m| m.as_str().to_owned());
+
+    let body = req.collect().await?.to_bytes();
+    let plugin_req = match serde_json::from_slice::<PluginRequest>(&body)
+        .map_err(|e| e.to_string())
+        .and_then(|plugin_req| plugin_req.check_version().map(|_| plugin_req))
+    {
+        Ok(plugin_req) => plugin_req,
+        Err(e) => {
+            log::error!(
+                "Invalid plugin request: {}. Body: {}",
+                e,
+                String::from_utf8_lossy(&body).replace('"', r#"\""#)
+            );
+            let data =
+                serde_json::json!({"code": 400, "msg": format!("Invalid plugin request: {}", e)});
+            return build_response(StatusCode::BAD_REQUEST, &data);
+        }
+    };
+
+    let op = plugin_req.op.name();
+    // Skip logging the high frequency events
+    if !matches!(op, "Ping" | "NewUserConn" | "NewWorkConn") {
+        log::info!(
+            "Received: {}",
+            String::from_utf8_lossy(&body).replace('"', r#"\""#)
+        );
+    }
+
+    // Validate and apply the operation before answering, so frps can turn away bad clients
+    let result = blocking(move || {
+        if let Some(frps_id) = frps_id.as_deref() {
+            touch_frps_server(frps_id, query.as_deref());
+        }
+        handler_inner(frps_id.as_deref(), &plugin_req.op)
+    })
+    .await;
+    let plugin_resp = match result {
+        Ok(()) => PluginResponse::allow(),
+        Err(e) => match e.downcast_ref::<Rejection>() {
+            Some(rejection) => {
+                log::warn!("Rejected {} request: {}", op, rejection);
+                PluginResponse::reject(rejection.to_string())
+            }
+            None => {
+                log::error!("Failed to handle {} request: {}", op, e);
+                PluginResponse::allow()
+            }
+        },
+    };
 
-    let whole_body = req.collect().await?.aggregate();
-    let mut data: serde_json::Value = serde_json::from_reader(whole_body.reader())?;
-    let response = process_json_data_and_build_response(data.clone())?;
+    build_response(StatusCode::OK, &plugin_resp)
+}
 
-    if let Err(e) = handler_inner(frps_id, &data).await {
-        log::error!("Failed to handle request: {}", e);
+fn device_id_from_metas(metas: &HashMap<String, String>) -> Result<&str> {
+    let device_id = match metas.get("deviceId") {
+        Some(id) => id.as_str(),
+        None => Err(Rejection(format!(
+            "Device ID not found in metas: {:?}",
+            metas
+        )))?,
+    };
+    if device_id.is_empty() {
+        Err(Rejection("Device ID is empty".to_string()))?
     }
-    Ok(response)
+    Ok(device_id)
 }
 
-async fn handler_inner(frps_id: Option<&str>, data: &serde_json::Value) -> Result<()> {
-    let op = data["op"].as_str().unwrap();
-    if op != "Ping" {
-        let formatted_json = serde_json::to_string(data).expect("Failed to serialize JSON");
-        log::info!("Received: {}", formatted_json.replace(r#"""#, r#"\""#));
+fn handler_inner(frps_id: Option<&str>, op: &Op) -> Result<()> {
+    match op {
+        Op::Login(content) => handle_login(content),
+        Op::NewProxy(content) => handle_new_proxy(frps_id, content),
+        Op::CloseProxy(content) => handle_close_proxy(frps_id, content),
+        Op::Ping(content) => handle_ping(content),
+        Op::NewUserConn(content) => handle_new_user_conn(content),
+        Op::NewWorkConn(content) => handle_new_work_conn(content),
     }
+}
 
-    // Handle JSON data and construct response...
-
-    if op == "Login" {
-        let content = &data["content"];
-        let metas = &content["metas"];
-        let device_id = match metas["deviceId"].as_str() {
-            Some(id) => id,
-            None => Err(format!("Device ID not found in metas: {:?}", metas))?,
-        };
-        if device_id.is_empty() {
-            Err("Device ID is empty")?
-        }
-        let os = content["os"].as_str().unwrap_or("default_os");
-        let arch = content["arch"].as_str().unwrap_or("default_arch");
-        let version = content["version"].as_str().unwrap_or("0.0.0");
-        let client_address = match content["client_address"].as_str() {
-            Some(addr) => addr,
-            None => Err(format!(
-                "Client address not found in content: {:?}",
-                content
-            ))?,
-        };
-
-        let login_time = chrono::Utc::now().naive_utc();
-
-        let count = count_device_by_device_id(device_id)?;
-        if count == 0 {
-            let _device = create_device(
-                device_id,
-                os,
-                arch,
-                version,
-                client_address,
-                &metas,
-                &login_time,
-            )?;
-        } else {
-            // Update the login_time of device
-            update_device(device_id, &login_time)?;
-        }
-    } else if op == "NewProxy" {
-        let content = &data["content"];
-        let metas = &content["user"]["metas"];
-        let device_id = match metas["deviceId"].as_str() {
-            Some(id) => id,
-            None => Err(format!("Device ID not found in metas: {:?}", metas))?,
-        };
-        if device_id.is_empty() {
-            Err("Device ID is empty")?
+fn handle_login(content: &LoginContent) -> Result<()> {
+    let metas = &content.metas;
+    let device_id = device_id_from_metas(metas)?;
+    let os = content.os.as_str();
+    let arch = content.arch.as_str();
+    let version = content.version.as_str();
+    let client_address = content.client_address.as_str();
+    if client_address.is_empty() {
+        Err(Rejection(format!(
+            "Client address not found in content: {:?}",
+            content
+        )))?
+    }
+
+    if let Some(ban) = find_ban(&[
+        (BanKind::DeviceId, device_id),
+        (BanKind::ClientAddress, client_address),
+    ])? {
+        Err(Rejection(format!(
+            "Device {} is banned by {} {}: {}",
+            device_id, ban.kind, ban.value, ban.reason
+        )))?
+    }
+
+    let public_key = metas
+        .get("publicKey")
+        .map(|s| s.as_str())
+        .unwrap_or_default();
+    let signature = metas
+        .get("signature")
+        .map(|s| s.as_str())
+        .unwrap_or_default();
+    let timestamp = content.timestamp;
+
+    let devices = store().query_device_by_device_id(device_id)?;
+
+    // Once a device has registered its public key, every login must be signed with it
+    let registered_key = devices
+        .first()
+        .map(|device| device.public_key.as_str())
+        .filter(|key| !key.is_empty());
+    let key = registered_key.unwrap_or(public_key);
+    if !key.is_empty() {
+        let message = identity::login_message(device_id, timestamp);
+        if !identity::is_fresh(timestamp) || !identity::verify_signature(key, &message, signature) {
+            Err(Rejection(format!(
+                "Invalid login signature of device {}",
+                device_id
+            )))?
         }
-        let node_id = match content["subdomain"].as_str() {
-            Some(id) => id,
-            None => Err(format!("Node ID not found in content: {:?}", content))?,
-        };
-        let run_id = content["user"]["run_id"].as_str().unwrap();
-        let subdomain = match content["proxy_name"].as_str() {
-            Some(id) => id,
-            None => Err(format!("Subdomain not found in content: {:?}", content))?,
-        };
-
-        // Record the subdomain and frps_id mapping in redis
-        if let Some(frps_id) = frps_id {
-            if let Err(e) = crate::redism::set_subdomain_frps_id(subdomain, frps_id) {
-                log::error!(
-                    "Failed to set redis key/value: {}/{}. Error msg: {}",
-                    subdomain,
-                    frps_id,
-                    e
-                );
-            }
+    }
+    // Only a new device binds the key it logs in with, a known one needs an admin approval
+    let approved = registered_key.is_some() || (devices.is_empty() && !public_key.is_empty());
+    if !approved && identity::signatures_required() {
+        Err(Rejection(format!(
+            "Device {} has no approved public key",
+            device_id
+        )))?
+    }
+
+    let login_time = chrono::Utc::now().naive_utc();
+
+    if devices.is_empty() {
+        let _device = store().create_device(&models::NewDevice {
+            device_id,
+            os,
+            arch,
+            version,
+            client_address,
+            meta: &serde_json::to_value(metas)?,
+            login_time: &login_time,
+            public_key,
+        })?;
+    } else {
+        let pending_key = devices[0].pending_public_key.as_str();
+        if registered_key.is_none() && !public_key.is_empty() && pending_key != public_key {
+            log::warn!(
+                "Device {} offered a public key, waiting for approval",
+                device_id
+            );
+            store().update_device_pending_public_key(device_id, public_key)?;
         }
+        // Update the login_time of device
+        store().update_device(device_id, &login_time)?;
+    }
+
+    Ok(())
+}
 
-        let now = chrono::Utc::now().naive_utc();
+fn handle_new_proxy(frps_id: Option<&str>, content: &NewProxyContent) -> Result<()> {
+    let metas = &content.user.metas;
+    let device_id = device_id_from_metas(metas)?;
+    let node_id = content.subdomain.as_str();
+    if !SUBDOMAIN_RE.is_match(node_id) {
+        Err(Rejection(format!(
+            "Invalid node ID in content: {:?}",
+            content
+        )))?
+    }
+    let run_id = content.user.run_id.as_str();
+    let subdomain = content.proxy_name.as_str();
+    if !SUBDOMAIN_RE.is_match(subdomain) {
+        Err(Rejection(format!(
+            "Invalid subdomain in content: {:?}",
+            content
+        )))?
+    }
 
-        let devices = query_device_by_device_id(device_id)?;
+    let devices = store().query_device_by_device_id(device_id)?;
 
-        if devices.is_empty() {
-            Err(format!("Device not found: {}", device_id))?
+    if devices.is_empty() {
+        Err(Rejection(format!("Device not found: {}", device_id)))?
+    }
+
+    if let Some(ban) = find_ban(&[
+        (BanKind::DeviceId, device_id),
+        (BanKind::NodeId, node_id),
+        (BanKind::Subdomain, subdomain),
+        (BanKind::ClientAddress, &devices[0].client_address),
+    ])? {
+        Err(Rejection(format!(
+            "Node {} is banned by {} {}: {}",
+            node_id, ban.kind, ban.value, ban.reason
+        )))?
+    }
+
+    let node = store().query_node_by_node_id(node_id)?;
+    if let Some(node) = &node {
+        // Refuse to take over a node which is still served by another device
+        if node.device_id != device_id && node.status != NODE_STATUS_OFFLINE {
+            Err(Rejection(format!(
+                "Node {} is already served by another device",
+                node_id
+            )))?
         }
+    }
 
-        let device = &devices[0];
-        let last_login_time = device.login_time;
+    // Record the subdomain and frps_id mapping in redis
+    if let Some(frps_id) = frps_id {
+        if let Err(e) = cache().set_subdomain_frps_id(subdomain, frps_id) {
+            log::error!(
+                "Failed to set redis key/value: {}/{}. Error msg: {}",
+                subdomain,
+                frps_id,
+                e
+            );
+        }
+    }
 
-        let client_address = device.client_address.clone();
-        let os = device.os.clone();
-        let arch = device.arch.clone();
-        let version = device.version.clone();
+    let now = chrono::Utc::now().naive_utc();
+    let metas = serde_json::to_value(metas)?;
+
+    let device = &devices[0];
+    let new_node = models::NewNode {
+        node_id,
+        device_id,
+        subdomain,
+        version: &device.version,
+        arch: &device.arch,
+        os: &device.os,
+        client_address: &device.client_address,
+        status: NODE_STATUS_ONLINE,
+        login_time: &device.login_time,
+        last_active_time: &now,
+        run_id,
+        meta: &metas,
+        frps_id: frps_id.unwrap_or_default(),
+    };
+
+    let mut node_online = false;
+    match node {
+        // Only handle the offline node cause the 'already exists' node will also send 'NewProxy' event
+        Some(node) if node.status == NODE_STATUS_OFFLINE => {
+            let _node_status = store().update_node_status_more(&new_node)?;
+            node_online = true;
+        }
+        None => {
+            let _node_status = store().create_node_status(&new_node);
+            node_online = true;
+        }
+        _ => {
+            // Ignore if node is online
+            // Ignore other Err
+        }
+    }
+    if node_online {
+        if let Some(location) = geoip::locate(&device.client_address) {
+            store().update_node_location(
+                node_id,
+                &location.country,
+                &location.subdivision,
+                &location.city,
+            )?;
+        }
 
-        let node = query_node_by_node_id(node_id)?;
-        let mut node_online = false;
-        match node {
-            // Only handle the offline node cause the 'already exists' node will also send 'NewProxy' event
-            Some(node) if node.status == NODE_STATUS_OFFLINE => {
-                let _node_status = update_node_status_more(
-                    node_id,
-                    subdomain,
-                    device_id,
-                    &version,
-                    &arch,
-                    &os,
-                    &client_address,
-                    #[cfg(feature = "sqlite")]
-                    &chrono::NaiveDateTime::from_timestamp(last_login_time, 0),
-                    #[cfg(feature = "mysql")]
-                    &last_login_time,
-                    &now,
-                    run_id,
-                    NODE_STATUS_ONLINE,
-                    &metas,
-                )?;
-                node_online = true;
-            }
-            None => {
-                let _node_status = create_node_status(
+        // If the node has joined some domain, add it to the redis
+        if let Some(node) = store().query_node_by_node_id(node_id)? {
+            if let Err(e) = refresh_node_routing(&node) {
+                log::error!(
+                    "Failed to join domain nodes in redis: {}. Error msg: {}",
                     node_id,
-                    device_id,
-                    subdomain,
-                    &version,
-                    &arch,
-                    &os,
-                    &client_address,
-                    #[cfg(feature = "sqlite")]
-                    &chrono::NaiveDateTime::from_timestamp(last_login_time, 0),
-                    #[cfg(feature = "mysql")]
-                    &last_login_time,
-                    &now,
-                    run_id,
-                    NODE_STATUS_ONLINE,
-                    &metas,
+                    e
                 );
-                node_online = true;
-            }
-            _ => {
-                // Ignore if node is online
-                // Ignore other Err
             }
         }
-        if node_online {
-            // If the node has joined some domain, add it to the redis
-            if let Some(domain_node) = query_domain_node_by_node_id(node_id)? {
-                let domain = domain_node.domain.as_str();
-                if let Err(e) = crate::redism::nodes_upjoin(domain, node_id, domain_node.weight) {
-                    log::error!(
-                        "Failed to join domain nodes in redis: {}. Error msg: {}",
-                        domain,
-                        e
-                    );
-                }
-            }
-        }
-    } else if op == "CloseProxy" {
-        let content = &data["content"];
-        let metas = &content["user"]["metas"];
-        let device_id = match metas["deviceId"].as_str() {
-            Some(id) => id,
-            None => Err(format!("Device ID not found in metas: {:?}", metas))?,
-        };
-        if device_id.is_empty() {
-            Err("Device ID is empty")?
-        }
-        let subdomain = content["proxy_name"].as_str().unwrap();
+    }
 
-        // Remove the subdomain and frps_id mapping from redis
-        if let Some(frps_id) = frps_id {
-            if let Err(e) = crate::redism::del_subdomain(subdomain) {
-                log::error!("Failed to del redis key: {}. Error msg: {}", subdomain, e);
-            }
+    Ok(())
+}
+
+fn handle_close_proxy(frps_id: Option<&str>, content: &CloseProxyContent) -> Result<()> {
+    let metas = &content.user.metas;
+    let device_id = match metas.get("deviceId") {
+        Some(id) if !id.is_empty() => id.as_str(),
+        _ => Err(format!("Device ID not found in metas: {:?}", metas))?,
+    };
+    let subdomain = content.proxy_name.as_str();
+
+    // Remove the subdomain and frps_id mapping from redis
+    if frps_id.is_some() {
+        if let Err(e) = cache().del_subdomain(subdomain) {
+            log::error!("Failed to del redis key: {}. Error msg: {}", subdomain, e);
         }
+    }
 
-        let last_active_time = chrono::Utc::now().naive_utc();
-
-        update_node_active_status(device_id, subdomain, &last_active_time, NODE_STATUS_OFFLINE)?;
-        if let Some(node) = query_node_by_subdomain(subdomain)? {
-            // If the node has joined some domain, remove it to the redis
-            if let Some(domain_node) = query_domain_node_by_node_id(&node.node_id)? {
-                let domain = domain_node.domain.as_str();
-                if let Err(e) = crate::redism::node_lefts(domain, &node.node_id, domain_node.weight)
-                {
-                    log::error!(
-                        "Failed to leave domain nodes in redis: {}. Error msg: {}",
-                        domain,
-                        e
-                    );
-                }
-            }
+    let last_active_time = chrono::Utc::now().naive_utc();
+
+    store().update_node_active_status(
+        device_id,
+        subdomain,
+        &last_active_time,
+        NODE_STATUS_OFFLINE,
+    )?;
+    if let Some(node) = store().query_node_by_subdomain(subdomain)? {
+        // If the node has joined some domains, remove it from their routing
+        pull_node_routing(&node.node_id)?;
+    }
+
+    Ok(())
+}
+
+fn handle_ping(content: &PingContent) -> Result<()> {
+    let metas = &content.user.metas;
+    let device_id = match metas.get("deviceId") {
+        Some(id) if !id.is_empty() => id.as_str(),
+        _ => Err(format!("Device ID not found in metas: {:?}", metas))?,
+    };
+    let last_active_time = chrono::Utc::now().naive_utc();
+
+    // The 'Ping' event only contain device_id but without subdomain
+    // Only update the online or unavail node by device_id cause there may be multiple nodes with the same device_id
+    store().update_online_node_last_active_time(device_id, &last_active_time)?;
+
+    Ok(())
+}
+
+// The tightest limit set by the domains of a node, 0 if they all leave it unlimited,
+// NODE_USER_CONN_LIMIT if none sets one
+fn user_conn_limit(domain_limits: &[i64]) -> i64 {
+    match domain_limits.iter().filter(|limit| **limit > 0).min() {
+        Some(limit) => *limit,
+        None if domain_limits.is_empty() => *NODE_USER_CONN_LIMIT,
+        None => 0,
+    }
+}
+
+// The user connection limit of the node loaded within USER_CONN_LIMIT_REFRESH_SECS,
+// as the domains are too slow to query on every user connection
+fn cached_user_conn_limit(subdomain: &str) -> Result<i64> {
+    let now = chrono::Utc::now().timestamp();
+    if let Some((loaded, limit)) = CACHED_USER_CONN_LIMITS.lock().unwrap().get(subdomain) {
+        if now - loaded < USER_CONN_LIMIT_REFRESH_SECS {
+            return Ok(*limit);
         }
-    } else if op == "Ping" {
-        let content = &data["content"];
-        let metas = &content["user"]["metas"];
-        let device_id = match metas["deviceId"].as_str() {
-            Some(id) => id,
-            None => Err(format!("Device ID not found in metas: {:?}", metas))?,
-        };
-        if device_id.is_empty() {
-            Err("Device ID is empty")?
+    }
+
+    let limit = cached_user_conn_limit(subdomain)?;
+    let mut limits = CACHED_USER_CONN_LIMITS.lock().unwrap();
+    // Forget the nodes which had no user connection lately
+    limits.retain(|_, (loaded, _)| now - *loaded < USER_CONN_LIMIT_REFRESH_SECS);
+    limits.insert(subdomain.to_string(), (now, limit));
+    Ok(limit)
+}
+
+fn handle_new_user_conn(content: &NewUserConnContent) -> Result<()> {
+    let subdomain = content.proxy_name.as_str();
+    let device_id = content
+        .user
+        .metas
+        .get("deviceId")
+        .map(|s| s.as_str())
+        .unwrap_or_default();
+
+    if let Some(ban) = find_cached_ban(&[
+        (BanKind::DeviceId, device_id),
+        (BanKind::Subdomain, subdomain),
+    ])? {
+        Err(Rejection(format!(
+            "Node {} is banned: {}",
+            subdomain, ban.reason
+        )))?
+    }
+
+    let count = match cache().incr_user_conns(subdomain, USER_CONN_WINDOW_SECS) {
+        Ok(count) => count,
+        Err(e) => {
+            log::error!(
+                "Failed to count user connections of {}. Error msg: {}",
+                subdomain,
+                e
+            );
+            0
         }
-        let last_active_time = chrono::Utc::now().naive_utc();
+    };
+    let limit = user_conn_limit(&store().query_user_conn_limits_by_subdomain(subdomain)?);
+    if limit > 0 && count > limit {
+        Err(Rejection(format!(
+            "Too many user connections to node {}",
+            subdomain
+        )))?
+    }
 
-        // The 'Ping' event only contain device_id but without subdomain
-        // Only update the online or unavail node by device_id cause there may be multiple nodes with the same device_id
-        update_online_node_last_active_time(device_id, &last_active_time)?;
+    Ok(())
+}
+
+fn handle_new_work_conn(content: &NewWorkConnContent) -> Result<()> {
+    // The work connection only carries the run_id of frpc, which identifies its nodes
+    // more precisely than the device_id in 'Ping'
+    let run_id = match content.run_id.as_str() {
+        "" => content.user.run_id.as_str(),
+        run_id => run_id,
+    };
+    if run_id.is_empty() {
+        Err(format!("Run ID not found in content: {:?}", content))?
     }
+    let last_active_time = chrono::Utc::now().naive_utc();
+
+    store().update_online_node_last_active_time_by_run_id(run_id, &last_active_time)?;
 
     Ok(())
 }
@@ -362,7 +584,8 @@ pub async fn query_nodes(req: Request<IncomingBody>) -> Result<Response<BoxBody>
         }
     }
 
-    let result = query_nodes_by_parameters(query_parameters)?;
+    let filter = NodeFilter::from_params(query_parameters);
+    let result = blocking(move || store().query_nodes_by_filter(&filter)).await?;
 
     let data = serde_json::json!({"code": 0, "msg": "OK", "data": result });
 
@@ -399,7 +622,7 @@ pub async fn get_living_nodes(req: Request<IncomingBody>) -> Result<Response<Box
         .parse::<u64>()
         .unwrap_or(0);
 
-    let nodes = query_living_nodes(lived_secs, page, size)?;
+    let nodes = blocking(move || store().query_living_nodes(lived_secs, page, size)).await?;
 
     let data = serde_json::json!({"code": 0, "msg": "OK", "data": nodes });
 
@@ -410,3 +633,17 @@ pub async fn get_living_nodes(req: Request<IncomingBody>) -> Result<Response<Box
         .body(full(json))?;
     Ok(response)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn domains_override_the_default_user_conn_limit() {
+        assert_eq!(user_conn_limit(&[]), *NODE_USER_CONN_LIMIT);
+        assert_eq!(user_conn_limit(&[50]), 50);
+        assert_eq!(user_conn_limit(&[0, 50, 20]), 20);
+        assert_eq!(user_conn_limit(&[0]), 0);
+        assert_eq!(user_conn_limit(&[0, 0]), 0);
+    }
+}
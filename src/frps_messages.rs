@@ -0,0 +1,152 @@
+use lazy_static::lazy_static;
+use semver::{Version, VersionReq};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+// Messages of the frps server plugin protocol
+// https://github.com/fatedier/frp/blob/dev/doc/server_plugin.md
+
+lazy_static! {
+    static ref SUPPORTED_VERSIONS: VersionReq = VersionReq::parse("^0.1").unwrap();
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PluginRequest {
+    pub version: String,
+    #[serde(flatten)]
+    pub op: Op,
+}
+
+impl PluginRequest {
+    pub fn check_version(&self) -> std::result::Result<(), String> {
+        let version = Version::parse(&self.version)
+            .map_err(|e| format!("Invalid plugin version {}: {}", self.version, e))?;
+        if !SUPPORTED_VERSIONS.matches(&version) {
+            return Err(format!("Unsupported plugin version {}", self.version));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "op", content = "content")]
+pub enum Op {
+    Login(LoginContent),
+    NewProxy(NewProxyContent),
+    CloseProxy(CloseProxyContent),
+    Ping(PingContent),
+    NewWorkConn(NewWorkConnContent),
+    NewUserConn(NewUserConnContent),
+}
+
+impl Op {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Op::Login(_) => "Login",
+            Op::NewProxy(_) => "NewProxy",
+            Op::CloseProxy(_) => "CloseProxy",
+            Op::Ping(_) => "Ping",
+            Op::NewWorkConn(_) => "NewWorkConn",
+            Op::NewUserConn(_) => "NewUserConn",
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct UserInfo {
+    #[serde(default)]
+    pub metas: HashMap<String, String>,
+    #[serde(default)]
+    pub run_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LoginContent {
+    #[serde(default = "default_version")]
+    pub version: String,
+    #[serde(default = "default_os")]
+    pub os: String,
+    #[serde(default = "default_arch")]
+    pub arch: String,
+    #[serde(default)]
+    pub timestamp: i64,
+    #[serde(default)]
+    pub metas: HashMap<String, String>,
+    #[serde(default)]
+    pub client_address: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NewProxyContent {
+    #[serde(default)]
+    pub user: UserInfo,
+    pub proxy_name: String,
+    #[serde(default)]
+    pub subdomain: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CloseProxyContent {
+    #[serde(default)]
+    pub user: UserInfo,
+    pub proxy_name: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PingContent {
+    #[serde(default)]
+    pub user: UserInfo,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NewWorkConnContent {
+    #[serde(default)]
+    pub user: UserInfo,
+    #[serde(default)]
+    pub run_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NewUserConnContent {
+    #[serde(default)]
+    pub user: UserInfo,
+    pub proxy_name: String,
+}
+
+fn default_version() -> String {
+    String::from("0.0.0")
+}
+
+fn default_os() -> String {
+    String::from("default_os")
+}
+
+fn default_arch() -> String {
+    String::from("default_arch")
+}
+
+#[derive(Debug, Serialize)]
+pub struct PluginResponse {
+    pub reject: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reject_reason: Option<String>,
+    pub unchange: bool,
+}
+
+impl PluginResponse {
+    pub fn allow() -> Self {
+        PluginResponse {
+            reject: false,
+            reject_reason: None,
+            unchange: true,
+        }
+    }
+
+    pub fn reject(reason: String) -> Self {
+        PluginResponse {
+            reject: true,
+            reject_reason: Some(reason),
+            unchange: true,
+        }
+    }
+}
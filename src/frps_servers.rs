@@ -0,0 +1,127 @@
+use chrono::NaiveDateTime;
+use hyper::{body::Incoming as IncomingBody, header, Request, Response, StatusCode};
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::db::*;
+use crate::domain_nodes::offline_nodes;
+use crate::models;
+use gaia_hub::*;
+
+// Don't write the last seen time of a frps more often than this
+pub static FRPS_SEEN_THROTTLE_SECS: i64 = 10;
+// A frps without any event for this long is considered gone
+pub static FRPS_SILENT_DURATION: u64 = 90;
+
+lazy_static! {
+    // The last time each frps was recorded as seen by this instance
+    static ref FRPS_LAST_RECORDED: Mutex<HashMap<String, i64>> = Mutex::new(HashMap::new());
+}
+
+#[derive(serde::Serialize)]
+struct FrpsServerInfo {
+    #[serde(flatten)]
+    server: models::FrpsServer,
+    node_count: i64,
+}
+
+/// Record an event from the frps. The `version` and `region` come from the query of the plugin path.
+pub fn touch_frps_server(frps_id: &str, query: Option<&str>) {
+    let now = chrono::Utc::now().naive_utc();
+    let timestamp = now.and_utc().timestamp();
+    if let Some(t) = FRPS_LAST_RECORDED.lock().unwrap().get(frps_id) {
+        if timestamp - t < FRPS_SEEN_THROTTLE_SECS {
+            return;
+        }
+    }
+
+    let params: HashMap<_, _> = form_urlencoded::parse(query.unwrap_or("").as_bytes())
+        .into_owned()
+        .collect();
+    let version = params.get("version").map(|s| s.as_str());
+    let region = params.get("region").map(|s| s.as_str());
+
+    // Only a recorded event throttles the next ones, a failed write is retried on the next event
+    match store().upsert_frps_server(frps_id, version, region, &now) {
+        Ok(_) => {
+            FRPS_LAST_RECORDED
+                .lock()
+                .unwrap()
+                .insert(frps_id.to_string(), timestamp);
+        }
+        Err(e) => log::error!("Failed to record frps {}. Error msg: {}", frps_id, e),
+    }
+}
+
+pub async fn list_frps_servers(_req: Request<IncomingBody>) -> Result<Response<BoxBody>> {
+    let servers = blocking(|| {
+        let node_counts: HashMap<String, i64> = store()
+            .count_active_nodes_by_frps_id()?
+            .into_iter()
+            .collect();
+
+        Ok(store()
+            .query_frps_servers()?
+            .into_iter()
+            .map(|server| FrpsServerInfo {
+                node_count: node_counts.get(&server.frps_id).copied().unwrap_or(0),
+                server,
+            })
+            .collect::<Vec<_>>())
+    })
+    .await?;
+
+    let data = serde_json::json!({"code": 0, "msg": "OK", "data": servers });
+
+    let json = serde_json::to_string(&data)?;
+    let response = Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(full(json))?;
+    Ok(response)
+}
+
+// Close all nodes of the frps servers which stopped sending events
+pub async fn close_silent_frps_nodes(now: NaiveDateTime) {
+    if let Err(err) = blocking(move || {
+        close_silent_frps_nodes_blocking(now);
+        Ok(())
+    })
+    .await
+    {
+        log::error!("Failed to close the nodes of silent frps: {:?}", err);
+    }
+}
+
+fn close_silent_frps_nodes_blocking(now: NaiveDateTime) {
+    let silent_before = now
+        .checked_sub_signed(chrono::Duration::seconds(FRPS_SILENT_DURATION as i64))
+        .unwrap();
+    let servers = match store().query_silent_frps_servers(&silent_before) {
+        Ok(servers) => servers,
+        Err(err) => {
+            log::error!("Failed to query silent frps servers: {:?}", err);
+            return;
+        }
+    };
+
+    for server in servers {
+        let frps_id = server.frps_id.as_str();
+        if let Err(err) = store().update_frps_server_status(frps_id, NODE_STATUS_OFFLINE) {
+            log::error!("Failed to offline frps {}: {:?}", frps_id, err);
+            continue;
+        }
+        match store()
+            .query_active_nodes_by_frps_id(frps_id)
+            .and_then(offline_nodes)
+        {
+            Ok(n) => {
+                log::info!("Closed {} nodes of silent frps {}", n, frps_id);
+            }
+            Err(err) => {
+                log::error!("Failed to close nodes of frps {}: {:?}", frps_id, err);
+            }
+        }
+    }
+}
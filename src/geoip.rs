@@ -0,0 +1,64 @@
+use lazy_static::lazy_static;
+use maxminddb::{geoip2, Reader};
+use std::env;
+
+use crate::bans::parse_client_ip;
+
+lazy_static! {
+    // Optional GeoIP2/GeoLite2 City database to locate nodes by their client address
+    static ref GEOIP_READER: Option<Reader<Vec<u8>>> = match env::var("GEOIP_DB") {
+        Ok(path) => Some(Reader::open_readfile(&path).expect("Failed to open GEOIP_DB")),
+        Err(_) => None,
+    };
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
+pub struct Location {
+    #[serde(default)]
+    pub country: String,
+    #[serde(default)]
+    pub subdivision: String,
+    #[serde(default)]
+    pub city: String,
+}
+
+pub fn geoip_enabled() -> bool {
+    GEOIP_READER.is_some()
+}
+
+/// Resolve the country and subdivision ISO codes and the English city name of a client address.
+pub fn locate(client_address: &str) -> Option<Location> {
+    let reader = GEOIP_READER.as_ref()?;
+    let ip = parse_client_ip(client_address)?;
+    let city: geoip2::City = match reader.lookup(ip) {
+        Ok(city) => city,
+        Err(e) => {
+            log::warn!("Failed to locate {}: {}", client_address, e);
+            return None;
+        }
+    };
+
+    let country = city
+        .country
+        .and_then(|c| c.iso_code)
+        .unwrap_or_default()
+        .to_string();
+    let subdivision = city
+        .subdivisions
+        .and_then(|s| s.into_iter().next())
+        .and_then(|s| s.iso_code)
+        .unwrap_or_default()
+        .to_string();
+    let city = city
+        .city
+        .and_then(|c| c.names)
+        .and_then(|names| names.get("en").copied())
+        .unwrap_or_default()
+        .to_string();
+
+    Some(Location {
+        country,
+        subdivision,
+        city,
+    })
+}
@@ -0,0 +1,149 @@
+use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+use lazy_static::lazy_static;
+use std::env;
+
+lazy_static! {
+    // Refuse the logins and device calls which aren't signed by an approved key
+    static ref REQUIRE_DEVICE_SIGNATURES: bool = env::var("REQUIRE_DEVICE_SIGNATURES")
+        .unwrap_or_else(|_| String::from("false"))
+        .parse()
+        .expect("REQUIRE_DEVICE_SIGNATURES must be true or false");
+}
+
+/// Signatures older or newer than this are refused to limit replays.
+pub static SIGNATURE_MAX_AGE_SECS: i64 = 5 * 60;
+
+pub static SIGNATURE_HEADER: &str = "x-device-signature";
+pub static TIMESTAMP_HEADER: &str = "x-device-timestamp";
+
+/// The message signed by frpc in the Login metas.
+pub fn login_message(device_id: &str, timestamp: i64) -> Vec<u8> {
+    format!("{}:{}", device_id, timestamp).into_bytes()
+}
+
+/// The message signed for the device-info and device-health calls.
+pub fn api_message(device_id: &str, timestamp: i64, body: &[u8]) -> Vec<u8> {
+    let mut message = format!("{}:{}:", device_id, timestamp).into_bytes();
+    message.extend_from_slice(body);
+    message
+}
+
+pub fn signatures_required() -> bool {
+    *REQUIRE_DEVICE_SIGNATURES
+}
+
+fn parse_public_key(public_key: &str) -> Option<VerifyingKey> {
+    let bytes: [u8; 32] = hex::decode(public_key).ok()?.try_into().ok()?;
+    VerifyingKey::from_bytes(&bytes).ok()
+}
+
+pub fn is_valid_public_key(public_key: &str) -> bool {
+    parse_public_key(public_key).is_some()
+}
+
+pub fn is_fresh(timestamp: i64) -> bool {
+    is_fresh_at(timestamp, chrono::Utc::now().timestamp())
+}
+
+fn is_fresh_at(timestamp: i64, now: i64) -> bool {
+    now.checked_sub(timestamp)
+        .is_some_and(|age| age.abs() <= SIGNATURE_MAX_AGE_SECS)
+}
+
+/// Verify a hex encoded ed25519 signature against a hex encoded public key.
+pub fn verify_signature(public_key: &str, message: &[u8], signature: &str) -> bool {
+    let key = match parse_public_key(public_key) {
+        Some(key) => key,
+        None => return false,
+    };
+    let signature = match hex::decode(signature)
+        .ok()
+        .and_then(|bytes| Signature::from_slice(&bytes).ok())
+    {
+        Some(signature) => signature,
+        None => return false,
+    };
+    key.verify(message, &signature).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::{Signer, SigningKey};
+
+    fn signing_key() -> SigningKey {
+        SigningKey::from_bytes(&[7; 32])
+    }
+
+    fn public_key() -> String {
+        hex::encode(signing_key().verifying_key().as_bytes())
+    }
+
+    fn sign(message: &[u8]) -> String {
+        hex::encode(signing_key().sign(message).to_bytes())
+    }
+
+    #[test]
+    fn fresh_within_the_max_age_either_way() {
+        let now = 1_700_000_000;
+        assert!(is_fresh_at(now, now));
+        assert!(is_fresh_at(now - SIGNATURE_MAX_AGE_SECS, now));
+        assert!(is_fresh_at(now + SIGNATURE_MAX_AGE_SECS, now));
+        assert!(!is_fresh_at(now - SIGNATURE_MAX_AGE_SECS - 1, now));
+        assert!(!is_fresh_at(now + SIGNATURE_MAX_AGE_SECS + 1, now));
+        assert!(!is_fresh_at(i64::MIN, now));
+        assert!(!is_fresh_at(i64::MAX, now));
+    }
+
+    #[test]
+    fn verifies_login_and_api_signatures() {
+        let message = login_message("dev1", 1_700_000_000);
+        assert!(verify_signature(&public_key(), &message, &sign(&message)));
+
+        let message = api_message("dev1", 1_700_000_000, br#"{"health":true}"#);
+        assert!(verify_signature(&public_key(), &message, &sign(&message)));
+    }
+
+    #[test]
+    fn refuses_tampered_messages() {
+        let signature = sign(&api_message("dev1", 1_700_000_000, br#"{"health":true}"#));
+        for message in [
+            api_message("dev1", 1_700_000_000, br#"{"health":false}"#),
+            api_message("dev2", 1_700_000_000, br#"{"health":true}"#),
+            api_message("dev1", 1_700_000_001, br#"{"health":true}"#),
+            login_message("dev1", 1_700_000_000),
+        ] {
+            assert!(!verify_signature(&public_key(), &message, &signature));
+        }
+    }
+
+    #[test]
+    fn refuses_tampered_signatures_and_keys() {
+        let message = login_message("dev1", 1_700_000_000);
+        let signature = sign(&message);
+
+        let mut flipped = hex::decode(&signature).unwrap();
+        flipped[0] ^= 1;
+        assert!(!verify_signature(
+            &public_key(),
+            &message,
+            &hex::encode(flipped)
+        ));
+        assert!(!verify_signature(&public_key(), &message, &signature[2..]));
+        assert!(!verify_signature(&public_key(), &message, "not hex"));
+        assert!(!verify_signature(&public_key(), &message, ""));
+
+        let other_key = hex::encode(SigningKey::from_bytes(&[8; 32]).verifying_key().as_bytes());
+        assert!(!verify_signature(&other_key, &message, &signature));
+        assert!(!verify_signature(&public_key()[2..], &message, &signature));
+        assert!(!verify_signature("", &message, &signature));
+    }
+
+    #[test]
+    fn validates_public_keys() {
+        assert!(is_valid_public_key(&public_key()));
+        assert!(!is_valid_public_key(&public_key()[2..]));
+        assert!(!is_valid_public_key("zz"));
+        assert!(!is_valid_public_key(""));
+    }
+}
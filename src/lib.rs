@@ -5,7 +5,10 @@ pub static NODE_STATUS_ONLINE: &str = "online";
 pub static NODE_STATUS_OFFLINE: &str = "offline";
 pub static NODE_STATUS_UNAVAIL: &str = "unavail";
 
-type GenericError = Box<dyn std::error::Error + Send + Sync>;
+pub static DOMAIN_STATUS_ACTIVE: &str = "active";
+pub static DOMAIN_STATUS_SUSPENDED: &str = "suspended";
+
+pub type GenericError = Box<dyn std::error::Error + Send + Sync>;
 pub type Result<T> = std::result::Result<T, GenericError>;
 pub type BoxBody = http_body_util::combinators::BoxBody<Bytes, hyper::Error>;
 
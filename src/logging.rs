@@ -1,5 +1,4 @@
 use log::LevelFilter;
-use log4rs;
 use log4rs::append::console::ConsoleAppender;
 use log4rs::append::file::FileAppender;
 use log4rs::config::{Appender, Config, Root};
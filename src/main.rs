@@ -8,47 +8,87 @@ use hyper::{body::Incoming as IncomingBody, Method, Request, Response, StatusCod
 
 use gaia_hub::*;
 use hyper_util::rt::TokioIo;
-use redis::{Commands, ExistenceCheck, SetExpiry, SetOptions};
 use std::env;
 use std::sync::Arc;
 use tokio::net::TcpListener;
 use tokio::sync::Semaphore;
 
+mod adaptive;
 mod args;
+mod bans;
+mod cache;
 mod db;
 mod domain_nodes;
+mod domains;
 mod frps;
+mod frps_messages;
+mod frps_servers;
+mod geoip;
+mod identity;
 mod logging;
 mod models;
 mod node_services;
-#[path = "redis.rs"]
-mod redism;
+mod probes;
+mod reconcile;
+mod resolve;
 mod schema;
 
+use bans::*;
 use domain_nodes::*;
+use domains::*;
 use frps::*;
+use frps_servers::*;
 use node_services::*;
+use reconcile::*;
+use resolve::*;
 
 static NOTFOUND: &[u8] = b"Not Found";
 
 pub static NODE_LIVING_DURATION: u64 = 3 * 60;
 pub static CROSS_COMPARE_INTERVAL: u64 = 60;
 pub static CHECKING_NODES_HEALTH_DURATION: u64 = 60 * 60;
+pub static DOMAIN_GC_INTERVAL: u64 = 10 * 60;
 
 async fn health(_req: Request<IncomingBody>) -> Result<Response<BoxBody>> {
     Ok(Response::new(full(Bytes::from_static(b"ok"))))
 }
 
+// The connection pool usage, to tell when handlers queue up for the database
+async fn db_pool_stats(_req: Request<IncomingBody>) -> Result<Response<BoxBody>> {
+    let data = serde_json::json!({"code": 0, "msg": "OK", "data": db::store().pool_stats() });
+
+    let json = serde_json::to_string(&data)?;
+    let response = Response::builder()
+        .status(StatusCode::OK)
+        .header(hyper::header::CONTENT_TYPE, "application/json")
+        .body(full(json))?;
+    Ok(response)
+}
+
 async fn routers(req: Request<IncomingBody>) -> Result<Response<BoxBody>> {
     let response = match (req.method(), req.uri().path()) {
         (&Method::POST, path) if FRPS_PATH_RE.is_match(path) => handler(req).await,
         (&Method::POST, path) if DEVICE_API_PATH_RE.is_match(path) => device_api_handler(req).await,
+        (&Method::GET, "/inner/frps") => list_frps_servers(req).await,
         (&Method::GET, "/inner/nodes") => query_nodes(req).await,
         (&Method::GET, "/inner/living_nodes") => get_living_nodes(req).await,
+        (&Method::GET, "/inner/db_pool") => db_pool_stats(req).await,
+        (&Method::GET, "/inner/reconcile") => get_reconcile_stats(req).await,
+        (&Method::POST, "/inner/reconcile") => run_reconcile(req).await,
+        (&Method::POST, "/inner/gc_domains") => run_domain_gc(req).await,
         (&Method::GET, "/health-check") => health(req).await,
         (&Method::GET, "/domain_nodes") => get_domain_nodes(req).await,
         (&Method::PUT, "/domain_nodes") => create_domain_node(req).await,
         (&Method::DELETE, "/domain_nodes") => remove_domain_node(req).await,
+        (&Method::GET, "/domains") => get_domains(req).await,
+        (&Method::PUT, "/domains") => create_domains(req).await,
+        (&Method::DELETE, "/domains") => remove_domains(req).await,
+        (&Method::GET, path) if DOMAIN_RESOLVE_PATH_RE.is_match(path) => resolve_domain(req).await,
+        (&Method::GET, "/bans") => get_bans(req).await,
+        (&Method::PUT, "/bans") => create_bans(req).await,
+        (&Method::DELETE, "/bans") => remove_bans(req).await,
+        (&Method::GET, "/device_keys") => get_device_keys(req).await,
+        (&Method::PUT, "/device_keys") => approve_device_keys(req).await,
         _ => Ok(Response::builder()
             .status(StatusCode::NOT_FOUND)
             .body(full(NOTFOUND))
@@ -62,7 +102,7 @@ async fn close_expired_nodes(now: NaiveDateTime) {
     let expire_before = now
         .checked_sub_signed(chrono::Duration::seconds(NODE_LIVING_DURATION as i64))
         .unwrap();
-    match db::unavail_expired_nodes(&expire_before) {
+    match db::blocking(move || db::store().unavail_expired_nodes(&expire_before)).await {
         Ok(n) => {
             log::info!("Made {} expired nodes unavail", n);
         }
@@ -70,7 +110,7 @@ async fn close_expired_nodes(now: NaiveDateTime) {
             log::error!("Failed to unavail expired nodes: {:?}", err);
         }
     }
-    match db::close_expired_nodes(&expire_before) {
+    match db::blocking(move || db::store().close_expired_nodes(&expire_before)).await {
         Ok(n) => {
             log::info!("Closed {} expired nodes", n);
         }
@@ -81,25 +121,31 @@ async fn close_expired_nodes(now: NaiveDateTime) {
 }
 
 async fn check_nodes_health(_now: NaiveDateTime) {
-    #[cfg(feature = "sqlite")]
-    let mut earliest_login_time = chrono::DateTime::from_timestamp(0, 0)
-        .unwrap()
-        .naive_utc()
-        .and_utc()
-        .timestamp();
-    #[cfg(feature = "mysql")]
-    let mut earliest_login_time = chrono::DateTime::<chrono::Utc>::MIN_UTC.naive_utc();
+    let mut earliest_login_time = chrono::DateTime::UNIX_EPOCH.naive_utc();
 
     let least_lived_secs = 10;
     let page_size = 100;
-    let request_timeout_secs = 5;
     // Limit the number of concurrent tasks
     let semaphore = Arc::new(Semaphore::new(50));
 
+    let domain_probes = match db::blocking(probes::domain_probes).await {
+        Ok(domain_probes) => Arc::new(domain_probes),
+        Err(e) => {
+            log::error!("Failed to load the health probes of the domains: {}", e);
+            return;
+        }
+    };
+
     loop {
-        let nodes =
-            db::query_living_nodes_by_login_time(least_lived_secs, page_size, earliest_login_time);
-        if let Err(_) = nodes {
+        let nodes = db::blocking(move || {
+            db::store().query_living_nodes_by_login_time(
+                least_lived_secs,
+                page_size,
+                &earliest_login_time,
+            )
+        })
+        .await;
+        if nodes.is_err() {
             break;
         }
         let nodes = nodes.unwrap();
@@ -115,15 +161,55 @@ async fn check_nodes_health(_now: NaiveDateTime) {
 
         for node in nodes {
             let permit = semaphore.clone().acquire_owned().await.unwrap();
+            let domain_probes = domain_probes.clone();
             tokio::spawn(async move {
-                // Perform health check for the node
-                let is_healthy = determine_node_avail(&node, request_timeout_secs).await;
+                // Perform health check for the node with the probes of its domains
+                let node_id = node.node_id.clone();
+                let node_probes =
+                    db::blocking(move || probes::node_probes(&node_id, &domain_probes)).await;
+                let node_probes = match node_probes {
+                    Ok(node_probes) => node_probes,
+                    Err(e) => {
+                        log::error!(
+                            "Failed to load the health probes of {}: {}",
+                            node.node_id,
+                            e
+                        );
+                        return;
+                    }
+                };
+                let (verdict, latency_ms) = probes::probe_node(&node, &node_probes).await;
+                if verdict == probes::Verdict::Keep {
+                    log::debug!("Keep node {} as it is, its health is unknown", node.node_id);
+                    return;
+                }
+
+                // The response time of the healthy nodes scales their adaptive weights
+                let node_id = node.node_id.clone();
+                if let Err(e) = db::blocking(move || {
+                    db::store().update_node_probe_latency(&node_id, latency_ms)
+                })
+                .await
+                {
+                    log::error!(
+                        "Failed to record the health check of {}: {}",
+                        node.node_id,
+                        e
+                    );
+                }
+                let is_healthy = verdict == probes::Verdict::Healthy;
                 if !is_healthy && node.status == NODE_STATUS_ONLINE {
                     log::info!("Make node {} unavail because it is unhealthy", node.node_id);
-                    let _ = db::update_node_status(&node.node_id, NODE_STATUS_UNAVAIL);
+                    let _ = db::blocking(move || {
+                        db::store().update_node_status(&node.node_id, NODE_STATUS_UNAVAIL)
+                    })
+                    .await;
                 } else if is_healthy && node.status == NODE_STATUS_UNAVAIL {
                     log::info!("Make node {} avail because it is healthy", node.node_id);
-                    let _ = db::update_node_status(&node.node_id, NODE_STATUS_ONLINE);
+                    let _ = db::blocking(move || {
+                        db::store().update_node_status(&node.node_id, NODE_STATUS_ONLINE)
+                    })
+                    .await;
                 }
                 // Release the permit after the task is done
                 drop(permit);
@@ -136,71 +222,48 @@ async fn check_nodes_health(_now: NaiveDateTime) {
     }
 }
 
-// Determine the node health based on whether we can get successful response from the node
-async fn determine_node_avail(node: &models::LivingNode, timeout: u64) -> bool {
-    let client = reqwest::Client::new();
-
-    client
-        .post(format!("https://{}/v1/chat/completions", node.subdomain))
-        .header("Accept", "text/event-stream")
-        .json(&serde_json::json!({
-            "messages": [
-                {"role": "system", "content": "You are a helpful assistant."},
-                {"role": "user", "content": "Hello"},
-            ],
-            "model": node.chat_model,
-            "stream": true
-        }))
-        .timeout(std::time::Duration::from_secs(timeout))
-        .send()
-        .await
-        .map(|res| res.status().is_success())
-        .unwrap_or(true)
-}
-
-async fn cross_compare_domain_nodes(_now: NaiveDateTime) {
-    let start = chrono::Utc::now().naive_utc();
-
-    let domains = db::get_distinct_domains().unwrap();
-    for domain in domains {
-        let nodes = db::get_nodes_by_domain(&domain).unwrap();
-        let nodes_by_redis = redism::get_domain_nodes(&domain).unwrap();
-        for node in nodes.iter() {
-            if !nodes_by_redis.contains(&node) {
-                log::error!("Node {} not found in redis for domain {}", node.0, domain);
-                if let Err(e) = redism::nodes_join(&domain, &node.0, node.1) {
-                    log::error!(
-                        "Failed to add domain node to redis: {}. Error msg: {}",
-                        domain,
-                        e
-                    );
-                }
-            }
-        }
-        for node in nodes_by_redis.iter() {
-            if !nodes.contains(&node) {
-                log::error!("Node {} not found in db for domain {}", node.0, domain);
-                if let Err(e) = redism::node_lefts(&domain, &node.0, node.1) {
-                    log::error!(
-                        "Failed to del domain node in redis: {}. Error msg: {}",
-                        domain,
-                        e
-                    );
-                }
-            }
-        }
-    }
-    let end = chrono::Utc::now().naive_utc();
-    // Log the time cost
-    log::info!("Cross compare domain nodes finished in {:?}", end - start);
-}
-
 #[tokio::main]
 async fn main() -> Result<()> {
     // Trigger the args parsing
     let cluster = crate::args::ARGS.cluster;
 
     logging::configure_logging();
+    cache::check_runtime()?;
+    probes::default_probe()?;
+
+    let migrate_only = matches!(crate::args::ARGS.command, Some(args::Command::Migrate));
+    if migrate_only || !crate::args::ARGS.skip_migrations {
+        let version = db::store().run_migrations()?;
+        log::info!("Database schema at version {}", version);
+    }
+    if migrate_only {
+        return Ok(());
+    }
+
+    if let Some(args::Command::MigrateRedisKeys { from_prefix }) = &crate::args::ARGS.command {
+        let redis_url = env::var("REDIS_URL").map_err(|_| "No REDIS_URL in env")?;
+        let moved = db::blocking(move || {
+            let domains = db::store().get_distinct_domains()?;
+            let subdomains: Vec<String> = db::store()
+                .query_active_nodes()?
+                .into_iter()
+                .map(|node| node.subdomain)
+                .collect();
+            cache::RedisCache::new(&redis_url).move_keys_from(from_prefix, &domains, &subdomains)
+        })
+        .await?;
+        log::info!(
+            "Moved {} redis keys, kept {} already moved and skipped {} missing",
+            moved.moved,
+            moved.kept,
+            moved.missing
+        );
+        return Ok(());
+    }
+
+    if cluster && !cache::cache().is_shared() {
+        return Err("Running with --cluster needs a REDIS_URL shared by all the instances".into());
+    }
 
     let host = env::var("SERVER_HOST").expect("No SERVER_HOST in env");
     let port = env::var("SERVER_PORT").expect("No SERVER_PORT in env");
@@ -220,6 +283,16 @@ async fn main() -> Result<()> {
     )
     .await;
 
+    // Cronjob for closing the nodes of silent frps servers
+    cronjob(
+        CROSS_COMPARE_INTERVAL,
+        cluster,
+        String::from("silent_frps_lock"),
+        CROSS_COMPARE_INTERVAL,
+        close_silent_frps_nodes,
+    )
+    .await;
+
     // Cronjob for checking nodes health
     // Pass 1 min as the interval because the lock duration is long enough
     cronjob(
@@ -231,13 +304,33 @@ async fn main() -> Result<()> {
     )
     .await;
 
-    // Cronjob for cross-comparing domain nodes
+    // Cronjob for reconciling the routing of domains with the db
     cronjob(
         CROSS_COMPARE_INTERVAL,
         cluster,
         String::from("cross_compare_domain_nodes_lock"),
         CROSS_COMPARE_INTERVAL,
-        cross_compare_domain_nodes,
+        reconcile_domain_nodes,
+    )
+    .await;
+
+    // Cronjob for removing the drained nodes from their domains
+    cronjob(
+        DRAIN_CHECK_INTERVAL,
+        cluster,
+        String::from("drain_domain_nodes_lock"),
+        DRAIN_CHECK_INTERVAL,
+        drain_domain_nodes,
+    )
+    .await;
+
+    // Cronjob for dropping the routing of abandoned domains
+    cronjob(
+        DOMAIN_GC_INTERVAL,
+        cluster,
+        String::from("gc_domain_routing_lock"),
+        DOMAIN_GC_INTERVAL,
+        gc_domain_routing,
     )
     .await;
 
@@ -246,7 +339,7 @@ async fn main() -> Result<()> {
         let io = TokioIo::new(stream);
 
         tokio::task::spawn(async move {
-            let service = service_fn(move |req| routers(req));
+            let service = service_fn(routers);
             if let Err(err) = http1::Builder::new().serve_connection(io, service).await {
                 log::info!("Failed to serve connection: {:?}", err);
             }
@@ -271,25 +364,18 @@ async fn cronjob<F, Fut>(
             // Don't need redis if only run a single instance
             match cluster {
                 true => {
-                    match redism::establish_redis_conn() {
-                        Ok(mut conn) => {
-                            let opts = SetOptions::default()
-                                .conditional_set(ExistenceCheck::NX)
-                                .get(true)
-                                .with_expiration(SetExpiry::EX(lock_duration));
-
-                            // Use distributed redis lock to avoid duplicate work.
-                            // Set an expiration time for the lock to ensure that each attempt to acquire the lock will fail before the lock expires..
-                            if let Ok(None) = conn.set_options::<&str, String, Option<String>>(
-                                lock_key.as_str(),
-                                now.to_string(),
-                                opts,
-                            ) {
-                                work(now).await;
-                            }
-                        }
+                    // Use distributed redis lock to avoid duplicate work.
+                    // Set an expiration time for the lock to ensure that each attempt to acquire the lock will fail before the lock expires..
+                    let key = lock_key.clone();
+                    let locked = db::blocking(move || {
+                        cache::cache().acquire_lock(&key, &now.to_string(), lock_duration)
+                    })
+                    .await;
+                    match locked {
+                        Ok(true) => work(now).await,
+                        Ok(false) => {}
                         Err(e) => {
-                            panic!("Failed to establish redis connection: {:?}", e);
+                            log::error!("Failed to acquire the lock {}: {}", lock_key, e);
                         }
                     }
                 }
@@ -1,40 +1,11 @@
-use crate::schema::*;
-use diesel::prelude::*;
-
+use chrono::NaiveDateTime;
 use serde::Serialize;
-#[cfg(feature = "mysql")]
 use serde_json::Value;
 
-#[cfg(feature = "sqlite")]
-#[derive(Serialize, Queryable, Selectable)]
-#[diesel(table_name = crate::schema::node_status)]
-#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
-pub struct Node {
-    pub id: i32,
-    pub node_id: String,
-    pub device_id: String,
-    pub subdomain: String,
-    pub version: String,
-    pub arch: String,
-    pub os: String,
-    pub client_address: String,
-    pub login_time: i64,
-    pub last_active_time: i64,
-    pub last_avail_time: Option<i64>,
-    pub run_id: String,
-    pub meta: String,
-    pub node_version: String,
-    pub chat_model: String,
-    pub embedding_model: String,
-    pub status: String,
-    pub created_at: i64,
-    pub updated_at: i64,
-}
+// The records of the hub, independent of the storage backend.
+// Times are UTC and metas are JSON whatever the backend stores them as.
 
-#[cfg(feature = "mysql")]
-#[derive(Serialize, Queryable, Selectable)]
-#[diesel(table_name = crate::schema::node_status)]
-#[diesel(check_for_backend(diesel::mysql::Mysql))]
+#[derive(Debug, Clone, Serialize)]
 pub struct Node {
     pub id: i64,
     pub node_id: String,
@@ -44,23 +15,26 @@ pub struct Node {
     pub arch: String,
     pub os: String,
     pub client_address: String,
-    pub login_time: chrono::NaiveDateTime,
-    pub last_active_time: chrono::NaiveDateTime,
-    pub last_avail_time: Option<chrono::NaiveDateTime>,
+    pub login_time: NaiveDateTime,
+    pub last_active_time: NaiveDateTime,
+    pub last_avail_time: Option<NaiveDateTime>,
     pub run_id: String,
     pub meta: Value,
     pub node_version: String,
     pub chat_model: String,
     pub embedding_model: String,
     pub status: String,
-    pub created_at: chrono::NaiveDateTime,
-    pub updated_at: chrono::NaiveDateTime,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+    pub frps_id: String,
+    pub country: String,
+    pub subdivision: String,
+    pub city: String,
+    // The response time of the last successful health check
+    pub probe_latency_ms: Option<i64>,
 }
 
-#[cfg(feature = "sqlite")]
-#[derive(Serialize, Queryable, Selectable)]
-#[diesel(table_name = crate::schema::node_status)]
-#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+#[derive(Debug, Clone, Serialize)]
 pub struct NodeLimited {
     pub subdomain: String,
     pub node_id: String,
@@ -70,49 +44,52 @@ pub struct NodeLimited {
     pub embedding_model: String,
     pub device_id: String,
     pub client_address: String,
+    pub country: String,
+    pub subdivision: String,
+    pub city: String,
 }
 
-#[cfg(feature = "mysql")]
-#[derive(Serialize, Queryable, Selectable)]
-#[diesel(table_name = crate::schema::node_status)]
-#[diesel(check_for_backend(diesel::mysql::Mysql))]
-pub struct NodeLimited {
-    pub subdomain: String,
-    pub node_id: String,
-    pub status: String,
-    pub node_version: String,
-    pub chat_model: String,
-    pub embedding_model: String,
-    pub device_id: String,
-    pub client_address: String,
+impl From<Node> for NodeLimited {
+    fn from(node: Node) -> Self {
+        NodeLimited {
+            subdomain: node.subdomain,
+            node_id: node.node_id,
+            status: node.status,
+            node_version: node.node_version,
+            chat_model: node.chat_model,
+            embedding_model: node.embedding_model,
+            device_id: node.device_id,
+            client_address: node.client_address,
+            country: node.country,
+            subdivision: node.subdivision,
+            city: node.city,
+        }
+    }
 }
 
-#[cfg(feature = "sqlite")]
-#[derive(Serialize, Selectable, Queryable)]
-#[diesel(table_name = node_status)]
-#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+#[derive(Debug, Clone, Serialize)]
 pub struct LivingNode {
     pub node_id: String,
     pub subdomain: String,
     pub chat_model: String,
-    pub login_time: i64,
+    pub embedding_model: String,
+    pub login_time: NaiveDateTime,
     pub status: String,
 }
 
-#[cfg(feature = "mysql")]
-#[derive(Serialize, Selectable, Queryable)]
-#[diesel(table_name = node_status)]
-#[diesel(check_for_backend(diesel::mysql::Mysql))]
-pub struct LivingNode {
-    pub node_id: String,
-    pub subdomain: String,
-    pub chat_model: String,
-    pub login_time: chrono::NaiveDateTime,
-    pub status: String,
+impl From<Node> for LivingNode {
+    fn from(node: Node) -> Self {
+        LivingNode {
+            node_id: node.node_id,
+            subdomain: node.subdomain,
+            chat_model: node.chat_model,
+            embedding_model: node.embedding_model,
+            login_time: node.login_time,
+            status: node.status,
+        }
+    }
 }
 
-#[derive(Serialize, Insertable, AsChangeset)]
-#[diesel(table_name = node_status)]
 pub struct NewNode<'a> {
     pub node_id: &'a str,
     pub device_id: &'a str,
@@ -122,60 +99,14 @@ pub struct NewNode<'a> {
     pub os: &'a str,
     pub client_address: &'a str,
     pub status: &'a str,
-    #[cfg(feature = "sqlite")]
-    pub login_time: &'a i64,
-    #[cfg(feature = "mysql")]
-    pub login_time: &'a chrono::NaiveDateTime,
-    #[cfg(feature = "sqlite")]
-    pub last_active_time: &'a i64,
-    #[cfg(feature = "mysql")]
-    pub last_active_time: &'a chrono::NaiveDateTime,
+    pub login_time: &'a NaiveDateTime,
+    pub last_active_time: &'a NaiveDateTime,
     pub run_id: &'a str,
-    #[cfg(feature = "sqlite")]
-    pub meta: &'a str,
-    #[cfg(feature = "mysql")]
-    pub meta: &'a Value,
-}
-
-#[derive(Serialize, Insertable, AsChangeset)]
-#[diesel(table_name = devices)]
-pub struct NewDevice<'a> {
-    pub device_id: &'a str,
-    pub version: &'a str,
-    pub arch: &'a str,
-    pub os: &'a str,
-    pub client_address: &'a str,
-    #[cfg(feature = "sqlite")]
-    pub login_time: &'a i64,
-    #[cfg(feature = "mysql")]
-    pub login_time: &'a chrono::NaiveDateTime,
-    #[cfg(feature = "sqlite")]
-    pub meta: &'a str,
-    #[cfg(feature = "mysql")]
     pub meta: &'a Value,
+    pub frps_id: &'a str,
 }
 
-#[cfg(feature = "sqlite")]
-#[derive(Serialize, Queryable, Selectable)]
-#[diesel(table_name = crate::schema::devices)]
-#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
-pub struct Device {
-    pub id: i32,
-    pub device_id: String,
-    pub version: String,
-    pub arch: String,
-    pub os: String,
-    pub client_address: String,
-    pub login_time: i64,
-    pub meta: String,
-    pub created_at: i64,
-    pub updated_at: i64,
-}
-
-#[cfg(feature = "mysql")]
-#[derive(Serialize, Queryable, Selectable)]
-#[diesel(table_name = crate::schema::devices)]
-#[diesel(check_for_backend(diesel::mysql::Mysql))]
+#[derive(Debug, Clone, Serialize)]
 pub struct Device {
     pub id: i64,
     pub device_id: String,
@@ -183,27 +114,89 @@ pub struct Device {
     pub arch: String,
     pub os: String,
     pub client_address: String,
-    pub login_time: chrono::NaiveDateTime,
+    pub login_time: NaiveDateTime,
     pub meta: Value,
-    pub created_at: chrono::NaiveDateTime,
-    pub updated_at: chrono::NaiveDateTime,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+    pub public_key: String,
+    // A key offered by the device once known without one, bound only once approved
+    pub pending_public_key: String,
 }
 
-#[cfg(feature = "sqlite")]
-#[derive(Serialize, Insertable, AsChangeset, Queryable, Selectable)]
-#[diesel(table_name = crate::schema::domain_nodes)]
-#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
-pub struct DomainNodes {
-    pub domain: String,
-    pub node_id: String,
-    pub weight: i64,
+pub struct NewDevice<'a> {
+    pub device_id: &'a str,
+    pub version: &'a str,
+    pub arch: &'a str,
+    pub os: &'a str,
+    pub client_address: &'a str,
+    pub login_time: &'a NaiveDateTime,
+    pub meta: &'a Value,
+    pub public_key: &'a str,
 }
-#[cfg(feature = "mysql")]
-#[derive(Serialize, Insertable, AsChangeset, Queryable, Selectable)]
-#[diesel(table_name = crate::schema::domain_nodes)]
-#[diesel(check_for_backend(diesel::mysql::Mysql))]
+
+#[derive(Debug, Clone, Serialize)]
 pub struct DomainNodes {
     pub domain: String,
     pub node_id: String,
     pub weight: i64,
+    // Set while the node is drained out of the domain
+    pub draining_since: Option<NaiveDateTime>,
+    pub drain_deadline: Option<NaiveDateTime>,
+    // The last user connection seen while draining
+    pub last_user_conn_time: Option<NaiveDateTime>,
+}
+
+impl DomainNodes {
+    /// The weight the node is routed with, 0 while draining.
+    pub fn routed_weight(&self) -> i64 {
+        match self.draining_since {
+            Some(_) => 0,
+            None => self.weight,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Domain {
+    pub name: String,
+    pub owner: String,
+    pub description: String,
+    pub chat_model: String,
+    pub embedding_model: String,
+    pub status: String,
+    pub created_at: NaiveDateTime,
+    // The health probe of the nodes of the domain, the default one if none
+    pub health_probe: Option<Value>,
+    // The user connections allowed to each node of the domain per window, the default if none
+    pub user_conn_limit: Option<i64>,
+}
+
+pub struct NewDomain<'a> {
+    pub name: &'a str,
+    pub owner: &'a str,
+    pub description: &'a str,
+    pub chat_model: &'a str,
+    pub embedding_model: &'a str,
+    pub status: &'a str,
+    pub health_probe: Option<&'a Value>,
+    pub user_conn_limit: Option<i64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Ban {
+    pub id: i64,
+    pub kind: String,
+    pub value: String,
+    pub reason: String,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FrpsServer {
+    pub frps_id: String,
+    pub version: String,
+    pub region: String,
+    pub status: String,
+    pub last_seen_time: NaiveDateTime,
+    pub created_at: NaiveDateTime,
 }
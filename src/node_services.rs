@@ -1,12 +1,14 @@
 use bytes::{Buf, Bytes};
 use gaia_hub::*;
 use http_body_util::BodyExt;
-use hyper::{body::Incoming as IncomingBody, Request, Response};
+use hyper::{body::Incoming as IncomingBody, header, HeaderMap, Request, Response, StatusCode};
 use lazy_static::lazy_static;
-use log;
 use regex::Regex;
 
 use crate::db::*;
+use crate::domain_nodes::refresh_node_routing;
+use crate::geoip;
+use crate::identity;
 
 lazy_static! {
     pub(crate) static ref DEVICE_API_PATH_RE: Regex =
@@ -18,77 +20,129 @@ pub async fn device_api_handler(req: Request<IncomingBody>) -> Result<Response<B
     let captures = DEVICE_API_PATH_RE
         .captures(req.uri().path())
         .ok_or("Invalid path")?;
-    let path = captures.name("path").and_then(|m| Some(m.as_str()));
-    let device_id = captures.name("device_id").and_then(|m| Some(m.as_str()));
+    let path = captures.name("path").map(|m| m.as_str());
+    let device_id = captures.name("device_id").map(|m| m.as_str());
 
     let device_id = device_id.ok_or("Invalid device_id")?.to_string();
+    let path = path.map(|p| p.to_string());
 
-    match path {
-        Some("device-info") => device_info_handler(device_id, req).await,
-        Some("device-health") => device_health_handler(device_id, req).await,
+    let (parts, body) = req.into_parts();
+    // Aggregate the body...
+    let body = body.collect().await?.to_bytes();
+
+    let verified = {
+        let (device_id, body) = (device_id.clone(), body.clone());
+        blocking(move || verify_device_request(&device_id, &parts.headers, &body)).await?
+    };
+    if let Some(reason) = verified {
+        log::warn!("Unauthorized request of device {}: {}", device_id, reason);
+        let data = serde_json::json!({"code": 401, "msg": reason});
+        return Ok(Response::builder()
+            .status(StatusCode::UNAUTHORIZED)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(full(serde_json::to_string(&data)?))?);
+    }
+
+    match path.as_deref() {
+        Some("device-info") => device_info_handler(device_id, body).await,
+        Some("device-health") => device_health_handler(device_id, body).await,
         _ => Err("Invalid path".into()),
     }
 }
 
-async fn device_health_handler(
-    device_id: String,
-    req: Request<IncomingBody>,
-) -> Result<Response<BoxBody>> {
-    // Aggregate the body...
-    let whole_body = req.collect().await?.aggregate();
+// Devices which registered a public key must sign their calls with it, and every device
+// must once signatures are required. Returns the reason if the request should be refused.
+fn verify_device_request(
+    device_id: &str,
+    headers: &HeaderMap,
+    body: &[u8],
+) -> Result<Option<String>> {
+    let devices = store().query_device_by_device_id(device_id)?;
+    let public_key = match devices.first() {
+        Some(device) if !device.public_key.is_empty() => device.public_key.as_str(),
+        Some(_) if identity::signatures_required() => {
+            return Ok(Some(String::from("No approved device key")))
+        }
+        None if identity::signatures_required() => return Ok(Some(String::from("Unknown device"))),
+        _ => return Ok(None),
+    };
+
+    let timestamp = headers
+        .get(identity::TIMESTAMP_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<i64>().ok());
+    let signature = headers
+        .get(identity::SIGNATURE_HEADER)
+        .and_then(|v| v.to_str().ok());
+
+    let (timestamp, signature) = match (timestamp, signature) {
+        (Some(timestamp), Some(signature)) => (timestamp, signature),
+        _ => return Ok(Some(String::from("Missing device signature"))),
+    };
+    if !identity::is_fresh(timestamp) {
+        return Ok(Some(String::from("Expired device signature")));
+    }
+    let message = identity::api_message(device_id, timestamp, body);
+    if !identity::verify_signature(public_key, &message, signature) {
+        return Ok(Some(String::from("Invalid device signature")));
+    }
+    Ok(None)
+}
 
+async fn device_health_handler(device_id: String, body: Bytes) -> Result<Response<BoxBody>> {
     // Decode as JSON...
-    let device_health: serde_json::Value = serde_json::from_reader(whole_body.reader())?;
+    let device_health: serde_json::Value = serde_json::from_slice(&body)?;
 
     let health = device_health["health"]
         .as_bool()
         .ok_or("No health attribute")?;
 
-    let nodes = query_nodes_by_device_id(&device_id)?;
-    match health {
-        true => {
-            let now = chrono::Utc::now().naive_utc();
-            for node in nodes {
-                if node.status == NODE_STATUS_ONLINE {
-                    // Update the last avail time
-                    update_node_avail_time_and_status(&node.node_id, &now, NODE_STATUS_ONLINE)?;
-                } else if node.status == NODE_STATUS_UNAVAIL {
-                    // Reopen the avail node
-                    // while frpc is connected by checking last_active_time
-                    let active_after = now
-                        .checked_sub_signed(chrono::Duration::seconds(
-                            crate::NODE_LIVING_DURATION as i64,
-                        ))
-                        .unwrap();
-                    #[cfg(feature = "sqlite")]
-                    if node.last_active_time > active_after.and_utc().timestamp() {
-                        update_node_avail_time_and_status(&node.node_id, &now, NODE_STATUS_ONLINE)?;
-                    }
-                    #[cfg(feature = "mysql")]
-                    if node.last_active_time > active_after {
-                        update_node_avail_time_and_status(&node.node_id, &now, NODE_STATUS_ONLINE)?;
+    blocking(move || {
+        let nodes = store().query_nodes_by_device_id(&device_id)?;
+        match health {
+            true => {
+                let now = chrono::Utc::now().naive_utc();
+                for node in nodes {
+                    if node.status == NODE_STATUS_ONLINE {
+                        // Update the last avail time
+                        store().update_node_avail_time_and_status(
+                            &node.node_id,
+                            &now,
+                            NODE_STATUS_ONLINE,
+                        )?;
+                    } else if node.status == NODE_STATUS_UNAVAIL {
+                        // Reopen the avail node
+                        // while frpc is connected by checking last_active_time
+                        let active_after = now
+                            .checked_sub_signed(chrono::Duration::seconds(
+                                crate::NODE_LIVING_DURATION as i64,
+                            ))
+                            .unwrap();
+                        if node.last_active_time > active_after {
+                            store().update_node_avail_time_and_status(
+                                &node.node_id,
+                                &now,
+                                NODE_STATUS_ONLINE,
+                            )?;
+                        }
                     }
                 }
             }
+            false => {
+                // Unavail the nodes of device
+                store().update_nodes_status_by_device_id(&device_id, NODE_STATUS_UNAVAIL)?;
+            }
         }
-        false => {
-            // Unavail the nodes of device
-            update_nodes_status_by_device_id(&device_id, NODE_STATUS_UNAVAIL)?;
-        }
-    }
+        Ok(())
+    })
+    .await?;
 
     Ok(Response::new(crate::full(Bytes::from_static(b"ok"))))
 }
 
-async fn device_info_handler(
-    device_id: String,
-    req: Request<IncomingBody>,
-) -> Result<Response<BoxBody>> {
-    // Aggregate the body...
-    let whole_body = req.collect().await?.aggregate();
-
+async fn device_info_handler(device_id: String, body: Bytes) -> Result<Response<BoxBody>> {
     // Decode as JSON...
-    let node_info: serde_json::Value = serde_json::from_reader(whole_body.reader())?;
+    let node_info: serde_json::Value = serde_json::from_slice(&body)?;
 
     let node_version = node_info["node_version"]
         .as_str()
@@ -103,19 +157,143 @@ async fn device_info_handler(
         .ok_or("Missing embedding_model in node info")?
         .to_string();
 
-    update_nodes_info_by_device_id(
-        &device_id,
-        &node_version,
-        &chat_model_name,
-        &embedding_model_name,
-    )?;
-    log::info!(
-        "Updated nodes info of device {}: node_version: {}, chat model name: {}, embedding model name: {}",
-        device_id,
-        node_version,
-        chat_model_name,
-        embedding_model_name
-    );
+    // The self reported location is only used if the hub can't locate nodes by itself
+    let location = match !geoip::geoip_enabled() && node_info["location"].is_object() {
+        true => Some(serde_json::from_value::<geoip::Location>(
+            node_info["location"].clone(),
+        )?),
+        false => None,
+    };
+
+    blocking(move || {
+        store().update_nodes_info_by_device_id(
+            &device_id,
+            &node_version,
+            &chat_model_name,
+            &embedding_model_name,
+        )?;
+        log::info!(
+            "Updated nodes info of device {}: node_version: {}, chat model name: {}, embedding model name: {}",
+            device_id,
+            node_version,
+            chat_model_name,
+            embedding_model_name
+        );
+
+        if let Some(location) = location {
+            store().update_nodes_location_by_device_id(
+                &device_id,
+                &location.country,
+                &location.subdivision,
+                &location.city,
+            )?;
+        }
+
+        // Pull the nodes which no longer serve the models of their domain, or route them again
+        for node in store().query_nodes_by_device_id(&device_id)? {
+            if let Err(e) = refresh_node_routing(&node) {
+                log::error!(
+                    "Failed to refresh the routing of node {}. Error msg: {}",
+                    node.node_id,
+                    e
+                );
+            }
+        }
+        Ok(())
+    })
+    .await?;
 
     Ok(Response::new(crate::full(Bytes::from_static(b"ok"))))
 }
+
+#[derive(Debug, serde::Deserialize)]
+struct DeviceKeyItem {
+    device_id: String,
+    // The key waiting for approval if none
+    #[serde(default)]
+    public_key: Option<String>,
+}
+
+#[derive(Debug, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+enum DeviceKeyResultCode {
+    Approved,
+    DeviceNotExist,
+    NoPendingKey,
+    InvalidKey,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct DeviceKeyResult {
+    device_id: String,
+    code: DeviceKeyResultCode,
+}
+
+/// The keys offered by known devices, waiting for approval.
+pub async fn get_device_keys(_req: Request<IncomingBody>) -> Result<Response<BoxBody>> {
+    let devices = blocking(|| store().query_devices_with_pending_key()).await?;
+    let keys: Vec<serde_json::Value> = devices
+        .into_iter()
+        .map(|device| {
+            serde_json::json!({
+                "device_id": device.device_id,
+                "public_key": device.public_key,
+                "pending_public_key": device.pending_public_key,
+            })
+        })
+        .collect();
+
+    let data = serde_json::json!({"code": 0, "msg": "OK", "data": keys });
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(full(serde_json::to_string(&data)?))?)
+}
+
+/// Bind the given keys, or the ones waiting for approval, to the devices.
+pub async fn approve_device_keys(req: Request<IncomingBody>) -> Result<Response<BoxBody>> {
+    let whole_body = req.collect().await?.aggregate();
+    let items: Vec<DeviceKeyItem> = match serde_json::from_reader(whole_body.reader()) {
+        Ok(data) => data,
+        Err(e) => {
+            return Ok(Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(full(format!("Invalid JSON: {}", e)))?)
+        }
+    };
+
+    let results = blocking(move || {
+        let mut results = vec![];
+
+        for item in items {
+            let devices = store().query_device_by_device_id(&item.device_id)?;
+            let code = match (devices.first(), item.public_key.as_deref()) {
+                (None, _) => DeviceKeyResultCode::DeviceNotExist,
+                (Some(device), None) if device.pending_public_key.is_empty() => {
+                    DeviceKeyResultCode::NoPendingKey
+                }
+                (Some(device), public_key) => {
+                    let public_key = public_key.unwrap_or(&device.pending_public_key);
+                    if identity::is_valid_public_key(public_key) {
+                        store().update_device_public_key(&item.device_id, public_key)?;
+                        log::info!("Approved public key of device {}", item.device_id);
+                        DeviceKeyResultCode::Approved
+                    } else {
+                        DeviceKeyResultCode::InvalidKey
+                    }
+                }
+            };
+            results.push(DeviceKeyResult {
+                device_id: item.device_id,
+                code,
+            });
+        }
+        Ok(results)
+    })
+    .await?;
+
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(full(serde_json::to_string(&results)?))?)
+}
@@ -0,0 +1,333 @@
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::env;
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+
+use crate::db::*;
+use crate::models;
+use gaia_hub::*;
+
+lazy_static! {
+    // The probe of the nodes outside of any domain or in domains without one
+    static ref HEALTH_PROBE: ProbeConfig =
+        default_probe().expect("HEALTH_PROBE must be a valid probe config");
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProbeKind {
+    // GET /v1/models
+    #[default]
+    ModelsList,
+    // POST /v1/embeddings with the embedding model of the node
+    Embeddings,
+    // POST /v1/chat/completions with the chat model of the node, for a single token
+    Chat,
+    // Connect to the port of the node
+    Tcp,
+    // GET the path of the node
+    Http,
+}
+
+/// What a probe tells about the node, or what it is taken to tell when the node doesn't answer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Verdict {
+    Healthy,
+    Unhealthy,
+    // Leave the node as it is
+    Keep,
+}
+
+/// How to check the health of a node.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProbeConfig {
+    pub kind: ProbeKind,
+    // The path requested instead of the one of the kind, required by `http`
+    pub path: Option<String>,
+    // The port connected by `tcp`, not 0
+    pub port: u16,
+    // The status of a healthy answer, any 2xx if none
+    pub expect_status: Option<u16>,
+    // A text the body of a healthy answer contains, any body if empty
+    pub expect_body: String,
+    pub timeout_secs: u64,
+    // The verdict when the node doesn't answer in time
+    pub on_timeout: Verdict,
+    // The verdict when the node can't be reached
+    pub on_connect_error: Verdict,
+}
+
+impl Default for ProbeConfig {
+    fn default() -> Self {
+        ProbeConfig {
+            kind: ProbeKind::default(),
+            path: None,
+            port: 443,
+            expect_status: None,
+            expect_body: String::new(),
+            timeout_secs: 5,
+            on_timeout: Verdict::Unhealthy,
+            // A node nothing can reach serves no user either
+            on_connect_error: Verdict::Unhealthy,
+        }
+    }
+}
+
+impl ProbeConfig {
+    /// The probe described by the json, None if it isn't one.
+    pub fn parse(value: &Value) -> Option<ProbeConfig> {
+        let probe = ProbeConfig::deserialize(value).ok()?;
+        match (probe.kind, &probe.path) {
+            (ProbeKind::Http, None) => None,
+            (_, Some(path)) if !path.starts_with('/') => None,
+            _ if probe.timeout_secs == 0 || probe.port == 0 => None,
+            _ => Some(probe),
+        }
+    }
+}
+
+/// The probe of `HEALTH_PROBE`, checked like the ones of the domains, the default one if unset.
+pub fn default_probe() -> Result<ProbeConfig> {
+    let Ok(probe) = env::var("HEALTH_PROBE") else {
+        return Ok(ProbeConfig::default());
+    };
+    let value: Value = serde_json::from_str(&probe)?;
+    ProbeConfig::parse(&value).ok_or_else(|| format!("Invalid HEALTH_PROBE {}", probe).into())
+}
+
+/// The probe of each domain having one.
+pub fn domain_probes() -> Result<HashMap<String, ProbeConfig>> {
+    let mut probes = HashMap::new();
+    for domain in store().query_domains(None)? {
+        let Some(value) = domain.health_probe.as_ref() else {
+            continue;
+        };
+        match ProbeConfig::parse(value) {
+            Some(probe) => {
+                probes.insert(domain.name, probe);
+            }
+            None => log::warn!("Ignored the invalid health probe of domain {}", domain.name),
+        }
+    }
+    Ok(probes)
+}
+
+/// The distinct probes of the domains of the node, the default one for a domain without any
+/// or a node outside of any domain.
+pub fn node_probes(
+    node_id: &str,
+    domain_probes: &HashMap<String, ProbeConfig>,
+) -> Result<Vec<ProbeConfig>> {
+    let mut probes: Vec<ProbeConfig> = vec![];
+    for domain_node in store().query_domain_nodes_by_node_id(node_id)? {
+        let probe = domain_probes
+            .get(&domain_node.domain)
+            .unwrap_or(&HEALTH_PROBE);
+        if !probes.contains(probe) {
+            probes.push(probe.clone());
+        }
+    }
+    if probes.is_empty() {
+        probes.push(HEALTH_PROBE.clone());
+    }
+    Ok(probes)
+}
+
+// The verdict of a transport error by the policy of the probe
+fn error_verdict(probe: &ProbeConfig, e: &reqwest::Error) -> Verdict {
+    match e.is_timeout() {
+        true => probe.on_timeout,
+        false => probe.on_connect_error,
+    }
+}
+
+async fn probe_http(probe: &ProbeConfig, node: &models::LivingNode) -> Verdict {
+    let default_path = match probe.kind {
+        ProbeKind::ModelsList => "/v1/models",
+        ProbeKind::Embeddings => "/v1/embeddings",
+        ProbeKind::Chat => "/v1/chat/completions",
+        ProbeKind::Tcp | ProbeKind::Http => "/",
+    };
+    let url = format!(
+        "https://{}{}",
+        node.subdomain,
+        probe.path.as_deref().unwrap_or(default_path)
+    );
+
+    let client = reqwest::Client::new();
+    let request = match probe.kind {
+        ProbeKind::Embeddings => client.post(url).json(&serde_json::json!({
+            "model": node.embedding_model,
+            "input": ["Hello"],
+        })),
+        ProbeKind::Chat => client.post(url).json(&serde_json::json!({
+            "messages": [{"role": "user", "content": "Hello"}],
+            "model": node.chat_model,
+            "max_tokens": 1,
+            "stream": false,
+        })),
+        _ => client.get(url),
+    };
+    let response = match request
+        .timeout(Duration::from_secs(probe.timeout_secs))
+        .send()
+        .await
+    {
+        Ok(response) => response,
+        Err(e) => return error_verdict(probe, &e),
+    };
+
+    let status = response.status();
+    let status_matches = match probe.expect_status {
+        Some(expected) => status.as_u16() == expected,
+        None => status.is_success(),
+    };
+    if !status_matches {
+        return Verdict::Unhealthy;
+    }
+    if probe.expect_body.is_empty() {
+        return Verdict::Healthy;
+    }
+    match response.text().await {
+        Ok(body) if body.contains(&probe.expect_body) => Verdict::Healthy,
+        Ok(_) => Verdict::Unhealthy,
+        Err(e) => error_verdict(probe, &e),
+    }
+}
+
+async fn probe_tcp(probe: &ProbeConfig, node: &models::LivingNode) -> Verdict {
+    let connect = TcpStream::connect((node.subdomain.as_str(), probe.port));
+    match tokio::time::timeout(Duration::from_secs(probe.timeout_secs), connect).await {
+        Ok(Ok(_)) => Verdict::Healthy,
+        Ok(Err(_)) => probe.on_connect_error,
+        Err(_) => probe.on_timeout,
+    }
+}
+
+/// Run the probes against the node: it is unhealthy if any probe says so, else kept as it is
+/// if any probe can't tell, else healthy. The latency is the one of the slowest probe.
+pub async fn probe_node(
+    node: &models::LivingNode,
+    probes: &[ProbeConfig],
+) -> (Verdict, Option<i64>) {
+    let mut verdict = Verdict::Healthy;
+    let mut latency_ms = 0;
+    for probe in probes {
+        let started = Instant::now();
+        let probed = match probe.kind {
+            ProbeKind::Tcp => probe_tcp(probe, node).await,
+            _ => probe_http(probe, node).await,
+        };
+        latency_ms = latency_ms.max(started.elapsed().as_millis() as i64);
+        match probed {
+            Verdict::Unhealthy => return (Verdict::Unhealthy, None),
+            Verdict::Keep => verdict = Verdict::Keep,
+            Verdict::Healthy => {}
+        }
+    }
+    match verdict {
+        Verdict::Healthy => (Verdict::Healthy, Some(latency_ms)),
+        _ => (verdict, None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use tokio::net::TcpListener;
+
+    fn node(subdomain: &str) -> models::LivingNode {
+        models::LivingNode {
+            node_id: String::from("n1"),
+            subdomain: subdomain.to_string(),
+            chat_model: String::new(),
+            embedding_model: String::new(),
+            login_time: chrono::DateTime::from_timestamp(0, 0).unwrap().naive_utc(),
+            status: String::from("online"),
+        }
+    }
+
+    fn probe(value: Value) -> ProbeConfig {
+        ProbeConfig::parse(&value).unwrap()
+    }
+
+    // A local port nothing listens on
+    async fn closed_port() -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        listener.local_addr().unwrap().port()
+    }
+
+    #[test]
+    fn parses_probes_with_their_defaults() {
+        assert_eq!(probe(json!({})), ProbeConfig::default());
+        let tcp = probe(json!({"kind": "tcp", "port": 22, "on_connect_error": "keep"}));
+        assert_eq!(tcp.kind, ProbeKind::Tcp);
+        assert_eq!(tcp.port, 22);
+        assert_eq!(tcp.on_timeout, Verdict::Unhealthy);
+        assert_eq!(tcp.on_connect_error, Verdict::Keep);
+        let http = probe(json!({"kind": "http", "path": "/health", "expect_status": 204}));
+        assert_eq!(http.path.as_deref(), Some("/health"));
+        assert_eq!(http.expect_status, Some(204));
+        assert_eq!(http.on_connect_error, Verdict::Unhealthy);
+    }
+
+    #[test]
+    fn refuses_invalid_probes() {
+        for value in [
+            json!({"kind": "http"}),
+            json!({"kind": "http", "path": "health"}),
+            json!({"path": ""}),
+            json!({"timeout_secs": 0}),
+            json!({"kind": "tcp", "port": 0}),
+            json!({"kind": "tcp", "port": 70000}),
+            json!({"kind": "ping"}),
+            json!({"on_timeout": "retry"}),
+            json!({"timeout": 5}),
+            json!("models_list"),
+        ] {
+            assert!(ProbeConfig::parse(&value).is_none(), "{}", value);
+        }
+    }
+
+    #[tokio::test]
+    async fn tcp_probes_connect_to_the_port() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let open = probe(json!({"kind": "tcp", "port": listener.local_addr().unwrap().port()}));
+        assert_eq!(probe_tcp(&open, &node("127.0.0.1")).await, Verdict::Healthy);
+
+        let port = closed_port().await;
+        for verdict in [Verdict::Unhealthy, Verdict::Keep, Verdict::Healthy] {
+            let closed = probe(json!({"kind": "tcp", "port": port, "on_connect_error": verdict}));
+            assert_eq!(probe_tcp(&closed, &node("127.0.0.1")).await, verdict);
+        }
+    }
+
+    #[tokio::test]
+    async fn http_probes_follow_the_connect_error_policy() {
+        let subdomain = format!("127.0.0.1:{}", closed_port().await);
+        for verdict in [Verdict::Unhealthy, Verdict::Keep] {
+            let closed = probe(json!({"on_connect_error": verdict, "on_timeout": "healthy"}));
+            assert_eq!(probe_http(&closed, &node(&subdomain)).await, verdict);
+        }
+    }
+
+    #[tokio::test]
+    async fn http_probes_follow_the_timeout_policy() {
+        // Takes the connections without ever answering
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let subdomain = listener.local_addr().unwrap().to_string();
+        for verdict in [Verdict::Keep, Verdict::Healthy] {
+            let silent = probe(json!({"timeout_secs": 1, "on_timeout": verdict}));
+            assert_eq!(probe_http(&silent, &node(&subdomain)).await, verdict);
+        }
+        let (verdict, latency_ms) =
+            probe_node(&node(&subdomain), &[probe(json!({"timeout_secs": 1}))]).await;
+        assert_eq!((verdict, latency_ms), (Verdict::Unhealthy, None));
+    }
+}
@@ -0,0 +1,405 @@
+use chrono::NaiveDateTime;
+use hyper::{body::Incoming as IncomingBody, header, Request, Response, StatusCode};
+use lazy_static::lazy_static;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+
+use crate::adaptive::{adaptive_weights, is_health_change};
+use crate::cache::{cache, NodeChange};
+use crate::db::*;
+use crate::domains::routable_domain_nodes;
+use gaia_hub::*;
+
+lazy_static! {
+    static ref STATS: Mutex<ReconcileStats> = Mutex::new(ReconcileStats::default());
+}
+
+/// The changes bringing the routing of a domain in the cache to its online nodes in the db.
+#[derive(Debug, Clone, Serialize)]
+pub struct DomainPatch {
+    domain: String,
+    changes: Vec<NodeChange>,
+    // The changes skipped because the node moved in the cache while reconciling
+    conflicts: usize,
+    // The reweights which only follow the health of the nodes in adaptive mode, not a drift
+    adapted: usize,
+}
+
+/// What a reconciliation run found and did.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ReconcileReport {
+    started_at: Option<NaiveDateTime>,
+    duration_ms: u64,
+    dry_run: bool,
+    domains: usize,
+    drifted_domains: usize,
+    failed_domains: usize,
+    added: usize,
+    reweighted: usize,
+    removed: usize,
+    conflicts: usize,
+    adapted: usize,
+    patches: Vec<DomainPatch>,
+}
+
+// The drift found by the runs applied on this hub instance since it started
+#[derive(Debug, Clone, Default, Serialize)]
+struct DriftTotals {
+    runs: u64,
+    drifted_domains: u64,
+    failed_domains: u64,
+    added: u64,
+    reweighted: u64,
+    removed: u64,
+    conflicts: u64,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+struct ReconcileStats {
+    last: Option<ReconcileReport>,
+    totals: DriftTotals,
+    // The weights adapted to the health of the nodes since it started
+    adapted: u64,
+}
+
+/// The minimal changes turning the `cached` routing into the `wanted` one.
+pub fn diff(wanted: &[(String, i64)], cached: &[(String, i64)]) -> Vec<NodeChange> {
+    let cached: HashMap<&str, i64> = cached.iter().map(|(id, w)| (id.as_str(), *w)).collect();
+    let wanted_ids: HashMap<&str, i64> = wanted.iter().map(|(id, w)| (id.as_str(), *w)).collect();
+
+    let mut changes: Vec<NodeChange> = wanted_ids
+        .iter()
+        .filter(|(id, weight)| cached.get(*id) != Some(*weight))
+        .map(|(id, weight)| NodeChange {
+            node_id: id.to_string(),
+            from: cached.get(id).copied(),
+            to: Some(*weight),
+        })
+        .chain(
+            cached
+                .iter()
+                .filter(|(id, _)| !wanted_ids.contains_key(*id))
+                .map(|(id, weight)| NodeChange {
+                    node_id: id.to_string(),
+                    from: Some(*weight),
+                    to: None,
+                }),
+        )
+        .collect();
+    changes.sort_by(|a, b| a.node_id.cmp(&b.node_id));
+    changes
+}
+
+/// Bring the routing of the domain to its routable nodes.
+// Read the cache before the db: a node moving in between is then either seen at its new
+// state in the db, or left alone by the patch since it no longer matches `from`
+pub fn reconcile_domain(domain: &str, dry_run: bool) -> Result<DomainPatch> {
+    let cached = cache().get_domain_nodes(domain)?;
+    let wanted = routable_domain_nodes(domain)?;
+    let changes = diff(&wanted, &cached);
+    let adapted = match adaptive_weights() {
+        false => 0,
+        true => count_health_changes(domain, &changes)?,
+    };
+
+    let conflicts = match changes.is_empty() || dry_run {
+        true => 0,
+        false => changes.len() - cache().patch_domain_nodes(domain, &changes)?,
+    };
+    Ok(DomainPatch {
+        domain: domain.to_string(),
+        changes,
+        conflicts,
+        adapted,
+    })
+}
+
+// The reweights moving a node between the weights its health gives to its configured weight
+fn count_health_changes(domain: &str, changes: &[NodeChange]) -> Result<usize> {
+    let weights: HashMap<String, i64> = store().get_nodes_by_domain(domain)?.into_iter().collect();
+    Ok(changes
+        .iter()
+        .filter(
+            |change| match (weights.get(&change.node_id), change.from, change.to) {
+                (Some(weight), Some(from), Some(to)) => is_health_change(*weight, from, to),
+                _ => false,
+            },
+        )
+        .count())
+}
+
+/// Bring the routing of every domain in the cache to the online nodes in the db, or with
+/// `dry_run` only tell the changes it would take.
+pub fn reconcile(dry_run: bool) -> Result<ReconcileReport> {
+    let started_at = chrono::Utc::now().naive_utc();
+    let mut report = ReconcileReport {
+        started_at: Some(started_at),
+        dry_run,
+        ..Default::default()
+    };
+
+    let domains = store().get_distinct_domains()?;
+    report.domains = domains.len();
+    for domain in domains {
+        let patch = match reconcile_domain(&domain, dry_run) {
+            Ok(patch) => patch,
+            Err(e) => {
+                log::error!("Failed to reconcile domain {}: {}", domain, e);
+                report.failed_domains += 1;
+                continue;
+            }
+        };
+        if patch.changes.is_empty() {
+            continue;
+        }
+        for change in patch.changes.iter() {
+            match (change.from, change.to) {
+                (None, _) => report.added += 1,
+                (_, None) => report.removed += 1,
+                _ => report.reweighted += 1,
+            }
+        }
+        report.reweighted -= patch.adapted;
+        report.adapted += patch.adapted;
+        if patch.changes.len() > patch.adapted {
+            report.drifted_domains += 1;
+        }
+        report.conflicts += patch.conflicts;
+        report.patches.push(patch);
+    }
+
+    let elapsed = chrono::Utc::now().naive_utc() - started_at;
+    report.duration_ms = elapsed.num_milliseconds().max(0) as u64;
+
+    if !dry_run {
+        let mut stats = STATS.lock().unwrap();
+        let totals = &mut stats.totals;
+        totals.runs += 1;
+        totals.drifted_domains += report.drifted_domains as u64;
+        totals.failed_domains += report.failed_domains as u64;
+        totals.added += report.added as u64;
+        totals.reweighted += report.reweighted as u64;
+        totals.removed += report.removed as u64;
+        totals.conflicts += report.conflicts as u64;
+        stats.adapted += report.adapted as u64;
+        stats.last = Some(report.clone());
+    }
+    Ok(report)
+}
+
+pub async fn reconcile_domain_nodes(_now: NaiveDateTime) {
+    match blocking(|| reconcile(false)).await {
+        Ok(report) => {
+            for patch in report.patches.iter() {
+                if patch.adapted > 0 {
+                    log::info!(
+                        "Adapted the weights of {} nodes of domain {} to their health",
+                        patch.adapted,
+                        patch.domain
+                    );
+                }
+                let drifted = patch.changes.len() - patch.adapted;
+                if drifted > 0 {
+                    log::warn!(
+                        "Repaired {} drifted nodes of domain {}, {} changed meanwhile",
+                        drifted,
+                        patch.domain,
+                        patch.conflicts
+                    );
+                }
+            }
+            log::info!(
+                "Reconciled {} domains in {}ms, {} drifted",
+                report.domains,
+                report.duration_ms,
+                report.drifted_domains
+            );
+        }
+        Err(e) => {
+            log::error!("Failed to reconcile domain nodes: {}", e);
+        }
+    }
+}
+
+/// Drop the routing of the domains left in the cache without any node in the db, or with
+/// `dry_run` only tell them.
+pub fn collect_abandoned_domains(dry_run: bool) -> Result<Vec<String>> {
+    // Read the cache before the db, so a domain created meanwhile is found in the db.
+    // One created after reading the db may be dropped, then restored by the next reconciliation.
+    let routed = cache().routed_domains()?;
+    let known: HashSet<String> = store().get_distinct_domains()?.into_iter().collect();
+
+    let abandoned: Vec<String> = routed
+        .into_iter()
+        .filter(|domain| !known.contains(domain))
+        .collect();
+    if !dry_run {
+        for domain in abandoned.iter() {
+            cache().drop_domain(domain)?;
+        }
+    }
+    Ok(abandoned)
+}
+
+pub async fn gc_domain_routing(_now: NaiveDateTime) {
+    match blocking(|| collect_abandoned_domains(false)).await {
+        Ok(dropped) if dropped.is_empty() => {}
+        Ok(dropped) => {
+            log::warn!("Dropped the routing of abandoned domains: {:?}", dropped);
+        }
+        Err(e) => {
+            log::error!("Failed to collect abandoned domains: {}", e);
+        }
+    }
+}
+
+fn build_response(status: StatusCode, data: &serde_json::Value) -> Result<Response<BoxBody>> {
+    let json = serde_json::to_string(data)?;
+    let response = Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(full(json))?;
+    Ok(response)
+}
+
+/// The last reconciliation run on this hub instance and the drift found since it started.
+pub async fn get_reconcile_stats(_req: Request<IncomingBody>) -> Result<Response<BoxBody>> {
+    let stats = STATS.lock().unwrap().clone();
+    build_response(
+        StatusCode::OK,
+        &serde_json::json!({"code": 0, "msg": "OK", "data": stats}),
+    )
+}
+
+// The `dry_run` query param, false by default
+fn dry_run_param(req: &Request<IncomingBody>) -> Option<bool> {
+    let query = req.uri().query().unwrap_or("");
+    let params: HashMap<_, _> = form_urlencoded::parse(query.as_bytes())
+        .into_owned()
+        .collect();
+    match params.get("dry_run").map(|v| v.as_str()) {
+        None | Some("false") => Some(false),
+        Some("true") => Some(true),
+        Some(_) => None,
+    }
+}
+
+fn invalid_dry_run() -> Result<Response<BoxBody>> {
+    build_response(
+        StatusCode::BAD_REQUEST,
+        &serde_json::json!({"code": 400, "msg": "dry_run must be true or false"}),
+    )
+}
+
+/// Run a reconciliation now, only reporting the patches with `dry_run=true`.
+pub async fn run_reconcile(req: Request<IncomingBody>) -> Result<Response<BoxBody>> {
+    let Some(dry_run) = dry_run_param(&req) else {
+        return invalid_dry_run();
+    };
+
+    let report = blocking(move || reconcile(dry_run)).await?;
+    build_response(
+        StatusCode::OK,
+        &serde_json::json!({"code": 0, "msg": "OK", "data": report}),
+    )
+}
+
+/// Drop the routing of the abandoned domains now, only listing them with `dry_run=true`.
+pub async fn run_domain_gc(req: Request<IncomingBody>) -> Result<Response<BoxBody>> {
+    let Some(dry_run) = dry_run_param(&req) else {
+        return invalid_dry_run();
+    };
+
+    let domains = blocking(move || collect_abandoned_domains(dry_run)).await?;
+    build_response(
+        StatusCode::OK,
+        &serde_json::json!({"code": 0, "msg": "OK", "data": {"dry_run": dry_run, "domains": domains}}),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::{add_node, domain_node};
+
+    fn routing(weights: &[(&str, i64)]) -> Vec<(String, i64)> {
+        weights
+            .iter()
+            .map(|(node_id, weight)| (node_id.to_string(), *weight))
+            .collect()
+    }
+
+    fn change(node_id: &str, from: Option<i64>, to: Option<i64>) -> NodeChange {
+        NodeChange {
+            node_id: node_id.to_string(),
+            from,
+            to,
+        }
+    }
+
+    #[test]
+    fn diff_adds_reweights_and_removes_nodes() {
+        let wanted = routing(&[("n1", 3), ("n2", 5), ("n4", 1)]);
+        let cached = routing(&[("n1", 3), ("n2", 2), ("n3", 4)]);
+        assert_eq!(
+            diff(&wanted, &cached),
+            [
+                change("n2", Some(2), Some(5)),
+                change("n3", Some(4), None),
+                change("n4", None, Some(1)),
+            ]
+        );
+    }
+
+    #[test]
+    fn diff_leaves_matching_routing_alone() {
+        let weights = routing(&[("n1", 3), ("n2", 0)]);
+        assert!(diff(&weights, &weights).is_empty());
+        assert!(diff(&[], &[]).is_empty());
+    }
+
+    #[test]
+    fn diff_keeps_draining_nodes_at_weight_0() {
+        let wanted = routing(&[("n1", 3), ("n2", 0)]);
+        let cached = routing(&[("n1", 3), ("n2", 4)]);
+        assert_eq!(diff(&wanted, &cached), [change("n2", Some(4), Some(0))]);
+
+        let joining = routing(&[("n1", 3)]);
+        assert_eq!(diff(&wanted, &joining), [change("n2", None, Some(0))]);
+    }
+
+    #[test]
+    fn dry_runs_write_nothing() {
+        let domain = "reconcile-dry-run";
+        add_node(store(), "reconcile-n1", NODE_STATUS_ONLINE);
+        add_node(store(), "reconcile-n2", NODE_STATUS_ONLINE);
+        store()
+            .apply_domain_node_changes(&[
+                DomainNodeChange::Insert(domain_node(domain, "reconcile-n1", 3)),
+                DomainNodeChange::Insert(domain_node(domain, "reconcile-n2", 2)),
+            ])
+            .unwrap();
+        cache().nodes_join(domain, "reconcile-n2", 5).unwrap();
+        cache().nodes_join(domain, "reconcile-n3", 1).unwrap();
+        let cached = cache().get_domain_nodes(domain).unwrap();
+        let last = STATS.lock().unwrap().last.clone().map(|r| r.started_at);
+
+        let patch = reconcile_domain(domain, true).unwrap();
+        assert_eq!(patch.changes.len(), 3);
+        assert_eq!(patch.conflicts, 0);
+        let report = reconcile(true).unwrap();
+        assert!(report.dry_run);
+        assert!(report.patches.iter().any(|p| p.domain == domain));
+        assert_eq!(cache().get_domain_nodes(domain).unwrap(), cached);
+        assert_eq!(
+            STATS.lock().unwrap().last.clone().map(|r| r.started_at),
+            last
+        );
+
+        reconcile_domain(domain, false).unwrap();
+        let mut routed = cache().get_domain_nodes(domain).unwrap();
+        routed.sort();
+        assert_eq!(routed, routing(&[("reconcile-n1", 3), ("reconcile-n2", 2)]));
+    }
+}
@@ -0,0 +1,243 @@
+use hyper::{body::Incoming as IncomingBody, header, Request, Response, StatusCode};
+use lazy_static::lazy_static;
+use rand::Rng;
+use regex::Regex;
+use std::collections::HashMap;
+
+use crate::cache::cache;
+use crate::db::*;
+use gaia_hub::*;
+
+lazy_static! {
+    pub(crate) static ref DOMAIN_RESOLVE_PATH_RE: Regex =
+        Regex::new(r"^/domains/(?<domain>[\w\-]+)/resolve$").unwrap();
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+struct ResolvedNode {
+    node_id: String,
+    subdomain: String,
+    weight: i64,
+}
+
+fn build_response(status: StatusCode, data: &serde_json::Value) -> Result<Response<BoxBody>> {
+    let json = serde_json::to_string(data)?;
+    let response = Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(full(json))?;
+    Ok(response)
+}
+
+/// Pick one online node of the domain, by weighted random or, given a `hash`, by the
+/// weighted rendezvous of the hash with every node, so the same hash keeps the same node.
+pub async fn resolve_domain(req: Request<IncomingBody>) -> Result<Response<BoxBody>> {
+    let domain = DOMAIN_RESOLVE_PATH_RE
+        .captures(req.uri().path())
+        .and_then(|caps| caps.name("domain"))
+        .ok_or("Invalid path")?
+        .as_str()
+        .to_lowercase();
+    let query = req.uri().query().unwrap_or("");
+    let params: HashMap<_, _> = form_urlencoded::parse(query.as_bytes())
+        .into_owned()
+        .collect();
+    let hash = params.get("hash").filter(|hash| !hash.is_empty()).cloned();
+
+    let candidates = blocking(move || routable_nodes(&domain)).await?;
+
+    let node = match hash {
+        Some(hash) => pick_sticky(&candidates, &hash),
+        None => pick_random(&candidates),
+    };
+    match node {
+        Some(node) => build_response(
+            StatusCode::OK,
+            &serde_json::json!({"code": 0, "msg": "OK", "data": node}),
+        ),
+        None => build_response(
+            StatusCode::NOT_FOUND,
+            &serde_json::json!({"code": 404, "msg": "No online node in the domain"}),
+        ),
+    }
+}
+
+// The nodes routed for the domain which are online and have some weight
+fn routable_nodes(domain: &str) -> Result<Vec<ResolvedNode>> {
+    let weights: HashMap<String, i64> = cache()
+        .get_domain_nodes(domain)?
+        .into_iter()
+        .filter(|(_, weight)| *weight > 0)
+        .collect();
+    if weights.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let filter = NodeFilter {
+        status: Some(NODE_STATUS_ONLINE.to_string()),
+        ids: Some(weights.keys().cloned().collect()),
+        ..Default::default()
+    };
+    let mut nodes: Vec<ResolvedNode> = store()
+        .query_nodes_by_filter(&filter)?
+        .into_iter()
+        .map(|node| ResolvedNode {
+            weight: weights[&node.node_id],
+            node_id: node.node_id,
+            subdomain: node.subdomain,
+        })
+        .collect();
+    // Keep the picks independent of the order the store returns
+    nodes.sort_by(|a, b| a.node_id.cmp(&b.node_id));
+    Ok(nodes)
+}
+
+fn pick_random(nodes: &[ResolvedNode]) -> Option<&ResolvedNode> {
+    // None for weights beyond any the domain nodes take
+    let total = nodes
+        .iter()
+        .try_fold(0i64, |total, node| total.checked_add(node.weight))?;
+    if total <= 0 {
+        return None;
+    }
+    let mut point = rand::thread_rng().gen_range(0..total);
+    nodes.iter().find(|node| {
+        point -= node.weight;
+        point < 0
+    })
+}
+
+// Each node scores `weight / -ln(u)` with `u` uniform in (0, 1) drawn from the hash and the
+// node id. The best score wins, so only the hashes won by a leaving node move elsewhere.
+fn pick_sticky<'a>(nodes: &'a [ResolvedNode], hash: &str) -> Option<&'a ResolvedNode> {
+    nodes
+        .iter()
+        .map(|node| {
+            let h = mix(fnv1a(&[hash.as_bytes(), &[0xff], node.node_id.as_bytes()]));
+            let u = ((h >> 11) as f64 + 0.5) / (1u64 << 53) as f64;
+            (node.weight as f64 / -u.ln(), node)
+        })
+        .max_by(|(a, _), (b, _)| a.total_cmp(b))
+        .map(|(_, node)| node)
+}
+
+// A hash which stays the same across builds and hub instances
+fn fnv1a(parts: &[&[u8]]) -> u64 {
+    let mut h: u64 = 0xcbf29ce484222325;
+    for byte in parts.iter().flat_map(|part| part.iter()) {
+        h ^= *byte as u64;
+        h = h.wrapping_mul(0x100000001b3);
+    }
+    h
+}
+
+// The splitmix64 finalizer, to spread the close fnv hashes of similar ids
+fn mix(mut h: u64) -> u64 {
+    h = (h ^ (h >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    h = (h ^ (h >> 27)).wrapping_mul(0x94d049bb133111eb);
+    h ^ (h >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(node_id: &str, weight: i64) -> ResolvedNode {
+        ResolvedNode {
+            node_id: node_id.to_string(),
+            subdomain: format!("{}.gaia", node_id),
+            weight,
+        }
+    }
+
+    // The share of the picks won by each node, in the order of the nodes
+    fn shares<'a>(
+        nodes: &'a [ResolvedNode],
+        picks: usize,
+        mut pick: impl FnMut(usize) -> Option<&'a ResolvedNode>,
+    ) -> Vec<f64> {
+        let mut counts = vec![0; nodes.len()];
+        for i in 0..picks {
+            let picked = pick(i).unwrap();
+            counts[nodes
+                .iter()
+                .position(|n| n.node_id == picked.node_id)
+                .unwrap()] += 1;
+        }
+        counts.iter().map(|c| *c as f64 / picks as f64).collect()
+    }
+
+    fn sticky_picks(nodes: &[ResolvedNode]) -> Vec<String> {
+        (0..10_000)
+            .map(|i| {
+                pick_sticky(nodes, &format!("user-{}", i))
+                    .unwrap()
+                    .node_id
+                    .clone()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn picks_in_proportion_to_the_weights() {
+        let nodes = [node("n1", 1), node("n2", 3), node("n3", 6)];
+        let random = shares(&nodes, 50_000, |_| pick_random(&nodes));
+        let sticky = shares(&nodes, 50_000, |i| pick_sticky(&nodes, &i.to_string()));
+        for shares in [random, sticky] {
+            for (share, expected) in shares.iter().zip([0.1, 0.3, 0.6]) {
+                assert!((share - expected).abs() < 0.02, "{:?}", shares);
+            }
+        }
+    }
+
+    #[test]
+    fn never_picks_nodes_without_weight() {
+        let nodes = [node("n1", 0), node("n2", 2), node("n3", 0)];
+        for i in 0..1_000 {
+            assert_eq!(pick_random(&nodes).unwrap().node_id, "n2");
+            assert_eq!(pick_sticky(&nodes, &i.to_string()).unwrap().node_id, "n2");
+        }
+        let idle = [node("n1", 0)];
+        assert!(pick_random(&idle).is_none());
+        assert!(pick_random(&[]).is_none());
+        assert!(pick_sticky(&[], "user").is_none());
+    }
+
+    #[test]
+    fn refuses_weights_summing_beyond_i64() {
+        let nodes = [node("n1", i64::MAX), node("n2", 1)];
+        assert!(pick_random(&nodes).is_none());
+    }
+
+    #[test]
+    fn sticky_picks_stay_on_the_same_node() {
+        let nodes = [node("n1", 1), node("n2", 2), node("n3", 3)];
+        assert_eq!(sticky_picks(&nodes), sticky_picks(&nodes));
+    }
+
+    #[test]
+    fn a_joining_node_only_takes_its_share() {
+        let nodes = [node("n1", 1), node("n2", 2), node("n3", 3)];
+        let joined = [node("n1", 1), node("n2", 2), node("n3", 3), node("n4", 2)];
+        let (before, after) = (sticky_picks(&nodes), sticky_picks(&joined));
+
+        let moved: Vec<_> = before.iter().zip(&after).filter(|(b, a)| b != a).collect();
+        assert!(moved.iter().all(|(_, a)| *a == "n4"));
+        let share = moved.len() as f64 / before.len() as f64;
+        assert!((share - 0.25).abs() < 0.02, "{}", share);
+    }
+
+    #[test]
+    fn a_leaving_node_only_gives_up_its_share() {
+        let nodes = [node("n1", 1), node("n2", 2), node("n3", 3)];
+        let left = [node("n1", 1), node("n3", 3)];
+        let (before, after) = (sticky_picks(&nodes), sticky_picks(&left));
+
+        for (b, a) in before.iter().zip(&after) {
+            match b.as_str() {
+                "n2" => assert_ne!(a, "n2"),
+                _ => assert_eq!(a, b),
+            }
+        }
+    }
+}
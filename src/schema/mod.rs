@@ -0,0 +1,9 @@
+// The diesel schema of each storage backend.
+// The tables are the same, only the types of the times and metas differ.
+
+#[cfg(feature = "mysql")]
+pub mod mysql;
+#[cfg(feature = "postgres")]
+pub mod postgres;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
@@ -0,0 +1,93 @@
+diesel::table! {
+    devices (id) {
+        id -> Int8,
+        device_id -> Varchar,
+        version -> Varchar,
+        arch -> Varchar,
+        os -> Varchar,
+        client_address -> Varchar,
+        login_time -> Datetime,
+        meta -> Json,
+        created_at -> Datetime,
+        updated_at -> Datetime,
+        public_key -> Varchar,
+        pending_public_key -> Varchar,
+    }
+}
+
+diesel::table! {
+    node_status (id) {
+        id -> Int8,
+        node_id -> Varchar,
+        device_id -> Varchar,
+        subdomain -> Varchar,
+        version -> Varchar,
+        arch -> Varchar,
+        os -> Varchar,
+        client_address -> Varchar,
+        login_time -> Datetime,
+        last_active_time -> Datetime,
+        last_avail_time -> Nullable<Datetime>,
+        run_id -> Varchar,
+        meta -> Json,
+        node_version -> Varchar,
+        chat_model -> Varchar,
+        embedding_model -> Varchar,
+        status -> Varchar,
+        created_at -> Datetime,
+        updated_at -> Datetime,
+        frps_id -> Varchar,
+        country -> Varchar,
+        subdivision -> Varchar,
+        city -> Varchar,
+        probe_latency_ms -> Nullable<Int8>,
+    }
+}
+
+diesel::table! {
+    bans (id) {
+        id -> Int8,
+        kind -> Varchar,
+        value -> Varchar,
+        reason -> Varchar,
+        created_at -> Datetime,
+    }
+}
+
+diesel::table! {
+    frps_servers (frps_id) {
+        frps_id -> Varchar,
+        version -> Varchar,
+        region -> Varchar,
+        status -> Varchar,
+        last_seen_time -> Datetime,
+        created_at -> Datetime,
+    }
+}
+
+diesel::table! {
+    domain_nodes (domain, node_id) {
+        domain -> Varchar,
+        node_id -> Varchar,
+        weight -> Int8,
+        draining_since -> Nullable<Datetime>,
+        drain_deadline -> Nullable<Datetime>,
+        last_user_conn_time -> Nullable<Datetime>,
+    }
+}
+
+diesel::table! {
+    domains (name) {
+        name -> Varchar,
+        owner -> Varchar,
+        description -> Varchar,
+        chat_model -> Varchar,
+        embedding_model -> Varchar,
+        status -> Varchar,
+        created_at -> Datetime,
+        health_probe -> Nullable<Json>,
+        user_conn_limit -> Nullable<Int8>,
+    }
+}
+
+diesel::allow_tables_to_appear_in_same_query!(domain_nodes, domains, node_status);
@@ -1,7 +1,6 @@
-#[cfg(feature = "sqlite")]
 diesel::table! {
     devices (id) {
-        id -> Int4,
+        id -> Int8,
         device_id -> Varchar,
         version -> Varchar,
         arch -> Varchar,
@@ -11,29 +10,14 @@ diesel::table! {
         meta -> Text,
         created_at -> Int8,
         updated_at -> Int8,
+        public_key -> Varchar,
+        pending_public_key -> Varchar,
     }
 }
 
-#[cfg(feature = "mysql")]
-diesel::table! {
-    devices (id) {
-        id -> Int8,
-        device_id -> Varchar,
-        version -> Varchar,
-        arch -> Varchar,
-        os -> Varchar,
-        client_address -> Varchar,
-        login_time -> Datetime,
-        meta -> Json,
-        created_at -> Datetime,
-        updated_at -> Datetime,
-    }
-}
-
-#[cfg(feature = "sqlite")]
 diesel::table! {
     node_status (id) {
-        id -> Int4,
+        id -> Int8,
         node_id -> Varchar,
         device_id -> Varchar,
         subdomain -> Varchar,
@@ -52,31 +36,32 @@ diesel::table! {
         status -> Varchar,
         created_at -> Int8,
         updated_at -> Int8,
+        frps_id -> Varchar,
+        country -> Varchar,
+        subdivision -> Varchar,
+        city -> Varchar,
+        probe_latency_ms -> Nullable<Int8>,
     }
 }
 
-#[cfg(feature = "mysql")]
 diesel::table! {
-    node_status (id) {
+    bans (id) {
         id -> Int8,
-        node_id -> Varchar,
-        device_id -> Varchar,
-        subdomain -> Varchar,
+        kind -> Varchar,
+        value -> Varchar,
+        reason -> Varchar,
+        created_at -> Int8,
+    }
+}
+
+diesel::table! {
+    frps_servers (frps_id) {
+        frps_id -> Varchar,
         version -> Varchar,
-        arch -> Varchar,
-        os -> Varchar,
-        client_address -> Varchar,
-        login_time -> Datetime,
-        last_active_time -> Datetime,
-        last_avail_time -> Nullable<Datetime>,
-        run_id -> Varchar,
-        meta -> Json,
-        node_version -> Varchar,
-        chat_model -> Varchar,
-        embedding_model -> Varchar,
+        region -> Varchar,
         status -> Varchar,
-        created_at -> Datetime,
-        updated_at -> Datetime,
+        last_seen_time -> Int8,
+        created_at -> Int8,
     }
 }
 
@@ -85,7 +70,24 @@ diesel::table! {
         domain -> Varchar,
         node_id -> Varchar,
         weight -> Int8,
+        draining_since -> Nullable<Int8>,
+        drain_deadline -> Nullable<Int8>,
+        last_user_conn_time -> Nullable<Int8>,
+    }
+}
+
+diesel::table! {
+    domains (name) {
+        name -> Varchar,
+        owner -> Varchar,
+        description -> Varchar,
+        chat_model -> Varchar,
+        embedding_model -> Varchar,
+        status -> Varchar,
+        created_at -> Int8,
+        health_probe -> Nullable<Text>,
+        user_conn_limit -> Nullable<Int8>,
     }
 }
 
-diesel::allow_tables_to_appear_in_same_query!(domain_nodes, node_status);
+diesel::allow_tables_to_appear_in_same_query!(domain_nodes, domains, node_status);